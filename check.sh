@@ -11,7 +11,34 @@ cargo check                         --target i686-pc-windows-gnu
 cargo check --features size_classes
 cargo check --features size_classes --target wasm32-unknown-unknown
 cargo check --features size_classes --target i686-pc-windows-gnu
+cargo check --features log_spaced_size_classes --target wasm32-unknown-unknown
+cargo check --no-default-features --features buddy_classes --target wasm32-unknown-unknown
+cargo check --no-default-features --features buddy_classes --target i686-pc-windows-gnu
+WEE_ALLOC_MAX_PAGES=256 cargo check --target wasm32-unknown-unknown
+cargo check --features "mmap_huge_pages mmap_noreserve"
+cargo check --features abort_on_oom
+cargo check --features abort_on_oom --target wasm32-unknown-unknown
+cargo check --features abort_on_oom --target i686-pc-windows-gnu
+cargo check --no-default-features --features "static_array_backend abort_on_oom"
+cargo check --features guard_words
+cargo check --features allocator_api
+cargo check --features thread_local_cache
+cargo check --features deterministic --target wasm32-unknown-unknown
+cargo check --features no_merge --target wasm32-unknown-unknown
+cargo check --features cacheline_align_cells --target wasm32-unknown-unknown
+cargo check --features runtime_histogram --target wasm32-unknown-unknown
+cargo check --no-default-features --features "static_array_backend trace_events"
+cargo check --features fault_injection --target wasm32-unknown-unknown
+cargo check --features zero_header_on_free --target wasm32-unknown-unknown
+cargo check --features header_checksum --target wasm32-unknown-unknown
+cargo check --features alloc_tags --target wasm32-unknown-unknown
+cargo check --features inline_hot --target wasm32-unknown-unknown
+cargo check --features outline_hot --target wasm32-unknown-unknown
+cargo check --features doubly_linked_free_list --target wasm32-unknown-unknown
+cargo check --features "guard_words size_classes" --target wasm32-unknown-unknown
 cargo check --no-default-features --features "static_array_backend"
+cargo check --no-default-features --features "static_array_backend static_8mib"
+cargo check --no-default-features --features "growable_backend size_classes"
 cargo check --no-default-features --features "static_array_backend size_classes"
 cd -
 
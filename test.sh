@@ -18,12 +18,45 @@ cd ./test
 time cargo test --release --features "extra_assertions size_classes"
 time cargo test --release --features "extra_assertions"
 time cargo test --release --features "size_classes"
+time cargo test --release --features "extra_assertions size_classes address_ordered_free_list"
+time cargo test --release --features "address_ordered_free_list"
+time cargo test --release --features "deterministic size_classes"
+time cargo test --release --features "extra_assertions size_classes no_merge"
+time cargo test --release --features "extra_assertions size_classes cacheline_align_cells"
+time cargo test --release --features "cacheline_align_cells"
+time cargo test --release --features "extra_assertions size_classes doubly_linked_free_list"
+time cargo test --release --features "extra_assertions address_ordered_free_list doubly_linked_free_list"
+time cargo test --release --features "track_peak"
+time cargo test --release --features "count_allocations size_classes"
+time cargo test --release --features "runtime_histogram size_classes"
+time cargo test --release --features "trace_events"
+time cargo test --release --features "fault_injection size_classes"
+time cargo test --release --features "zero_header_on_free size_classes"
+time cargo test --release --features "zero_header_on_free extra_assertions"
+time cargo test --release --features "header_checksum size_classes"
+time cargo test --release --features "header_checksum extra_assertions"
+time cargo test --release --features "alloc_tags size_classes"
+time cargo test --release --features "alloc_tags extra_assertions"
+time cargo test --release --features "inline_hot size_classes"
+time cargo test --release --features "outline_hot size_classes"
+time cargo test --release --features "extra_assertions log_spaced_size_classes"
+time cargo test --release --features "extra_assertions buddy_classes"
+time cargo test --release --features "buddy_classes"
+time cargo test --release --features "guard_words size_classes"
+time WEE_ALLOC_MIN_CELL_WORDS=2 cargo test --release --features "extra_assertions size_classes"
+time WEE_ALLOC_INITIAL_PAGES=64 cargo test --release --features "extra_assertions size_classes"
+time WEE_ALLOC_MIN_GROW_PAGES=16 cargo test --release --features "extra_assertions"
+time WEE_ALLOC_GROW_PERCENT=150 cargo test --release --features "extra_assertions"
 time cargo test --release
 
+time cargo test --release --features "growable_backend extra_assertions size_classes"
+time cargo test --release --features "growable_backend"
+
 export WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES=$((512 * 1024 * 1024))
 
 time cargo test --release --features "static_array_backend extra_assertions size_classes"
 time cargo test --release --features "static_array_backend extra_assertions"
 time cargo test --release --features "static_array_backend size_classes"
+time cargo test --release --features "static_array_backend buddy_classes"
 time cargo test --release --features "static_array_backend"
 cd -
@@ -1,10 +1,14 @@
 #![feature(test)]
 
+extern crate dlmalloc;
 extern crate test;
 extern crate wee_alloc;
 extern crate wee_alloc_test;
 
+use std::alloc::{Alloc, AllocErr, Layout};
+use std::fs;
 use std::io;
+use std::ptr::NonNull;
 use wee_alloc_test::*;
 
 macro_rules! bench_trace {
@@ -52,3 +56,124 @@ bench_trace!(
 bench_trace!(bench_trace_grep_recursive, "../traces/grep-recursive.trace");
 bench_trace!(bench_trace_ls, "../traces/ls.trace");
 bench_trace!(bench_trace_source_map, "../traces/source-map.trace");
+
+#[bench]
+#[cfg(not(feature = "extra_assertions"))]
+fn bench_realloc_heavy(b: &mut test::Bencher) {
+    let operations = Operations::growing_vecs(64, 16);
+    let a = &wee_alloc::WeeAlloc::INIT;
+
+    #[cfg(feature = "count_allocations")]
+    {
+        let before = a.counters();
+        operations.run_with_allocator(a);
+        let after = a.counters();
+
+        let stdout = io::stdout();
+        let _stdout = stdout.lock();
+        println!("################## bench_realloc_heavy ##################");
+        println!(
+            "# Bytes copied by realloc: {}",
+            after.realloc_bytes_copied - before.realloc_bytes_copied
+        );
+        println!(
+            "# Bytes grown in place by realloc: {}",
+            after.realloc_bytes_grown_in_place - before.realloc_bytes_grown_in_place
+        );
+    }
+
+    b.iter(|| {
+        operations.run_with_allocator(a);
+    });
+}
+
+// `dlmalloc`, which `wee_alloc` is often weighed against, wrapped up so that
+// it can run `Operations`.
+struct Dlmalloc(dlmalloc::Dlmalloc);
+
+unsafe impl Alloc for Dlmalloc {
+    unsafe fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        NonNull::new(self.0.malloc(layout.size(), layout.align())).ok_or(AllocErr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.free(ptr.as_ptr(), layout.size(), layout.align())
+    }
+
+    unsafe fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        NonNull::new(
+            self.0
+                .realloc(ptr.as_ptr(), layout.size(), layout.align(), new_size),
+        )
+        .ok_or(AllocErr)
+    }
+}
+
+// Every trace in `traces` that reallocates, by name.
+fn realloc_traces() -> Vec<(String, Operations)> {
+    let trace_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/traces");
+    let mut traces: Vec<_> = fs::read_dir(trace_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".trace"))
+        .map(|name| {
+            let operations = Operations::read_trace(&name);
+            (name, operations)
+        })
+        .filter(|&(_, ref operations)| operations.has_reallocs())
+        .collect();
+    traces.sort_by(|a, b| a.0.cmp(&b.0));
+    traces
+}
+
+#[bench]
+#[cfg(not(feature = "extra_assertions"))]
+fn bench_realloc_traces(b: &mut test::Bencher) {
+    let traces = realloc_traces();
+    let a = &wee_alloc::WeeAlloc::INIT;
+
+    {
+        let stdout = io::stdout();
+        let _stdout = stdout.lock();
+        println!("################## bench_realloc_traces ##################");
+        println!("#");
+        println!("# trace: allocator: bytes copied, reallocs in place, peak pages");
+        println!("#");
+        for &(ref name, ref operations) in &traces {
+            let wee = operations.run_with_allocator_stats(a, || a.pages_in_use().0);
+            println!(
+                "# {}: wee_alloc: {}, {}, {}",
+                name, wee.bytes_copied, wee.realloc_in_place, wee.peak_pages
+            );
+            // `dlmalloc` doesn't say how many pages it has, so only compare
+            // what realloc did.
+            let dl = operations.run_with_allocator_stats(Dlmalloc(dlmalloc::Dlmalloc::new()), || 0);
+            println!(
+                "# {}: dlmalloc: {}, {}, -",
+                name, dl.bytes_copied, dl.realloc_in_place
+            );
+        }
+    }
+
+    b.iter(|| {
+        for &(_, ref operations) in &traces {
+            operations.run_with_allocator(a);
+        }
+    });
+}
+
+#[bench]
+#[cfg(not(feature = "extra_assertions"))]
+fn bench_realloc_traces_dlmalloc(b: &mut test::Bencher) {
+    let traces = realloc_traces();
+    b.iter(|| {
+        for &(_, ref operations) in &traces {
+            operations.run_with_allocator(Dlmalloc(dlmalloc::Dlmalloc::new()));
+        }
+    });
+}
@@ -1,6 +1,7 @@
 #![feature(allocator_api)]
 
 extern crate histo;
+extern crate memory_units;
 #[macro_use]
 extern crate quickcheck;
 #[macro_use]
@@ -8,28 +9,47 @@ extern crate cfg_if;
 extern crate rand;
 extern crate wee_alloc;
 
+use memory_units::Bytes;
 use quickcheck::{Arbitrary, Gen};
 use std::alloc::{Alloc, Layout};
+use std::cmp;
 use std::f64;
+use std::fmt;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::path::Path;
+use std::ptr::NonNull;
 use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Operation {
-    // Allocate this many bytes.
-    Alloc(usize),
+    // Allocate this many bytes with this alignment.
+    Alloc { size: usize, align: usize },
 
     // Free the n^th allocation we've made, or no-op if there it has already
     // been freed.
     Free(usize),
+
+    // Reallocate the n^th allocation we've made to this many bytes. The result
+    // counts as a new allocation, and the n^th allocation is considered freed.
+    // If the n^th allocation has already been freed, this is a plain `Alloc`.
+    Realloc(usize, usize),
 }
 
 pub use Operation::*;
 
+static ALIGNS: [usize; 10] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512];
+
 impl Operation {
+    /// Allocate this many bytes with the default, word alignment.
+    pub fn alloc(size: usize) -> Self {
+        Alloc {
+            size,
+            align: mem::size_of::<usize>(),
+        }
+    }
+
     #[inline]
     fn arbitrary_alloc<G: Gen>(
         g: &mut G,
@@ -39,9 +59,23 @@ impl Operation {
         active_allocs.push(*num_allocs);
         *num_allocs += 1;
 
+        let size = Self::arbitrary_size(g);
+
+        // Use a non-default alignment with probability P = 1/10.
+        let align = if g.gen_weighted_bool(10) {
+            ALIGNS[g.gen_range(0, ALIGNS.len())]
+        } else {
+            mem::size_of::<usize>()
+        };
+
+        Alloc { size, align }
+    }
+
+    #[inline]
+    fn arbitrary_size<G: Gen>(g: &mut G) -> usize {
         // Zero sized allocation 1/1000 times.
         if g.gen_weighted_bool(1000) {
-            return Alloc(0);
+            return 0;
         }
 
         // XXX: Keep this synced with `wee_alloc`.
@@ -53,14 +87,14 @@ impl Operation {
         if g.gen_weighted_bool(20) {
             let n =
                 g.gen_range(1, 10) * max_small_alloc_size + g.gen_range(0, max_small_alloc_size);
-            return Alloc(n);
+            return n;
         }
 
         // Small allocation.
         if g.gen() {
-            Alloc(g.gen_range(12, 17))
+            g.gen_range(12, 17)
         } else {
-            Alloc(max_small_alloc_size)
+            max_small_alloc_size
         }
     }
 
@@ -72,6 +106,16 @@ impl Operation {
     }
 }
 
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Alloc { size, align } => write!(f, "Alloc({}, {}),", size, align),
+            Free(idx) => write!(f, "Free({}),", idx),
+            Realloc(idx, size) => write!(f, "Realloc({}, {}),", idx, size),
+        }
+    }
+}
+
 impl FromStr for Operation {
     type Err = ();
 
@@ -79,8 +123,15 @@ impl FromStr for Operation {
         if s.starts_with("Alloc(") && s.ends_with("),") {
             let start = "Alloc(".len();
             let end = s.len() - "),".len();
-            let n: usize = s[start..end].parse().map_err(|_| ())?;
-            return Ok(Alloc(n));
+            let args = &s[start..end];
+            // Older traces don't record the alignment, and always used word
+            // alignment.
+            if !args.contains(',') {
+                let size: usize = args.parse().map_err(|_| ())?;
+                return Ok(Operation::alloc(size));
+            }
+            let (size, align) = parse_pair(args)?;
+            return Ok(Alloc { size, align });
         }
 
         if s.starts_with("Free(") && s.ends_with("),") {
@@ -90,10 +141,24 @@ impl FromStr for Operation {
             return Ok(Free(idx));
         }
 
+        if s.starts_with("Realloc(") && s.ends_with("),") {
+            let start = "Realloc(".len();
+            let end = s.len() - "),".len();
+            let (idx, size) = parse_pair(&s[start..end])?;
+            return Ok(Realloc(idx, size));
+        }
+
         Err(())
     }
 }
 
+fn parse_pair(s: &str) -> Result<(usize, usize), ()> {
+    let mut parts = s.splitn(2, ',');
+    let a = parts.next().ok_or(())?.trim().parse().map_err(|_| ())?;
+    let b = parts.next().ok_or(())?.trim().parse().map_err(|_| ())?;
+    Ok((a, b))
+}
+
 #[derive(Debug, Clone)]
 pub struct Operations(Vec<Operation>);
 
@@ -109,6 +174,18 @@ impl FromStr for Operations {
     }
 }
 
+/// What an allocator did while running some `Operations`, as returned by
+/// `Operations::run_with_allocator_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// The bytes that reallocations copied, because they moved the allocation.
+    pub bytes_copied: usize,
+    /// The most pages in use after any one operation.
+    pub peak_pages: usize,
+    /// The number of reallocations that left the allocation where it was.
+    pub realloc_in_place: usize,
+}
+
 #[cfg(feature = "extra_assertions")]
 const NUM_OPERATIONS: usize = 2_000;
 
@@ -183,7 +260,7 @@ impl Arbitrary for Operations {
             .0
             .iter()
             .enumerate()
-            .filter_map(|(i, op)| if let Alloc(_) = *op { Some(i) } else { None })
+            .filter_map(|(i, op)| if let Alloc { .. } = *op { Some(i) } else { None })
             .collect();
 
         let ops = self.0.clone();
@@ -217,11 +294,14 @@ impl Arbitrary for Operations {
                     .enumerate()
                     .filter_map(|(j, op)| {
                         if i == j {
-                            if let Alloc(size) = *op {
+                            if let Alloc { size, align } = *op {
                                 if size == 0 {
                                     None
                                 } else {
-                                    Some(Alloc(size / 2))
+                                    Some(Alloc {
+                                        size: size / 2,
+                                        align,
+                                    })
                                 }
                             } else {
                                 Some(*op)
@@ -266,25 +346,274 @@ impl Operations {
         handle3.join().expect("Thread 3 Failed");
     }
 
+    /// Replay a trace across `num_threads` threads that share one allocator.
+    ///
+    /// Allocations are handed out to threads round-robin. Each `Free` and
+    /// `Realloc` runs on the thread that made the allocation it refers to, with
+    /// its index remapped into that thread's own numbering of allocations.
+    pub fn run_trace_multi_threaded(trace: &Self, num_threads: usize) {
+        use std::thread;
+
+        static WEE: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+        assert!(num_threads > 0);
+
+        let mut thread_ops = vec![vec![]; num_threads];
+        let mut thread_num_allocs = vec![0; num_threads];
+
+        // For each allocation in the trace, the thread it was assigned to and
+        // its index within that thread.
+        let mut remapped: Vec<(usize, usize)> = vec![];
+
+        let mut next_thread = 0;
+        for op in trace.0.iter().cloned() {
+            let (thread, op) = match op {
+                Alloc { .. } => {
+                    let thread = next_thread;
+                    next_thread = (next_thread + 1) % num_threads;
+                    (thread, op)
+                }
+                Realloc(idx, size) => match remapped.get(idx).cloned() {
+                    Some((thread, local_idx)) => (thread, Realloc(local_idx, size)),
+                    None => {
+                        let thread = next_thread;
+                        next_thread = (next_thread + 1) % num_threads;
+                        (thread, Operation::alloc(size))
+                    }
+                },
+                Free(idx) => {
+                    if let Some(&(thread, local_idx)) = remapped.get(idx) {
+                        thread_ops[thread].push(Free(local_idx));
+                    }
+                    continue;
+                }
+            };
+
+            remapped.push((thread, thread_num_allocs[thread]));
+            thread_num_allocs[thread] += 1;
+            thread_ops[thread].push(op);
+        }
+
+        let handles: Vec<_> = thread_ops
+            .into_iter()
+            .map(|ops| thread::spawn(move || Operations(ops).run_with_allocator(&WEE)))
+            .collect();
+
+        for (i, handle) in handles.into_iter().enumerate() {
+            handle
+                .join()
+                .unwrap_or_else(|_| panic!("Thread {} Failed", i));
+        }
+    }
+
     pub fn run_with_allocator<A: Alloc>(&self, mut a: A) {
         let mut allocs = vec![];
         for op in self.0.iter().cloned() {
-            match op {
-                Alloc(n) => {
-                    let layout = Layout::from_size_align(n, mem::size_of::<usize>()).unwrap();
-                    allocs.push(match unsafe { a.alloc(layout.clone()) } {
-                        Ok(ptr) => Some((ptr, layout)),
-                        Err(_) => None,
-                    });
+            Self::run_one(&mut a, &mut allocs, op);
+        }
+    }
+
+    /// Run these operations like `run_with_allocator`, and summarize what the
+    /// allocator did along the way. `pages_in_use` is sampled after every
+    /// operation, for `OpStats::peak_pages`.
+    pub fn run_with_allocator_stats<A, F>(&self, mut a: A, mut pages_in_use: F) -> OpStats
+    where
+        A: Alloc,
+        F: FnMut() -> usize,
+    {
+        let mut stats = OpStats::default();
+        let mut allocs: Vec<Option<(NonNull<u8>, Layout)>> = vec![];
+        for op in self.0.iter().cloned() {
+            let old = match op {
+                Realloc(idx, n) => allocs
+                    .get(idx)
+                    .cloned()
+                    .and_then(|entry| entry)
+                    .map(|(ptr, layout)| (ptr, cmp::min(layout.size(), n))),
+                _ => None,
+            };
+
+            Self::run_one(&mut a, &mut allocs, op);
+
+            if let (Some((old_ptr, copied)), Some(&Some((new_ptr, _)))) = (old, allocs.last()) {
+                if old_ptr == new_ptr {
+                    stats.realloc_in_place += 1;
+                } else {
+                    stats.bytes_copied += copied;
                 }
-                Free(idx) => {
-                    if let Some(entry) = allocs.get_mut(idx) {
-                        if let Some((ptr, layout)) = entry.take() {
-                            unsafe {
-                                a.dealloc(ptr, layout);
+            }
+            stats.peak_pages = stats.peak_pages.max(pages_in_use());
+        }
+        stats
+    }
+
+    /// Whether any of these operations is a `Realloc`.
+    pub fn has_reallocs(&self) -> bool {
+        self.0.iter().any(|op| match *op {
+            Realloc(..) => true,
+            _ => false,
+        })
+    }
+
+    /// Run these operations like `run_with_allocator`, and return where each
+    /// `Alloc` and `Realloc` put its allocation, as told by `locate`, or `None`
+    /// where it failed.
+    pub fn allocation_sites<A, F, T>(&self, mut a: A, mut locate: F) -> Vec<Option<T>>
+    where
+        A: Alloc,
+        F: FnMut(NonNull<u8>) -> T,
+    {
+        let mut sites = vec![];
+        let mut allocs = vec![];
+        for op in self.0.iter().cloned() {
+            let allocates = match op {
+                Free(_) => false,
+                _ => true,
+            };
+            Self::run_one(&mut a, &mut allocs, op);
+            if allocates {
+                let site = allocs
+                    .last()
+                    .and_then(|entry| entry.as_ref())
+                    .map(|&(ptr, _)| locate(ptr));
+                sites.push(site);
+            }
+        }
+        sites
+    }
+
+    // Perform a single operation, where `allocs` has the result of every
+    // operation before it, or `None` if it failed or has since been freed.
+    fn run_one<A: Alloc>(
+        a: &mut A,
+        allocs: &mut Vec<Option<(NonNull<u8>, Layout)>>,
+        op: Operation,
+    ) {
+        match op {
+            Alloc { size, align } => {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                allocs.push(match unsafe { a.alloc(layout.clone()) } {
+                    Ok(ptr) => Some((ptr, layout)),
+                    Err(_) => None,
+                });
+            }
+            Realloc(idx, n) => {
+                let orig = allocs.get_mut(idx).and_then(|entry| entry.take());
+                allocs.push(match orig {
+                    Some((ptr, layout)) => {
+                        let new_layout = Layout::from_size_align(n, layout.align()).unwrap();
+                        match unsafe { a.realloc(ptr, layout.clone(), n) } {
+                            Ok(ptr) => Some((ptr, new_layout)),
+                            // On failure, the original allocation is
+                            // untouched, so free it now.
+                            Err(_) => {
+                                unsafe {
+                                    a.dealloc(ptr, layout);
+                                }
+                                None
                             }
                         }
                     }
+                    None => {
+                        let layout = Layout::from_size_align(n, mem::size_of::<usize>()).unwrap();
+                        match unsafe { a.alloc(layout.clone()) } {
+                            Ok(ptr) => Some((ptr, layout)),
+                            Err(_) => None,
+                        }
+                    }
+                });
+            }
+            Free(idx) => {
+                if let Some(entry) = allocs.get_mut(idx) {
+                    if let Some((ptr, layout)) = entry.take() {
+                        unsafe {
+                            a.dealloc(ptr, layout);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Run these operations with a fresh allocator, like `run_single_threaded`,
+    /// but take a byte-for-byte snapshot of the whole `growable_backend` heap
+    /// before every operation, and panic as soon as an operation writes
+    /// somewhere it shouldn't: into any live allocation other than the one it
+    /// operates on, or into memory that the allocator doesn't own.
+    ///
+    /// The allocator's own bookkeeping, i.e. cell headers and free cells, may
+    /// change freely, since only the allocator knows which of it an operation
+    /// needs to touch. Every allocation is filled with a pattern, so that a
+    /// stray write is unlikely to leave its bytes as they were.
+    ///
+    /// This copies the whole heap on every operation, so it is slow. Nothing
+    /// else may use the `growable_backend` heap while it runs, so call it from
+    /// a test binary of its own.
+    #[cfg(feature = "growable_backend")]
+    pub fn run_with_memory_audit(&self) {
+        let a = wee_alloc::WeeAlloc::new();
+        let mut allocs = vec![];
+        let mut before = vec![];
+
+        for (i, op) in self.0.iter().cloned().enumerate() {
+            unsafe {
+                wee_alloc::with_growable_backend_heap(|heap| {
+                    before.clear();
+                    before.extend_from_slice(heap);
+                });
+            }
+
+            // The allocation that this operation may write to, if any.
+            let touched = match op {
+                Alloc { .. } => None,
+                Realloc(idx, _) | Free(idx) => allocs.get(idx).cloned().and_then(|entry| entry),
+            };
+            let num_allocs = allocs.len();
+
+            Self::run_one(&mut &a, &mut allocs, op.clone());
+
+            unsafe {
+                wee_alloc::with_growable_backend_heap(|heap| {
+                    let base = heap.as_ptr() as usize;
+                    let changed = before
+                        .iter()
+                        .zip(heap.iter())
+                        .enumerate()
+                        .filter(|&(_, (b, h))| b != h)
+                        .map(|(offset, _)| base + offset);
+                    for addr in changed {
+                        if !a.owns(addr as *const u8) {
+                            panic!(
+                                "operation {} ({}) wrote to {:#x}, outside of the allocator's memory",
+                                i, op, addr
+                            );
+                        }
+                        let in_alloc = |&(ptr, ref layout): &(NonNull<u8>, Layout)| {
+                            let start = ptr.as_ptr() as usize;
+                            start <= addr && addr < start + layout.size()
+                        };
+                        if touched.as_ref().map_or(false, &in_alloc) {
+                            continue;
+                        }
+                        let scribbled = allocs[..num_allocs]
+                            .iter()
+                            .position(|entry| entry.as_ref().map_or(false, &in_alloc));
+                        if let Some(j) = scribbled {
+                            panic!(
+                                "operation {} ({}) wrote to {:#x}, in live allocation {}",
+                                i, op, addr, j
+                            );
+                        }
+                    }
+                });
+            }
+
+            // Fill the new allocation, if there is one.
+            if allocs.len() > num_allocs {
+                if let Some(&Some((ptr, ref layout))) = allocs.last() {
+                    unsafe {
+                        std::ptr::write_bytes(ptr.as_ptr(), i as u8 | 1, layout.size());
+                    }
                 }
             }
         }
@@ -295,7 +624,7 @@ impl Operations {
     pub fn size_histogram(&self) -> histo::Histogram {
         let mut histogram = histo::Histogram::with_buckets(Self::NUM_BUCKETS);
         for op in &self.0 {
-            if let Alloc(n) = *op {
+            if let Alloc { size: n, .. } | Realloc(_, n) = *op {
                 let n = n as f64;
                 let n = n.log2().round();
                 histogram.add(n as u64);
@@ -316,6 +645,49 @@ impl Operations {
         histogram
     }
 
+    /// Operations that grow `num_vecs` byte vectors side by side, the way
+    /// `Vec` does: each one starts out one byte long, and is reallocated to
+    /// double its size `num_doublings` times before being freed.
+    pub fn growing_vecs(num_vecs: usize, num_doublings: usize) -> Self {
+        let mut ops = Vec::with_capacity(num_vecs * (num_doublings + 2));
+        let mut current: Vec<usize> = (0..num_vecs).collect();
+        for _ in 0..num_vecs {
+            ops.push(Alloc { size: 1, align: 1 });
+        }
+
+        let mut num_allocs = num_vecs;
+        for doubling in 1..num_doublings + 1 {
+            for idx in &mut current {
+                ops.push(Realloc(*idx, 1 << doubling));
+                *idx = num_allocs;
+                num_allocs += 1;
+            }
+        }
+
+        ops.extend(current.into_iter().map(Free));
+        Operations(ops)
+    }
+
+    /// Serialize these operations in the same format that `read_trace`
+    /// parses.
+    pub fn to_trace_string(&self) -> String {
+        let mut trace = String::new();
+        for op in &self.0 {
+            trace.push_str(&op.to_string());
+            trace.push('\n');
+        }
+        trace
+    }
+
+    /// Write these operations to the given file in the `traces` directory, so
+    /// that a failing case can be checked in as a regression trace.
+    pub fn save_failing_case(&self, trace: &str) -> io::Result<()> {
+        let trace = Path::new(trace);
+        let trace_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/traces"));
+        let mut file = fs::File::create(trace_dir.join(trace))?;
+        file.write_all(self.to_trace_string().as_bytes())
+    }
+
     pub fn read_trace(trace: &str) -> Self {
         let trace = Path::new(trace);
         let trace_dir = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/traces"));
@@ -333,7 +705,17 @@ macro_rules! run_quickchecks {
         #[test]
         fn $name() {
             fn single_threaded(ops: Operations) {
-                ops.run_single_threaded();
+                use std::panic;
+
+                let result = panic::catch_unwind(|| ops.run_single_threaded());
+                if let Err(e) = result {
+                    let trace = concat!("failing-", stringify!($name), ".trace");
+                    match ops.save_failing_case(trace) {
+                        Ok(()) => eprintln!("Saved failing operations to traces/{}", trace),
+                        Err(err) => eprintln!("Failed to save failing operations: {}", err),
+                    }
+                    panic::resume_unwind(e);
+                }
             }
 
             quickcheck::QuickCheck::new()
@@ -366,9 +748,6 @@ fn multi_threaded_quickchecks() {
     );
 }
 
-#[cfg(test)]
-static ALIGNS: [usize; 10] = [1, 2, 4, 8, 16, 32, 64, 128, 256, 512];
-
 quickcheck! {
     fn single_allocation_with_size_and_align(size: usize, align: usize) -> () {
         let size = size % 65536;
@@ -380,6 +759,133 @@ quickcheck! {
     }
 }
 
+#[test]
+fn trace_string_round_trips() {
+    let ops = Operations(vec![
+        Operation::alloc(16),
+        Alloc {
+            size: 100,
+            align: 64,
+        },
+        Realloc(0, 32),
+        Free(1),
+        Free(2),
+    ]);
+    let trace = ops.to_trace_string();
+    let parsed: Operations = trace.parse().unwrap();
+    assert_eq!(parsed.to_trace_string(), trace);
+}
+
+#[test]
+fn run_growing_vecs() {
+    Operations::growing_vecs(16, 12).run_single_threaded();
+}
+
+#[test]
+fn parse_alloc_with_and_without_align() {
+    match "Alloc(24),".parse() {
+        Ok(Alloc { size: 24, align }) => assert_eq!(align, mem::size_of::<usize>()),
+        otherwise => panic!("unexpected parse result: {:?}", otherwise),
+    }
+
+    let op = Alloc {
+        size: 100,
+        align: 64,
+    };
+    match op.to_string().parse() {
+        Ok(Alloc {
+            size: 100,
+            align: 64,
+        }) => {}
+        otherwise => panic!("unexpected parse result: {:?}", otherwise),
+    }
+}
+
+#[test]
+fn replay_realloc_and_aligned_allocs() {
+    let ops: Operations = "Alloc(16),\n\
+                           Alloc(100, 64),\n\
+                           Realloc(0, 4096),\n\
+                           Realloc(1, 8),\n\
+                           Realloc(0, 32),\n\
+                           Free(2),\n\
+                           Free(3),\n\
+                           Free(4),\n"
+        .parse()
+        .unwrap();
+    ops.run_single_threaded();
+}
+
+#[test]
+fn op_stats_tell_moved_reallocs_from_in_place_ones() {
+    let ops: Operations = "Alloc(8),\n\
+                           Realloc(0, 8),\n\
+                           Realloc(1, 4096),\n\
+                           Free(2),\n"
+        .parse()
+        .unwrap();
+    let a = &wee_alloc::WeeAlloc::INIT;
+    let stats = ops.run_with_allocator_stats(a, || a.pages_in_use().0);
+    assert_eq!(stats.realloc_in_place, 1);
+    assert_eq!(stats.bytes_copied, 8);
+    assert!(stats.peak_pages > 0);
+}
+
+#[test]
+#[cfg(feature = "deterministic")]
+fn deterministic_allocation_sites_do_not_depend_on_segment_placement() {
+    const REGION_BYTES: usize = 64 * 1024;
+
+    #[repr(align(4096))]
+    struct Region([u8; REGION_BYTES]);
+
+    // The first two allocations can't share a segment, so each gets one of
+    // them. Once they are freed, both segments have a free cell to reuse.
+    let ops: Operations = "Alloc(40000),\n\
+                           Alloc(40000),\n\
+                           Free(0),\n\
+                           Free(1),\n\
+                           Alloc(3000),\n\
+                           Alloc(3000),\n\
+                           Alloc(16),\n\
+                           Realloc(2, 6000),\n\
+                           Free(4),\n\
+                           Alloc(100, 64),\n"
+        .parse()
+        .unwrap();
+
+    // Run the operations on an allocator made from `main` with `donated`
+    // donated to it, and give each allocation's segment and offset in it.
+    let run = |main: &mut Region, donated: &mut Region| {
+        let main_start = main.0.as_ptr() as usize;
+        let donated_start = donated.0.as_ptr() as usize;
+        let a = wee_alloc::WeeAlloc::from_slice(&mut main.0);
+        unsafe {
+            a.donate(
+                NonNull::new_unchecked(donated.0.as_mut_ptr()),
+                Bytes(REGION_BYTES),
+            );
+        }
+        ops.allocation_sites(&a, |ptr| {
+            let addr = ptr.as_ptr() as usize;
+            if donated_start <= addr && addr < donated_start + REGION_BYTES {
+                ("donated", addr - donated_start)
+            } else {
+                ("main", addr - main_start)
+            }
+        })
+    };
+
+    // Run the same operations twice, with the donated segment below the
+    // main one, and then above it.
+    let mut regions = vec![Region([0; REGION_BYTES]), Region([0; REGION_BYTES])];
+    let (low, high) = regions.split_at_mut(1);
+    let first = run(&mut high[0], &mut low[0]);
+    let second = run(&mut low[0], &mut high[0]);
+    assert!(first.iter().all(|site| site.is_some()));
+    assert_eq!(first, second);
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 macro_rules! test_trace {
@@ -405,33 +911,39 @@ test_trace!(test_trace_grep_recursive, "../traces/grep-recursive.trace");
 test_trace!(test_trace_ls, "../traces/ls.trace");
 test_trace!(test_trace_source_map, "../traces/source-map.trace");
 
+#[test]
+fn test_trace_ffmpeg_multi_threaded() {
+    let ops = Operations::read_trace("../traces/ffmpeg.trace");
+    Operations::run_trace_multi_threaded(&ops, 4);
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 #[test]
 fn regression_test_0() {
-    Operations(vec![Alloc(1)]).run_single_threaded();
+    Operations(vec![Operation::alloc(1)]).run_single_threaded();
 }
 
 #[test]
 fn regression_test_1() {
-    Operations(vec![Alloc(1414), Free(0), Alloc(1414), Free(1)]).run_single_threaded();
+    Operations(vec![Operation::alloc(1414), Free(0), Operation::alloc(1414), Free(1)]).run_single_threaded();
 }
 
 #[test]
 fn regression_test_2() {
-    Operations(vec![Alloc(168), Free(0), Alloc(0), Alloc(168), Free(2)]).run_single_threaded();
+    Operations(vec![Operation::alloc(168), Free(0), Operation::alloc(0), Operation::alloc(168), Free(2)]).run_single_threaded();
 }
 
 #[test]
 fn regression_test_3() {
-    Operations(vec![Alloc(13672), Free(0), Alloc(1)]).run_single_threaded();
+    Operations(vec![Operation::alloc(13672), Free(0), Operation::alloc(1)]).run_single_threaded();
 }
 
 #[test]
 fn allocate_size_zero() {
     use std::iter;
     Operations(
-        iter::repeat(Alloc(0))
+        iter::repeat(Operation::alloc(0))
             .take(1000)
             .chain((0..1000).map(|i| Free(i)))
             .collect(),
@@ -439,15 +951,41 @@ fn allocate_size_zero() {
     .run_single_threaded();
 }
 
+#[test]
+fn realloc_size_zero() {
+    use std::iter;
+    Operations(
+        iter::repeat(Operation::alloc(0))
+            .take(1000)
+            .chain((0..1000).map(|i| Realloc(i, 16)))
+            .chain((1000..2000).map(|i| Realloc(i, 0)))
+            .chain((2000..3000).map(|i| Free(i)))
+            .collect(),
+    )
+    .run_single_threaded();
+}
+
+#[test]
+fn alloc_excess_reports_the_rounded_up_cell() {
+    let mut a = &wee_alloc::WeeAlloc::INIT;
+    let layout = Layout::from_size_align(13, 1).unwrap();
+    unsafe {
+        let excess = a.alloc_excess(layout.clone()).unwrap();
+        assert!(excess.1 >= 13);
+        assert_eq!(excess.1 % mem::size_of::<usize>(), 0);
+        a.dealloc(excess.0, Layout::from_size_align(excess.1, 1).unwrap());
+    }
+}
+
 #[test]
 fn allocate_many_small() {
     use std::iter;
 
     Operations(
-        iter::repeat(Alloc(16 * mem::size_of::<usize>()))
+        iter::repeat(Operation::alloc(16 * mem::size_of::<usize>()))
             .take(100)
             .chain((0..100).map(|i| Free(i)))
-            .chain(iter::repeat(Alloc(256 * mem::size_of::<usize>())).take(100))
+            .chain(iter::repeat(Operation::alloc(256 * mem::size_of::<usize>())).take(100))
             .chain((0..100).map(|i| Free(i + 100)))
             .collect(),
     )
@@ -459,16 +997,30 @@ fn allocate_many_large() {
     use std::iter;
 
     Operations(
-        iter::repeat(Alloc(257 * mem::size_of::<usize>()))
+        iter::repeat(Operation::alloc(257 * mem::size_of::<usize>()))
             .take(100)
             .chain((0..100).map(|i| Free(i)))
-            .chain(iter::repeat(Alloc(1024 * mem::size_of::<usize>())).take(100))
+            .chain(iter::repeat(Operation::alloc(1024 * mem::size_of::<usize>())).take(100))
             .chain((0..100).map(|i| Free(i + 100)))
             .collect(),
     )
     .run_single_threaded();
 }
 
+#[test]
+fn allocate_many_large_free_alternating() {
+    use std::iter;
+
+    Operations(
+        iter::repeat(Operation::alloc(512 * mem::size_of::<usize>()))
+            .take(100)
+            .chain((0..50).map(|i| Free(i * 2)))
+            .chain((0..50).map(|i| Free(i * 2 + 1)))
+            .collect(),
+    )
+    .run_single_threaded();
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 
 // Tests taken from
@@ -502,6 +1054,72 @@ fn smoke() {
     }
 }
 
+#[test]
+fn donate_initial_heap_region() {
+    let mut region = vec![0usize; 8192];
+    let start = region.as_mut_ptr() as usize;
+    let end = start + region.len() * mem::size_of::<usize>();
+
+    let wee = wee_alloc::WeeAlloc::INIT;
+    let mut a = &wee;
+    unsafe {
+        let ptr = NonNull::new(region.as_mut_ptr() as *mut u8).unwrap();
+        wee.donate(ptr, Bytes(end - start));
+
+        let layout = Layout::from_size_align(1024, mem::size_of::<usize>()).unwrap();
+        let ptr = a
+            .alloc(layout.clone())
+            .expect("should be able to alloc from the donated region");
+        let addr = ptr.as_ptr() as usize;
+        assert!(start <= addr && addr < end, "allocation should be inside the donated region");
+        a.dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn size_class_segments_are_reclaimed() {
+    // Give the allocator a fixed region of memory up front, and check that
+    // cycling through many size classes never needs to grow the heap beyond
+    // it.
+    let mut region = vec![0usize; 512 * 1024 / mem::size_of::<usize>()];
+    let start = region.as_mut_ptr() as usize;
+    let end = start + region.len() * mem::size_of::<usize>();
+    let in_region = |ptr: NonNull<u8>| {
+        let addr = ptr.as_ptr() as usize;
+        start <= addr && addr < end
+    };
+
+    let wee = wee_alloc::WeeAlloc::INIT;
+    let mut a = &wee;
+    unsafe {
+        wee.donate(NonNull::new(start as *mut u8).unwrap(), Bytes(end - start));
+
+        let mut ptrs = Vec::with_capacity(1000);
+        for round in 0..10 {
+            let small = Layout::from_size_align((round + 4) * mem::size_of::<usize>(), 1).unwrap();
+            for _ in 0..1000 {
+                let ptr = a.alloc(small.clone()).unwrap();
+                assert!(in_region(ptr));
+                ptrs.push(ptr);
+            }
+            for ptr in ptrs.drain(..) {
+                a.dealloc(ptr, small.clone());
+            }
+
+            // Freeing the last cell of each segment gave all but one of the
+            // size class's segments back, so this doesn't need a new one.
+            let ptr = a.alloc(small.clone()).unwrap();
+            assert!(in_region(ptr));
+            a.dealloc(ptr, small);
+
+            let large = Layout::from_size_align(32 * 1024, 1).unwrap();
+            let ptr = a.alloc(large.clone()).unwrap();
+            assert!(in_region(ptr));
+            a.dealloc(ptr, large);
+        }
+    }
+}
+
 #[test]
 fn cannot_alloc_max_usize() {
     let mut a = &wee_alloc::WeeAlloc::INIT;
@@ -513,6 +1131,388 @@ fn cannot_alloc_max_usize() {
     }
 }
 
+#[test]
+fn try_alloc_and_dealloc() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::new::<u64>();
+        let ptr = a
+            .try_alloc(layout.clone())
+            .expect("should be able to try_alloc a u64");
+        *(ptr.as_ptr() as *mut u64) = 42;
+        assert_eq!(*(ptr.as_ptr() as *mut u64), 42);
+        a.try_dealloc(ptr, layout);
+
+        let layout = Layout::from_size_align(std::usize::MAX, 1).unwrap();
+        assert_eq!(a.try_alloc(layout), Err(wee_alloc::AllocError));
+    }
+}
+
+#[test]
+fn debug_dumps_free_lists() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr, layout);
+    }
+
+    let debug = format!("{:?}", a);
+    assert!(debug.starts_with("WeeAlloc"));
+    assert!(debug.contains("next_cell_is_invalid: true"));
+}
+
+#[test]
+fn reserve_warms_free_lists() {
+    let a = wee_alloc::WeeAlloc::INIT;
+
+    let small = Layout::from_size_align(16, mem::size_of::<usize>()).unwrap();
+    assert_eq!(a.reserve(small, 100), 100);
+
+    let large = Layout::from_size_align(1024 * mem::size_of::<usize>(), 1).unwrap();
+    assert_eq!(a.reserve(large, 10), 10);
+
+    let huge = Layout::from_size_align(std::usize::MAX / 2, 1).unwrap();
+    assert_eq!(a.reserve(huge, 3), 0);
+}
+
+#[test]
+fn huge_allocations_fail_cleanly() {
+    use std::alloc::GlobalAlloc;
+
+    static WEE: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
+
+    let max = usize::max_value();
+    let sizes = [max, max - 1, max - 4096, max / 2, max / 2 + 1, max / 4];
+    for &size in sizes.iter() {
+        for &align in [mem::size_of::<usize>(), 4096, 1 << 20].iter() {
+            // Some of these are too big to even make a `Layout` out of.
+            if let Ok(layout) = Layout::from_size_align(size, align) {
+                let ptr = unsafe { WEE.alloc(layout) };
+                assert!(ptr.is_null(), "allocating {} bytes should fail", size);
+            }
+        }
+    }
+}
+
+#[test]
+fn from_slice_allocations_stay_in_slice() {
+    let mut buf = vec![0u8; 64 * 1024 + 3];
+    let start = buf.as_ptr() as usize;
+    let end = start + buf.len();
+
+    let wee = wee_alloc::WeeAlloc::from_slice(&mut buf[3..]);
+    let mut a = &wee;
+    unsafe {
+        let layout = Layout::from_size_align(1024, mem::size_of::<usize>()).unwrap();
+        let mut ptrs = vec![];
+        while let Ok(ptr) = a.alloc(layout.clone()) {
+            let addr = ptr.as_ptr() as usize;
+            assert!(start <= addr && addr + 1024 <= end, "allocation should be inside the slice");
+            ptrs.push(ptr);
+        }
+        assert!(!ptrs.is_empty());
+
+        for ptr in ptrs {
+            a.dealloc(ptr, layout.clone());
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn freed_large_cell_feeds_hungry_size_class() {
+    let mut buf = vec![0u8; 96 * 1024];
+    let a = wee_alloc::WeeAlloc::from_slice(&mut buf);
+    unsafe {
+        let large_layout = Layout::from_size_align(64 * 1024, mem::size_of::<usize>()).unwrap();
+        let large = a.try_alloc(large_layout.clone()).unwrap();
+        let start = large.as_ptr() as usize;
+        let end = start + large_layout.size();
+
+        // The first small allocation has to refill its size class, making it
+        // hungry, so freeing the large allocation feeds the size class.
+        let small_layout = Layout::from_size_align(3 * mem::size_of::<usize>(), 1).unwrap();
+        let mut smalls = vec![a.try_alloc(small_layout.clone()).unwrap()];
+        a.try_dealloc(large, large_layout.clone());
+
+        // The large cell is now a segment for the size class, so there's no
+        // room left in the slice for another large allocation...
+        assert!(a.try_alloc(large_layout.clone()).is_err());
+
+        // ...but small allocations eventually come out of it.
+        loop {
+            let small = a
+                .try_alloc(small_layout.clone())
+                .expect("should be able to allocate from the fed segment");
+            smalls.push(small);
+            let addr = small.as_ptr() as usize;
+            if start <= addr && addr < end {
+                break;
+            }
+        }
+
+        for small in smalls {
+            a.try_dealloc(small, small_layout.clone());
+        }
+    }
+}
+
+#[test]
+#[cfg(not(feature = "no_merge"))]
+fn compact_merges_adjacent_free_cells() {
+    let mut region = vec![0usize; 104 * 1024 / mem::size_of::<usize>()];
+    let size = region.len() * mem::size_of::<usize>();
+
+    let a = wee_alloc::WeeAlloc::new();
+    unsafe {
+        a.donate(NonNull::new(region.as_mut_ptr() as *mut u8).unwrap(), Bytes(size));
+
+        // Cells are carved from the end of the donated region, so each of
+        // these allocations is just below the previous one.
+        let layout = Layout::from_size_align(16 * 1024, mem::size_of::<usize>()).unwrap();
+        let ptrs: Vec<_> = (0..5)
+            .map(|_| a.try_alloc(layout.clone()).unwrap())
+            .collect();
+
+        // Freeing `ptrs[2]` last merges it into `ptrs[3]`, but only schedules
+        // the merge with `ptrs[1]`.
+        a.try_dealloc(ptrs[3], layout.clone());
+        a.try_dealloc(ptrs[1], layout.clone());
+        a.try_dealloc(ptrs[2], layout.clone());
+
+        let before = a.largest_free_cell();
+        a.compact();
+        let after = a.largest_free_cell();
+        assert!(
+            after > before,
+            "compacting should grow the largest free cell ({:?} -> {:?})",
+            before,
+            after
+        );
+        assert!(after >= Bytes(3 * 16 * 1024));
+
+        a.try_dealloc(ptrs[0], layout.clone());
+        a.try_dealloc(ptrs[4], layout.clone());
+    }
+}
+
+#[test]
+fn verify_integrity_after_allocs_and_frees() {
+    let mut buf = vec![0u8; 256 * 1024];
+    let a = wee_alloc::WeeAlloc::from_slice(&mut buf);
+    assert_eq!(a.verify_integrity(), Ok(()));
+
+    unsafe {
+        let layouts: Vec<_> = (0..64)
+            .map(|i| {
+                let align = ALIGNS[i % ALIGNS.len()];
+                Layout::from_size_align(8 + (i * 37) % 1024, align).unwrap()
+            })
+            .collect();
+        let ptrs: Vec<_> = layouts
+            .iter()
+            .map(|l| a.try_alloc(l.clone()).unwrap())
+            .collect();
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        for (i, (ptr, layout)) in ptrs.iter().zip(layouts.iter()).enumerate() {
+            if i % 3 != 0 {
+                a.try_dealloc(*ptr, layout.clone());
+            }
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        for (i, (ptr, layout)) in ptrs.iter().zip(layouts.iter()).enumerate() {
+            if i % 3 == 0 {
+                a.try_dealloc(*ptr, layout.clone());
+            }
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+fn verify_integrity_detects_use_after_free() {
+    let mut buf = vec![0u8; 64 * 1024];
+    let a = wee_alloc::WeeAlloc::from_slice(&mut buf);
+
+    unsafe {
+        let layout = Layout::from_size_align(256, mem::size_of::<usize>()).unwrap();
+        let ptrs: Vec<_> = (0..3)
+            .map(|_| a.try_alloc(layout.clone()).unwrap())
+            .collect();
+        a.try_dealloc(ptrs[1], layout.clone());
+
+        *ptrs[1].as_ptr().offset(128) = 0x42;
+        match a.verify_integrity() {
+            Err(wee_alloc::IntegrityError::FreeCellNotPoisoned(_)) => {}
+            otherwise => panic!("expected a poisoning error, found {:?}", otherwise),
+        }
+    }
+}
+
+#[test]
+fn oom_hook_is_called_on_failure() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static OOM_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+    fn hook(layout: Layout) {
+        OOM_SIZE.store(layout.size(), Ordering::SeqCst);
+    }
+
+    let a = wee_alloc::WeeAlloc::INIT;
+    a.set_oom_hook(Some(hook));
+    unsafe {
+        let layout = Layout::new::<u64>();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr, layout);
+        assert_eq!(OOM_SIZE.load(Ordering::SeqCst), 0);
+
+        let layout = Layout::from_size_align(std::usize::MAX, 1).unwrap();
+        assert!(a.try_alloc(layout).is_err());
+        assert_eq!(OOM_SIZE.load(Ordering::SeqCst), std::usize::MAX);
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+#[should_panic(expected = "double free or corruption")]
+fn double_free_small() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::new::<u64>();
+        let _keep_neighbor_allocated = a.try_alloc(layout.clone()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr, layout.clone());
+        a.try_dealloc(ptr, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+#[should_panic(expected = "double free or corruption")]
+fn double_free_large() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr, layout.clone());
+        a.try_dealloc(ptr, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+#[should_panic(expected = "not a wee_alloc pointer")]
+fn dealloc_foreign_pointer() {
+    static mut NOT_OURS: [usize; 8] = [0; 8];
+
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let _ptr = a.try_alloc(layout.clone()).unwrap();
+        let foreign = NonNull::new(NOT_OURS[4..].as_mut_ptr() as *mut u8).unwrap();
+        a.try_dealloc(foreign, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+#[should_panic(expected = "not a wee_alloc pointer")]
+fn dealloc_unaligned_pointer() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        let unaligned = NonNull::new(ptr.as_ptr().offset(1)).unwrap();
+        a.try_dealloc(unaligned, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "track_peak")]
+fn peak_bytes_grows_with_heap() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(1024 * 1024, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        assert!(a.peak_bytes().0 >= layout.size());
+        a.try_dealloc(ptr, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "count_allocations")]
+fn counters_count_allocs_and_deallocs() {
+    let a = wee_alloc::WeeAlloc::new();
+    unsafe {
+        // Other tests run concurrently and share the counters, so only check
+        // that ours are reflected in them.
+        let before = a.counters();
+        let layout = Layout::from_size_align(64, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr, layout);
+        let after = a.counters();
+
+        assert!(after.allocs >= before.allocs + 1);
+        assert!(after.deallocs >= before.deallocs + 1);
+        assert!(after.first_fit_hits + after.refills > before.first_fit_hits + before.refills);
+    }
+}
+
+#[test]
+#[cfg(feature = "count_allocations")]
+fn counters_count_realloc_bytes() {
+    let a = wee_alloc::WeeAlloc::new();
+    let before = a.counters();
+    // Growing from one byte up to a word stays within the first cell, and
+    // growing past it has to move.
+    Operations::growing_vecs(1, 8).run_with_allocator(&a);
+    let after = a.counters();
+
+    let word = mem::size_of::<usize>();
+    assert!(after.realloc_bytes_grown_in_place >= before.realloc_bytes_grown_in_place + word - 1);
+    assert!(after.realloc_bytes_copied > before.realloc_bytes_copied);
+}
+
+#[test]
+#[cfg(feature = "growable_backend")]
+fn growable_backend_allocations_are_in_heap() {
+    let a = wee_alloc::WeeAlloc::new();
+    unsafe {
+        let layout = Layout::from_size_align(128 * 1024, mem::size_of::<usize>()).unwrap();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        let addr = ptr.as_ptr() as usize;
+        wee_alloc::with_growable_backend_heap(|heap| {
+            let start = heap.as_ptr() as usize;
+            let end = start + heap.len();
+            assert!(start <= addr && addr + layout.size() <= end);
+        });
+        a.try_dealloc(ptr, layout);
+    }
+}
+
+#[test]
+fn independent_allocators() {
+    let a = wee_alloc::WeeAlloc::new();
+    let b = wee_alloc::WeeAlloc::new();
+    unsafe {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let ptr_a = a.try_alloc(layout.clone()).unwrap();
+        a.try_dealloc(ptr_a, layout.clone());
+
+        // `b` does not get to reuse the cell that was freed into `a`.
+        let ptr_b = b.try_alloc(layout.clone()).unwrap();
+        assert_ne!(ptr_a, ptr_b);
+        b.try_dealloc(ptr_b, layout.clone());
+
+        assert_eq!(a.try_alloc(layout.clone()).unwrap(), ptr_a);
+    }
+}
+
 // This takes too long with our extra assertion checks enabled,
 // and the fixed-sized static array backend is too small.
 #[test]
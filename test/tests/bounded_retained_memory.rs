@@ -0,0 +1,53 @@
+// Like `pages_in_use.rs`, this lives in its own test binary: the second run
+// has to find the heap just as the first run left it.
+
+extern crate memory_units;
+extern crate quickcheck;
+extern crate wee_alloc;
+extern crate wee_alloc_test;
+
+use memory_units::Pages;
+use wee_alloc_test::Operations;
+
+// Every sequence of `Operations` frees everything it allocates by the end, so
+// running it a second time on the same allocator should be served entirely
+// from the free lists, without asking for a single page more.
+fn second_run_reuses_memory(ops: Operations) {
+    use std::panic;
+
+    let result = panic::catch_unwind(|| {
+        let a = wee_alloc::WeeAlloc::INIT;
+        ops.run_with_allocator(&a);
+
+        let before = a.pages_in_use();
+        ops.run_with_allocator(&a);
+        let rerun = a.pages_in_use() - before;
+        assert_eq!(
+            rerun,
+            Pages(0),
+            "running the same operations a second time grew the heap"
+        );
+    });
+
+    if let Err(e) = result {
+        let trace = "failing-second_run_reuses_memory.trace";
+        match ops.save_failing_case(trace) {
+            Ok(()) => eprintln!("Saved failing operations to traces/{}", trace),
+            Err(err) => eprintln!("Failed to save failing operations: {}", err),
+        }
+        panic::resume_unwind(e);
+    }
+}
+
+// Known failing: this is #106. Not all of the cells that the first run frees
+// are merged back into cells big enough for what the second run asks for, so
+// it grows the heap again. That happens with every free list configuration,
+// size classes and buddy classes included; `address_ordered_free_list` gets
+// closest, but still grows now and then. Run with `--ignored` to check a fix.
+#[test]
+#[ignore = "known failing: the second run still grows the heap (#106)"]
+fn retained_memory_is_bounded() {
+    quickcheck::QuickCheck::new()
+        .tests(1)
+        .quickcheck(second_run_reuses_memory as fn(Operations) -> ());
+}
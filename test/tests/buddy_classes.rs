@@ -0,0 +1,32 @@
+// Like `pages_in_use.rs`, this lives in its own test binary, so that the page
+// count after each replay of the trace is down to the trace alone.
+
+extern crate wee_alloc;
+extern crate wee_alloc_test;
+
+use wee_alloc_test::Operations;
+
+// Vectors that keep doubling in size, side by side, are what made the heap grow
+// without bound in #106: each freed buffer is too small for the next doubling,
+// and is never merged with the others. Buddy blocks are merged back together as
+// soon as both halves are free, so once the first run has grown the heap,
+// replaying the same trace again never needs any more pages.
+#[test]
+#[cfg_attr(not(feature = "buddy_classes"), ignore)]
+fn growing_vecs_do_not_grow_the_heap() {
+    let ops = Operations::read_trace("growing-vecs.trace");
+    let a = wee_alloc::WeeAlloc::INIT;
+
+    ops.run_with_allocator(&a);
+    let after_first_run = a.pages_in_use();
+
+    for i in 1..10 {
+        ops.run_with_allocator(&a);
+        assert_eq!(
+            a.pages_in_use(),
+            after_first_run,
+            "run {} of the trace grew the heap",
+            i + 1
+        );
+    }
+}
@@ -0,0 +1,59 @@
+// Like `pages_in_use.rs`, this lives in its own test binary, so that it can
+// count its own grows by watching the page count change.
+//
+// `test.sh` runs this with `WEE_ALLOC_GROW_PERCENT` both unset and set to 150.
+
+extern crate wee_alloc;
+
+use std::alloc::Layout;
+
+fn grow_percent() -> usize {
+    option_env!("WEE_ALLOC_GROW_PERCENT").map_or(0, |percent| percent.parse().unwrap())
+}
+
+#[test]
+fn bursts_grow_the_heap_in_batches() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let start = a.pages_in_use();
+    let mut last = start;
+    let mut grows = 0;
+    unsafe {
+        for _ in 0..100_000 {
+            a.try_alloc(layout.clone()).unwrap();
+            let now = a.pages_in_use();
+            if now != last {
+                grows += 1;
+                last = now;
+            }
+        }
+    }
+
+    // Each grow asks for more pages than the one before it, up to 16 pages,
+    // so the number of grows falls further and further behind the number of
+    // pages. Without batching, every page of a burst of small allocations is
+    // a grow of its own.
+    let pages = (last - start).0;
+    assert!(grows > 0);
+    // Size classes ask for bigger and bigger segments by themselves, but the
+    // main free list only asks for what each refill needs.
+    if grow_percent() == 0
+        && !cfg!(any(
+            feature = "size_classes",
+            feature = "log_spaced_size_classes"
+        ))
+        && option_env!("WEE_ALLOC_MIN_GROW_PAGES").is_none()
+        && option_env!("WEE_ALLOC_INITIAL_PAGES").is_none()
+    {
+        assert_eq!(grows, pages, "without batching, every grow is a single page");
+    }
+    if grow_percent() > 100 {
+        assert!(
+            grows * 4 <= pages,
+            "grew {} times for {} pages",
+            grows,
+            pages
+        );
+    }
+}
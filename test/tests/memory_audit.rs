@@ -0,0 +1,24 @@
+// This lives in its own test binary, so that nothing else writes to the
+// `growable_backend` heap while it is being audited.
+
+extern crate wee_alloc_test;
+
+#[cfg(feature = "growable_backend")]
+use wee_alloc_test::Operations;
+
+macro_rules! audit_trace {
+    ($name:ident, $trace:expr) => {
+        #[test]
+        #[cfg(feature = "growable_backend")]
+        fn $name() {
+            Operations::read_trace($trace).run_with_memory_audit();
+        }
+    };
+}
+
+// Copying the whole heap around every operation is slow, so stick to the
+// smaller traces.
+audit_trace!(audit_trace_cpp_demangle, "cpp-demangle.trace");
+audit_trace!(audit_trace_grep_random_data, "grep-random-data.trace");
+audit_trace!(audit_trace_growing_vecs, "growing-vecs.trace");
+audit_trace!(audit_trace_ls, "ls.trace");
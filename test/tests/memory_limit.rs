@@ -0,0 +1,49 @@
+// This lives in its own test binary, because the memory limit is global, and
+// would make other tests' allocations fail.
+
+extern crate memory_units;
+extern crate wee_alloc;
+
+use memory_units::{Bytes, Pages};
+use std::alloc::Layout;
+use std::ptr::NonNull;
+
+#[test]
+fn allocations_fail_cleanly_at_the_memory_limit() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    let layout = Layout::from_size_align(1024, 8).unwrap();
+
+    let limit: Bytes = (a.pages_in_use() + Pages(64)).into();
+    a.set_memory_limit(limit);
+
+    let mut ptrs: Vec<NonNull<u8>> = vec![];
+    loop {
+        match unsafe { a.try_alloc(layout.clone()) } {
+            Ok(p) => ptrs.push(p),
+            Err(_) => break,
+        }
+        assert!(
+            ptrs.len() < 1024,
+            "allocated more than the memory limit allows"
+        );
+    }
+    assert!(!ptrs.is_empty());
+    let in_use: Bytes = a.pages_in_use().into();
+    assert!(in_use <= limit);
+
+    // Freed memory can still be reused under the limit.
+    let p = ptrs.pop().unwrap();
+    unsafe {
+        a.try_dealloc(p, layout.clone());
+        ptrs.push(a.try_alloc(layout.clone()).unwrap());
+    }
+
+    // Lifting the limit lets the heap grow again.
+    a.set_memory_limit(Bytes(0));
+    unsafe {
+        ptrs.push(a.try_alloc(layout.clone()).unwrap());
+        for p in ptrs {
+            a.try_dealloc(p, layout.clone());
+        }
+    }
+}
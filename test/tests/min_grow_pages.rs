@@ -0,0 +1,41 @@
+// Like `pages_in_use.rs`, this lives in its own test binary, so that every
+// change in the page count it sees is one of its own grows.
+//
+// `test.sh` runs this with `WEE_ALLOC_MIN_GROW_PAGES` both unset and raised.
+
+extern crate memory_units;
+extern crate wee_alloc;
+
+use memory_units::Pages;
+use std::alloc::Layout;
+
+fn min_grow_pages() -> usize {
+    option_env!("WEE_ALLOC_MIN_GROW_PAGES").map_or(1, |pages| pages.parse().unwrap())
+}
+
+#[test]
+fn heap_grows_by_at_least_min_grow_pages() {
+    let min_grow_pages = min_grow_pages();
+    let a = wee_alloc::WeeAlloc::INIT;
+    let layout = Layout::from_size_align(64, 8).unwrap();
+
+    let start = a.pages_in_use();
+    let mut last = start;
+    let mut grows = 0;
+    unsafe {
+        for _ in 0..100_000 {
+            a.try_alloc(layout.clone()).unwrap();
+            let now = a.pages_in_use();
+            if now != last {
+                assert!(now - last >= Pages(min_grow_pages));
+                grows += 1;
+                last = now;
+            }
+        }
+    }
+
+    // Raising the minimum means proportionally fewer trips to the backend for
+    // the same burst of allocations.
+    assert!(grows > 0);
+    assert!(grows <= (last - start).0 / min_grow_pages);
+}
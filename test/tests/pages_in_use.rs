@@ -0,0 +1,26 @@
+// `pages_in_use` counts the pages of every `WeeAlloc` in the process, so tests
+// that watch it each live in their own test binary, where no other test grows
+// the heap while they're watching it.
+
+extern crate wee_alloc;
+
+use std::alloc::Layout;
+
+#[test]
+fn pages_in_use_only_grows_on_refill() {
+    let a = wee_alloc::WeeAlloc::INIT;
+    unsafe {
+        let layout = Layout::from_size_align(1024 * 1024, 8).unwrap();
+
+        let before = a.pages_in_use();
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        let after_refill = a.pages_in_use();
+        assert!(after_refill > before);
+        a.try_dealloc(ptr, layout.clone());
+
+        // This reuses the cell we just freed, without growing the heap.
+        let ptr = a.try_alloc(layout.clone()).unwrap();
+        assert_eq!(a.pages_in_use(), after_refill);
+        a.try_dealloc(ptr, layout);
+    }
+}
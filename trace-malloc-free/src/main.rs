@@ -52,10 +52,10 @@ main!(|cli: Cli| {
         Regex::new(r#"^\-\-\d+\-\- realloc\(0x(?P<orig>\w+),(?P<size>\d+)\) = 0x(?P<new>\w+)$"#)
             .unwrap();
 
-    // TODO: record the requested alignment and replay that as well.
-    let memalign_re =
-        Regex::new(r#"r#"^\-\-\d+\-\- memalign\(al \d+, size (?P<size>\d+)\) = 0x(?P<ptr>\w+)$"#)
-            .unwrap();
+    let memalign_re = Regex::new(
+        r#"^\-\-\d+\-\- memalign\(al (?P<align>\d+), size (?P<size>\d+)\) = 0x(?P<ptr>\w+)$"#,
+    )
+    .unwrap();
 
     let free_re = Regex::new(r#"^\-\-\d+\-\- free\(0x(?P<ptr>\w+)\)$"#).unwrap();
 
@@ -87,30 +87,33 @@ main!(|cli: Cli| {
         }
 
         if let Some(captures) = realloc_re.captures(line) {
-            // Reallocs get treated as a free and new alloc.
+            // The result of a realloc counts as a new allocation, and the
+            // original allocation is considered freed.
             let orig = usize::from_str_radix(captures.name("orig").unwrap().as_str(), 16)?;
             let size: usize = captures.name("size").unwrap().as_str().parse()?;
             let new = usize::from_str_radix(captures.name("new").unwrap().as_str(), 16)?;
 
-            if let Some(idx) = active_mallocs.remove(&orig) {
-                writeln!(&mut output, "Free({}),", idx)?;
-            }
+            let orig_idx = active_mallocs.remove(&orig);
 
             active_mallocs.insert(new, num_mallocs);
             num_mallocs += 1;
 
-            writeln!(&mut output, "Alloc({}),", size)?;
+            match orig_idx {
+                Some(idx) => writeln!(&mut output, "Realloc({}, {}),", idx, size)?,
+                None => writeln!(&mut output, "Alloc({}),", size)?,
+            }
             continue;
         }
 
         if let Some(captures) = memalign_re.captures(line) {
+            let align: usize = captures.name("align").unwrap().as_str().parse()?;
             let size: usize = captures.name("size").unwrap().as_str().parse()?;
             let ptr = usize::from_str_radix(captures.name("ptr").unwrap().as_str(), 16)?;
 
             active_mallocs.insert(ptr, num_mallocs);
             num_mallocs += 1;
 
-            writeln!(&mut output, "Alloc({}),", size)?;
+            writeln!(&mut output, "Alloc({}, {}),", size, align)?;
             continue;
         }
 
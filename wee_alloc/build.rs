@@ -1,38 +1,130 @@
 use std::env::{self, VarError};
+use std::fmt::Display;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
 
 const DEFAULT_STATIC_ARRAY_BACKEND_SIZE_BYTES: u32 = 1024 * 1024 * 32;
 const WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES: &'static str = "WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES";
 
+// Cargo features that pick the static array backend's size, for when setting
+// an environment variable isn't practical. If several are enabled, the largest
+// wins.
+const STATIC_ARRAY_BACKEND_SIZE_FEATURES: [(&'static str, u32); 3] = [
+    ("CARGO_FEATURE_STATIC_128MIB", 1024 * 1024 * 128),
+    ("CARGO_FEATURE_STATIC_32MIB", 1024 * 1024 * 32),
+    ("CARGO_FEATURE_STATIC_8MIB", 1024 * 1024 * 8),
+];
+
+const DEFAULT_INITIAL_PAGES: usize = 0;
+const WEE_ALLOC_INITIAL_PAGES: &'static str = "WEE_ALLOC_INITIAL_PAGES";
+
+// Zero means that `wee_alloc` picks its own default.
+const DEFAULT_MIN_CELL_WORDS: usize = 0;
+const WEE_ALLOC_MIN_CELL_WORDS: &'static str = "WEE_ALLOC_MIN_CELL_WORDS";
+
+const DEFAULT_MIN_GROW_PAGES: usize = 1;
+const WEE_ALLOC_MIN_GROW_PAGES: &'static str = "WEE_ALLOC_MIN_GROW_PAGES";
+
+// Zero means that the heap only grows by as much as each allocation needs.
+const DEFAULT_GROW_PERCENT: usize = 0;
+const WEE_ALLOC_GROW_PERCENT: &'static str = "WEE_ALLOC_GROW_PERCENT";
+
+// Zero means that there is no maximum.
+const DEFAULT_MAX_PAGES: usize = 0;
+const WEE_ALLOC_MAX_PAGES: &'static str = "WEE_ALLOC_MAX_PAGES";
+
 fn main() {
-    create_static_array_backend_size_bytes_file();
+    let size = env_or(
+        WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES,
+        STATIC_ARRAY_BACKEND_SIZE_FEATURES
+            .iter()
+            .find(|&&(feature, _)| env::var_os(feature).is_some())
+            .map(|&(_, size)| size)
+            .unwrap_or(DEFAULT_STATIC_ARRAY_BACKEND_SIZE_BYTES),
+    );
+    write_const("wee_alloc_static_array_backend_size_bytes.txt", size);
+
+    let pages = env_or(WEE_ALLOC_INITIAL_PAGES, DEFAULT_INITIAL_PAGES);
+    // Only the first allocator to grow the heap gets the initial pages.
+    assert!(
+        pages == 0 || !deterministic(),
+        "WEE_ALLOC_INITIAL_PAGES can't be used with the `deterministic` feature"
+    );
+    write_const("wee_alloc_initial_pages.txt", pages);
+
+    let words = env_or(WEE_ALLOC_MIN_CELL_WORDS, DEFAULT_MIN_CELL_WORDS);
+    // A free cell uses the first word of its data for the free list, so zero
+    // is only good as the default, which lets `wee_alloc` pick.
+    assert!(
+        words >= 1 || env::var_os(WEE_ALLOC_MIN_CELL_WORDS).is_none(),
+        "WEE_ALLOC_MIN_CELL_WORDS must be at least 1"
+    );
+    write_const("wee_alloc_min_cell_words.txt", words);
+
+    let pages = env_or(WEE_ALLOC_MIN_GROW_PAGES, DEFAULT_MIN_GROW_PAGES);
+    assert!(pages >= 1, "WEE_ALLOC_MIN_GROW_PAGES must be at least 1");
+    write_const("wee_alloc_min_grow_pages.txt", pages);
+
+    let percent = env_or(WEE_ALLOC_GROW_PERCENT, DEFAULT_GROW_PERCENT);
+    // A batch depends on how the heap grew before, and falls back to a smaller
+    // grow when it can't be had.
+    assert!(
+        percent == 0 || !deterministic(),
+        "WEE_ALLOC_GROW_PERCENT can't be used with the `deterministic` feature"
+    );
+    write_const("wee_alloc_grow_percent.txt", percent);
+
+    let pages = env_or(WEE_ALLOC_MAX_PAGES, DEFAULT_MAX_PAGES);
+    write_const("wee_alloc_max_pages.txt", pages);
+
     export_rerun_rules();
 }
 
-fn create_static_array_backend_size_bytes_file() {
+// The unsigned integer in the environment variable `var`, or `default` if it
+// isn't set.
+fn env_or<T: FromStr>(var: &str, default: T) -> T {
+    match env::var(var) {
+        Ok(s) => s
+            .parse()
+            .unwrap_or_else(|_| panic!("Could not interpret {} as an unsigned integer", var)),
+        Err(VarError::NotPresent) => default,
+        Err(VarError::NotUnicode(_)) => panic!(
+            "Could not interpret {} as a string representing an unsigned integer",
+            var
+        ),
+    }
+}
+
+// Write `value` to the file `name` in `OUT_DIR`, for the crate to `include!` as
+// a constant.
+fn write_const<T: Display>(name: &str, value: T) {
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR environment variable not provided");
-    let dest_path = Path::new(&out_dir).join("wee_alloc_static_array_backend_size_bytes.txt");
-    let size: u32 = match env::var(WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES) {
-        Ok(s) => s.parse().expect("Could not interpret WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES as a 32 bit unsigned integer"),
-        Err(ve) => match ve {
-            VarError::NotPresent => { DEFAULT_STATIC_ARRAY_BACKEND_SIZE_BYTES },
-            VarError::NotUnicode(_) => { panic!("Could not interpret WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES as a string representing a 32 bit unsigned integer")},
-        },
-    };
+    let dest_path = Path::new(&out_dir).join(name);
     let mut f = File::create(&dest_path)
-        .expect("Could not create file to store wee_alloc static_array_backend size metadata.");
-    write!(f, "{}", size)
-        .expect("Could not write to wee_alloc static_array_backend size metadata file");
-    f.flush()
-        .expect("Could not flush write to wee_alloc static_array_backend size metadata file");
+        .unwrap_or_else(|e| panic!("Could not create {}: {}", dest_path.display(), e));
+    write!(f, "{}", value)
+        .and_then(|()| f.flush())
+        .unwrap_or_else(|e| panic!("Could not write to {}: {}", dest_path.display(), e));
 }
+
+// Whether the `deterministic` feature is enabled, which rules out sizing a grow
+// by anything but the allocation at hand.
+fn deterministic() -> bool {
+    env::var_os("CARGO_FEATURE_DETERMINISTIC").is_some()
+}
+
 fn export_rerun_rules() {
     println!(
         "cargo:rerun-if-env-changed={}",
         WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES
     );
+    println!("cargo:rerun-if-env-changed={}", WEE_ALLOC_INITIAL_PAGES);
+    println!("cargo:rerun-if-env-changed={}", WEE_ALLOC_MIN_CELL_WORDS);
+    println!("cargo:rerun-if-env-changed={}", WEE_ALLOC_MIN_GROW_PAGES);
+    println!("cargo:rerun-if-env-changed={}", WEE_ALLOC_GROW_PERCENT);
+    println!("cargo:rerun-if-env-changed={}", WEE_ALLOC_MAX_PAGES);
     for path in [
         "./Cargo.toml",
         "./build.rs",
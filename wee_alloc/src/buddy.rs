@@ -0,0 +1,348 @@
+//! Power-of-two free lists with buddy coalescing, used instead of size classes
+//! with the `buddy_classes` feature.
+//!
+//! Small allocations are served from arenas that are carved out of the main
+//! free list. Each arena is split into blocks whose sizes are powers of two,
+//! and every block is aligned to its own size. A block's buddy is the other
+//! half of the block that it was split from, so it can be found with a single
+//! xor. When a block is freed and its buddy is free as well, the two are merged
+//! back together, and so on up, so that freed memory never stays stranded in
+//! the free list of a single size.
+
+use super::{alloc_with_refill, dealloc_from_free_list, AllocErr, FreeCell, LargeAllocPolicy};
+use const_init::ConstInit;
+use core::cell::Cell;
+use core::cmp;
+use core::fmt;
+use core::ptr::{self, NonNull};
+use imp;
+use memory_units::{size_of, Bytes, Words};
+
+// Every arena is this big, and aligned to its size, so that a block's arena can
+// be found by masking off the low bits of its address.
+const ARENA_SIZE: usize = 8 * 1024;
+
+// The largest block is half an arena: the first half always holds the arena's
+// bitmap, so it can never be handed out whole.
+const MAX_ORDER: u32 = 12;
+
+// The smallest block has to have room for a `FreeBlock`.
+#[cfg(target_pointer_width = "64")]
+const MIN_ORDER: u32 = 5;
+#[cfg(not(target_pointer_width = "64"))]
+const MIN_ORDER: u32 = 4;
+
+const NUM_ORDERS: usize = (MAX_ORDER - MIN_ORDER + 1) as usize;
+
+// The arena's first block holds a bitmap with a bit for every smallest block in
+// the arena, which is set when a free block starts there. It is never handed
+// out, so the bits for the blocks that it covers are never set.
+const BITMAP_SIZE: usize = ARENA_SIZE >> MIN_ORDER >> 3;
+#[cfg(target_pointer_width = "64")]
+const BITMAP_ORDER: u32 = 5;
+#[cfg(not(target_pointer_width = "64"))]
+const BITMAP_ORDER: u32 = 6;
+
+/// The header of a free block, linking it into the free list for its order.
+#[repr(C)]
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+    order: usize,
+}
+
+/// The free lists for each order, from `MIN_ORDER` up to `MAX_ORDER`, and the
+/// number of arenas that their blocks come from.
+pub(crate) struct FreeLists([*mut FreeBlock; NUM_ORDERS], usize);
+
+impl ConstInit for FreeLists {
+    const INIT: FreeLists = FreeLists([ptr::null_mut(); NUM_ORDERS], 0);
+}
+
+/// Free lists of power-of-two sized blocks, from 16 bytes (32 bytes on 64-bit
+/// targets) up to a page.
+pub(crate) struct BuddyClasses(imp::Exclusive<FreeLists>);
+
+impl ConstInit for BuddyClasses {
+    const INIT: BuddyClasses = BuddyClasses(imp::Exclusive::INIT);
+}
+
+impl BuddyClasses {
+    /// The order of the blocks that serve allocations of `size` words with the
+    /// given alignment, or `None` if those are too big and come from the main
+    /// free list instead.
+    #[inline]
+    pub(crate) fn order(size: Words, align: Bytes) -> Option<u32> {
+        let size: Bytes = size.into();
+        let size = cmp::max(size.0, align.0);
+        if size > 1 << MAX_ORDER {
+            return None;
+        }
+        Some(cmp::max(
+            size.next_power_of_two().trailing_zeros(),
+            MIN_ORDER,
+        ))
+    }
+
+    /// Allocate a block of the given order, carving a new arena out of the
+    /// main free list if there is no free block big enough.
+    pub(crate) unsafe fn alloc<'a>(
+        &self,
+        order: u32,
+        main_head: &imp::Exclusive<*const FreeCell<'a>>,
+        large_policy: &LargeAllocPolicy,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        extra_assert!((MIN_ORDER..=MAX_ORDER).contains(&order));
+        self.0.with_exclusive_access(|lists| {
+            let mut current = match lists.smallest_nonempty(order) {
+                Some(current) => current,
+                None => {
+                    // A new arena has a free block of every order from
+                    // `BITMAP_ORDER` up to `MAX_ORDER`.
+                    lists.push_arena(new_arena(main_head, large_policy)?);
+                    cmp::max(order, BITMAP_ORDER)
+                }
+            };
+
+            // Split the block until it is the right size, freeing the upper
+            // halves as we go.
+            let block = lists.pop(current);
+            while current > order {
+                current -= 1;
+                lists.push(block.offset(1 << current), current);
+            }
+
+            Ok(NonNull::new_unchecked(block))
+        })
+    }
+
+    /// Free a block of the given order, merging it with its buddy for as long
+    /// as the buddy is free too. If that leaves its whole arena free, the arena
+    /// goes back to the main free list, unless it is the last one.
+    pub(crate) unsafe fn dealloc<'a>(
+        &self,
+        ptr: NonNull<u8>,
+        order: u32,
+        main_head: &imp::Exclusive<*const FreeCell<'a>>,
+        large_policy: &LargeAllocPolicy,
+    ) {
+        extra_assert!((MIN_ORDER..=MAX_ORDER).contains(&order));
+        extra_assert!(
+            ptr.as_ptr() as usize & ((1 << order) - 1) == 0,
+            "buddy blocks are aligned to their size"
+        );
+        self.0.with_exclusive_access(|lists| {
+            let mut block = ptr.as_ptr();
+            let mut order = order;
+            // The bitmap makes double frees cheap to catch, so do it whenever
+            // they are being looked for.
+            if cfg!(any(
+                feature = "extra_assertions",
+                feature = "zero_header_on_free"
+            )) {
+                assert!(
+                    !is_free(block),
+                    "double free: the buddy block at {:p} is already free",
+                    block
+                );
+            }
+
+            while order < MAX_ORDER {
+                let buddy = (block as usize ^ (1 << order)) as *mut u8;
+                if !is_free(buddy) || (*(buddy as *const FreeBlock)).order != order as usize {
+                    break;
+                }
+                lists.remove(buddy, order);
+                block = cmp::min(block, buddy);
+                order += 1;
+            }
+
+            lists.push(block, order);
+
+            // Keep one arena around even when it is empty, so that a program
+            // that allocates and frees a single small block over and over does
+            // not carve an arena out of the main free list every time.
+            let arena = (block as usize & !(ARENA_SIZE - 1)) as *mut u8;
+            if lists.1 > 1 && is_arena_free(arena) {
+                lists.remove_arena(arena);
+                free_arena(arena, main_head, large_policy);
+            }
+        })
+    }
+
+    /// Move every free block of `other` into these free lists. A block's buddy
+    /// is always in the same arena, and so was free in `other` too if it is
+    /// free at all, so there is nothing to merge.
+    pub(crate) unsafe fn absorb(&self, other: &mut BuddyClasses) {
+        let from = other.0.get_mut();
+        self.0.with_exclusive_access(|to| {
+            for order in MIN_ORDER..MAX_ORDER + 1 {
+                while !from.head(order).is_null() {
+                    let block = from.pop(order);
+                    to.push(block, order);
+                }
+            }
+            to.1 += from.1;
+            from.1 = 0;
+        })
+    }
+}
+
+impl FreeLists {
+    fn head(&mut self, order: u32) -> &mut *mut FreeBlock {
+        extra_assert!((MIN_ORDER..=MAX_ORDER).contains(&order));
+        unsafe { self.0.get_unchecked_mut((order - MIN_ORDER) as usize) }
+    }
+
+    fn smallest_nonempty(&mut self, order: u32) -> Option<u32> {
+        (order..MAX_ORDER + 1).find(|&order| !self.head(order).is_null())
+    }
+
+    unsafe fn push(&mut self, block: *mut u8, order: u32) {
+        let head = self.head(order);
+        let free = block as *mut FreeBlock;
+        ptr::write(
+            free,
+            FreeBlock {
+                next: *head,
+                prev: ptr::null_mut(),
+                order: order as usize,
+            },
+        );
+        if let Some(next) = (*head).as_mut() {
+            next.prev = free;
+        }
+        *head = free;
+        set_free(block, true);
+    }
+
+    unsafe fn pop(&mut self, order: u32) -> *mut u8 {
+        let block = *self.head(order) as *mut u8;
+        extra_assert!(!block.is_null());
+        self.remove(block, order);
+        block
+    }
+
+    unsafe fn remove(&mut self, block: *mut u8, order: u32) {
+        let free = &*(block as *const FreeBlock);
+        extra_assert!(free.order == order as usize);
+        match free.prev.as_mut() {
+            Some(prev) => prev.next = free.next,
+            None => *self.head(order) = free.next,
+        }
+        if let Some(next) = free.next.as_mut() {
+            next.prev = free.prev;
+        }
+        set_free(block, false);
+    }
+
+    // Free every block of a new arena, except for the first one, which holds
+    // the bitmap. The blocks double in size from there: the one right after
+    // the bitmap is as big as it, and the last one is half the arena.
+    unsafe fn push_arena(&mut self, arena: NonNull<u8>) {
+        let arena = arena.as_ptr();
+        ptr::write_bytes(arena, 0, BITMAP_SIZE);
+        for order in BITMAP_ORDER..MAX_ORDER + 1 {
+            self.push(arena.offset(1 << order), order);
+        }
+        self.1 += 1;
+    }
+
+    // Take every block of a wholly free arena out of the free lists.
+    unsafe fn remove_arena(&mut self, arena: *mut u8) {
+        for order in BITMAP_ORDER..MAX_ORDER + 1 {
+            self.remove(arena.offset(1 << order), order);
+        }
+        self.1 -= 1;
+    }
+}
+
+// Whether every block of `arena` but the bitmap is free. The bitmap's block is
+// never free, so its buddies can't merge past it, and the arena is split just
+// like `push_arena` left it. Check the biggest block first, since that is the
+// one most likely to be in use.
+unsafe fn is_arena_free(arena: *mut u8) -> bool {
+    (BITMAP_ORDER..MAX_ORDER + 1).rev().all(|order| {
+        let block = arena.offset(1 << order);
+        is_free(block) && (*(block as *const FreeBlock)).order == order as usize
+    })
+}
+
+// Get a new, suitably aligned arena from the main free list.
+unsafe fn new_arena<'a>(
+    main_head: &imp::Exclusive<*const FreeCell<'a>>,
+    large_policy: &LargeAllocPolicy,
+) -> Result<NonNull<u8>, AllocErr> {
+    let size = Words(ARENA_SIZE / size_of::<usize>().0);
+    main_head.with_exclusive_access(|head| {
+        let head_cell = Cell::new(*head);
+        let result = alloc_with_refill(size, Bytes(ARENA_SIZE), &head_cell, large_policy);
+        *head = head_cell.get();
+        result
+    })
+}
+
+// Give a wholly free arena back to the main free list.
+unsafe fn free_arena<'a>(
+    arena: *mut u8,
+    main_head: &imp::Exclusive<*const FreeCell<'a>>,
+    large_policy: &LargeAllocPolicy,
+) {
+    let size = Words(ARENA_SIZE / size_of::<usize>().0);
+    main_head.with_exclusive_access(|head| {
+        let head_cell = Cell::new(*head);
+        dealloc_from_free_list(NonNull::new_unchecked(arena), size, &head_cell, large_policy);
+        *head = head_cell.get();
+    })
+}
+
+// The bitmap byte and bit that record whether a free block starts at `block`.
+fn bitmap_bit(block: *mut u8) -> (*mut u8, u8) {
+    let addr = block as usize;
+    let arena = addr & !(ARENA_SIZE - 1);
+    let index = (addr - arena) >> MIN_ORDER;
+    ((arena + index / 8) as *mut u8, 1 << (index % 8))
+}
+
+unsafe fn is_free(block: *mut u8) -> bool {
+    let (byte, bit) = bitmap_bit(block);
+    *byte & bit != 0
+}
+
+unsafe fn set_free(block: *mut u8, free: bool) {
+    let (byte, bit) = bitmap_bit(block);
+    if free {
+        *byte |= bit;
+    } else {
+        *byte &= !bit;
+    }
+}
+
+impl fmt::Debug for BuddyClasses {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Show how many free blocks there are of each size.
+        let counts = unsafe {
+            self.0.try_with_exclusive_access(|lists| {
+                let mut counts = [0; NUM_ORDERS];
+                for (count, head) in counts.iter_mut().zip(lists.0.iter()) {
+                    let mut block = *head;
+                    while let Some(free) = block.as_ref() {
+                        *count += 1;
+                        block = free.next;
+                    }
+                }
+                counts
+            })
+        };
+        match counts {
+            Some(counts) => {
+                let mut map = f.debug_map();
+                for (i, count) in counts.iter().enumerate() {
+                    map.entry(&(1usize << (i as u32 + MIN_ORDER)), count);
+                }
+                map.finish()
+            }
+            None => write!(f, "<locked>"),
+        }
+    }
+}
@@ -7,3 +7,7 @@ pub(crate) trait ConstInit {
 impl<T> ConstInit for *const T {
     const INIT: Self = 0 as *mut _;
 }
+
+impl<T> ConstInit for Option<T> {
+    const INIT: Self = None;
+}
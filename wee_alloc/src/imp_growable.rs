@@ -0,0 +1,168 @@
+use super::{assert_is_word_aligned, AllocErr, PAGE_SIZE};
+use const_init::ConstInit;
+#[cfg(feature = "extra_assertions")]
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
+use core::slice;
+use memory_units::{Bytes, Pages};
+use spin::Mutex;
+use std::alloc::{GlobalAlloc, Layout, System};
+
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "growable";
+
+// The most memory that the heap can grow to. We reserve this much address space
+// up front, so that the heap never moves and pointers into it stay valid.
+const MAX_HEAP_BYTES: usize = 1 << 30;
+
+// A single, contiguous heap that grows a page at a time. It is allocated
+// directly from the system allocator, rather than with a `Vec`, so that we
+// don't re-enter `wee_alloc` when it is the global allocator.
+struct GrowableHeap {
+    base: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for GrowableHeap {}
+
+static HEAP: Mutex<GrowableHeap> = Mutex::new(GrowableHeap {
+    base: ptr::null_mut(),
+    len: 0,
+});
+
+pub(crate) unsafe fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr> {
+    let bytes: Bytes = pages.into();
+    let mut heap = HEAP.lock();
+
+    if heap.base.is_null() {
+        let layout = Layout::from_size_align(MAX_HEAP_BYTES, PAGE_SIZE.0).map_err(|_| AllocErr)?;
+        heap.base = System.alloc(layout);
+        if heap.base.is_null() {
+            return Err(AllocErr);
+        }
+    }
+
+    let end = heap.len.checked_add(bytes.0).ok_or(AllocErr)?;
+    if end > MAX_HEAP_BYTES {
+        return Err(AllocErr);
+    }
+
+    let ptr = heap.base.add(heap.len);
+    ptr::write_bytes(ptr, 0, bytes.0);
+    heap.len = end;
+
+    assert_is_word_aligned(ptr);
+    NonNull::new(ptr).ok_or(AllocErr)
+}
+
+/// Call `f` with all of the memory that the heap has grown to so far.
+pub(crate) unsafe fn with_heap<F, T>(f: F) -> T
+where
+    F: FnOnce(&[u8]) -> T,
+{
+    let heap = HEAP.lock();
+    if heap.base.is_null() {
+        f(&[])
+    } else {
+        f(slice::from_raw_parts(heap.base, heap.len))
+    }
+}
+
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    ::std::process::abort()
+}
+
+pub(crate) struct Exclusive<T> {
+    inner: Mutex<T>,
+
+    #[cfg(feature = "extra_assertions")]
+    in_use: Cell<bool>,
+}
+
+impl<T: ConstInit> ConstInit for Exclusive<T> {
+    const INIT: Self = Exclusive {
+        inner: Mutex::new(T::INIT),
+
+        #[cfg(feature = "extra_assertions")]
+        in_use: Cell::new(false),
+    };
+}
+
+extra_only! {
+    fn assert_not_in_use<T>(excl: &Exclusive<T>) {
+        assert!(!excl.in_use.get(), "`Exclusive<T>` is not re-entrant");
+    }
+}
+
+extra_only! {
+    fn set_in_use<T>(excl: &Exclusive<T>) {
+        excl.in_use.set(true);
+    }
+}
+
+extra_only! {
+    fn set_not_in_use<T>(excl: &Exclusive<T>) {
+        excl.in_use.set(false);
+    }
+}
+
+impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            inner: Mutex::new(inner),
+
+            #[cfg(feature = "extra_assertions")]
+            in_use: Cell::new(false),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        // `spin` 0.5's `Mutex` has no `get_mut`, but nobody else can be holding
+        // the lock, so taking it never spins.
+        let inner: *mut T = &mut *self.inner.lock();
+        unsafe { &mut *inner }
+    }
+
+    /// Get exclusive, mutable access to the inner value.
+    ///
+    /// # Safety
+    ///
+    /// It is the callers' responsibility to ensure that `f` does not re-enter
+    /// this method for this `Exclusive` instance.
+    #[inline]
+    pub(crate) unsafe fn with_exclusive_access<'a, F, U>(&'a self, f: F) -> U
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        let mut guard = self.inner.lock();
+        assert_not_in_use(self);
+        set_in_use(self);
+        let result = f(&mut guard);
+        set_not_in_use(self);
+        result
+    }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of blocking if
+    /// the lock is already held.
+    ///
+    /// # Safety
+    ///
+    /// Same as `with_exclusive_access`.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<'a, F, U>(&'a self, f: F) -> Option<U>
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        let mut guard = self.inner.try_lock()?;
+        assert_not_in_use(self);
+        set_in_use(self);
+        let result = f(&mut guard);
+        set_not_in_use(self);
+        Some(result)
+    }
+}
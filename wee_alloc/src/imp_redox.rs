@@ -0,0 +1,153 @@
+use super::AllocErr;
+use const_init::ConstInit;
+use core::cell::UnsafeCell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicI32, Ordering};
+use memory_units::{Bytes, Pages};
+use syscall;
+
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "redox";
+
+// `fmap`ping this file descriptor gives anonymous memory.
+const ANONYMOUS: usize = !0;
+
+pub(crate) fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr> {
+    let bytes: Bytes = pages.into();
+    let map = syscall::Map {
+        offset: 0,
+        size: bytes.0,
+        flags: syscall::PROT_READ | syscall::PROT_WRITE | syscall::MAP_PRIVATE,
+        address: 0,
+    };
+    match unsafe { syscall::fmap(ANONYMOUS, &map) } {
+        Ok(addr) => NonNull::new(addr as *mut u8).ok_or(AllocErr),
+        Err(_) => Err(AllocErr),
+    }
+}
+
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    if let Ok(pid) = syscall::getpid() {
+        let _ = syscall::kill(pid, syscall::SIGABRT);
+    }
+    // Exit with the same status as being killed by `SIGABRT`, in case the
+    // signal is ignored.
+    let _ = syscall::exit(128 + syscall::SIGABRT);
+    loop {}
+}
+
+// The states of an `Exclusive`'s futex.
+const UNLOCKED: i32 = 0;
+const LOCKED: i32 = 1;
+// Locked, and there may be threads waiting for it to be unlocked.
+const CONTENDED: i32 = 2;
+
+// Align to the cache line size on an i7 to prevent false sharing.
+#[repr(align(64))]
+pub(crate) struct Exclusive<T> {
+    lock: AtomicI32,
+    inner: UnsafeCell<T>,
+}
+
+impl<T: ConstInit> ConstInit for Exclusive<T> {
+    const INIT: Self = Exclusive {
+        lock: AtomicI32::new(UNLOCKED),
+        inner: UnsafeCell::new(T::INIT),
+    };
+}
+
+impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            lock: AtomicI32::new(UNLOCKED),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    fn futex(&self) -> *mut i32 {
+        &self.lock as *const AtomicI32 as *mut i32
+    }
+
+    fn try_lock(&self) -> bool {
+        self.lock
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+
+        // Mark the lock as contended, so that whoever holds it wakes us up
+        // when they unlock it, and sleep until then.
+        while self.lock.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+            let _ = unsafe {
+                syscall::futex(
+                    self.futex(),
+                    syscall::FUTEX_WAIT,
+                    CONTENDED,
+                    0,
+                    ptr::null_mut(),
+                )
+            };
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        if self.lock.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            let _ = syscall::futex(self.futex(), syscall::FUTEX_WAKE, 1, 0, ptr::null_mut());
+        }
+    }
+
+    /// Get exclusive, mutable access to the inner value.
+    ///
+    /// # Safety
+    ///
+    /// The lock is not reentrant. If `f` re-enters this method for the same
+    /// `Exclusive` instance, it will deadlock.
+    #[inline]
+    pub(crate) unsafe fn with_exclusive_access<F, U>(&self, f: F) -> U
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        self.lock();
+
+        let result = f(&mut *self.inner.get());
+
+        self.unlock();
+
+        result
+    }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of blocking if
+    /// the lock is already held.
+    ///
+    /// # Safety
+    ///
+    /// Same as `with_exclusive_access`.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<F, U>(&self, f: F) -> Option<U>
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        if !self.try_lock() {
+            return None;
+        }
+
+        let result = f(&mut *self.inner.get());
+
+        self.unlock();
+
+        Some(result)
+    }
+}
@@ -2,11 +2,15 @@ use super::AllocErr;
 use const_init::ConstInit;
 #[cfg(feature = "extra_assertions")]
 use core::cell::Cell;
+use core::cmp;
 use core::ptr::NonNull;
-use memory_units::{Bytes, Pages};
+use memory_units::{ByteSize, Bytes, Pages};
 use spin::Mutex;
 
-const SCRATCH_LEN_BYTES: usize = include!(concat!(
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "static_array";
+
+pub(crate) const SCRATCH_LEN_BYTES: usize = include!(concat!(
     env!("OUT_DIR"),
     "/wee_alloc_static_array_backend_size_bytes.txt"
 ));
@@ -15,11 +19,82 @@ const SCRATCH_LEN_BYTES: usize = include!(concat!(
 struct ScratchHeap([u8; SCRATCH_LEN_BYTES]);
 
 static mut SCRATCH_HEAP: ScratchHeap = ScratchHeap([0; SCRATCH_LEN_BYTES]);
-static mut OFFSET: Mutex<usize> = Mutex::new(0);
+static OFFSET: Mutex<usize> = Mutex::new(0);
+
+// The most runs of freed pages that we remember. Runs are usually many pages
+// long, since they are whole segments of the main free list, so budget one run
+// for every 16 pages. If we run out of room, the pages simply stay in the free
+// list.
+const MAX_FREE_RUNS: usize = SCRATCH_LEN_BYTES / Pages::BYTE_SIZE.0 / 16 + 1;
+
+// A stack of runs of pages below `OFFSET` that were handed out and then given
+// back, as `(offset, length)` pairs in bytes.
+struct FreeRuns {
+    runs: [(usize, usize); MAX_FREE_RUNS],
+    len: usize,
+}
+
+impl FreeRuns {
+    const fn new() -> FreeRuns {
+        FreeRuns {
+            runs: [(0, 0); MAX_FREE_RUNS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, offset: usize, len: usize) -> bool {
+        if self.len == MAX_FREE_RUNS {
+            return false;
+        }
+        self.runs[self.len] = (offset, len);
+        self.len += 1;
+        true
+    }
+
+    // Remove the runs right before and right after `offset..offset + len`,
+    // and return the run that covers all of them, so that freed neighbors
+    // can be handed out together again.
+    fn coalesce(&mut self, mut offset: usize, mut len: usize) -> (usize, usize) {
+        let mut i = 0;
+        while i < self.len {
+            let (run_offset, run_len) = self.runs[i];
+            if run_offset + run_len == offset || offset + len == run_offset {
+                offset = cmp::min(offset, run_offset);
+                len += run_len;
+                self.len -= 1;
+                self.runs[i] = self.runs[self.len];
+            } else {
+                i += 1;
+            }
+        }
+        (offset, len)
+    }
+
+    // Take `len` bytes from the most recently freed run that is big enough.
+    fn take(&mut self, len: usize) -> Option<usize> {
+        let i = (0..self.len).rev().find(|&i| self.runs[i].1 >= len)?;
+        let (offset, run_len) = self.runs[i];
+        if run_len == len {
+            self.len -= 1;
+            self.runs[i] = self.runs[self.len];
+        } else {
+            self.runs[i] = (offset + len, run_len - len);
+        }
+        Some(offset)
+    }
+}
+
+static FREE_RUNS: Mutex<FreeRuns> = Mutex::new(FreeRuns::new());
 
 pub(crate) unsafe fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr> {
     let bytes: Bytes = pages.into();
     let mut offset = OFFSET.lock();
+
+    if let Some(start) = FREE_RUNS.lock().take(bytes.0) {
+        let ptr = SCRATCH_HEAP.0[start..start + bytes.0].as_mut_ptr() as *mut u8;
+        return NonNull::new(ptr).ok_or_else(|| AllocErr);
+    }
+
     let end = bytes.0.checked_add(*offset).ok_or(AllocErr)?;
     if end < SCRATCH_LEN_BYTES {
         let ptr = SCRATCH_HEAP.0[*offset..end].as_mut_ptr() as *mut u8;
@@ -30,6 +105,52 @@ pub(crate) unsafe fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr>
     }
 }
 
+// Without an operating system, there's no portable way to abort. Trap on
+// WebAssembly, use the compiler intrinsic on nightly, and otherwise hang.
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            unsafe { ::core::arch::wasm32::unreachable() }
+        } else if #[cfg(feature = "nightly")] {
+            unsafe { ::core::intrinsics::abort() }
+        } else {
+            loop {}
+        }
+    }
+}
+
+/// Give pages obtained from `alloc_pages` back to the backend, so that a later
+/// call to `alloc_pages` may hand them out again.
+///
+/// Returns `false`, and leaves the pages alone, if they aren't whole pages of
+/// the scratch heap or there is no room to remember them.
+pub(crate) unsafe fn free_pages(ptr: NonNull<u8>, pages: Pages) -> bool {
+    let bytes: Bytes = pages.into();
+    let base = SCRATCH_HEAP.0[..].as_ptr() as usize;
+    let start = ptr.as_ptr() as usize;
+    if start < base || (start - base) % Pages::BYTE_SIZE.0 != 0 {
+        return false;
+    }
+
+    let start = start - base;
+    let mut offset = OFFSET.lock();
+    if start + bytes.0 > *offset {
+        return false;
+    }
+
+    let mut runs = FREE_RUNS.lock();
+    let (start, len) = runs.coalesce(start, bytes.0);
+
+    // If these are the last pages handed out, just bump them back.
+    if start + len == *offset {
+        *offset = start;
+        return true;
+    }
+
+    runs.push(start, len)
+}
+
 pub(crate) struct Exclusive<T> {
     inner: Mutex<T>,
 
@@ -65,6 +186,26 @@ extra_only! {
 }
 
 impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            inner: Mutex::new(inner),
+
+            #[cfg(feature = "extra_assertions")]
+            in_use: Cell::new(false),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        // `spin` 0.5's `Mutex` has no `get_mut`, but nobody else can be holding
+        // the lock, so taking it never spins.
+        let inner: *mut T = &mut *self.inner.lock();
+        unsafe { &mut *inner }
+    }
+
     /// Get exclusive, mutable access to the inner value.
     ///
     /// # Safety
@@ -86,4 +227,58 @@ impl<T> Exclusive<T> {
         set_not_in_use(self);
         result
     }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of blocking if
+    /// the lock is already held.
+    ///
+    /// # Safety
+    ///
+    /// Same as `with_exclusive_access`.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<'a, F, U>(&'a self, f: F) -> Option<U>
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        let mut guard = self.inner.try_lock()?;
+        assert_not_in_use(self);
+        set_in_use(self);
+        let result = f(&mut guard);
+        set_not_in_use(self);
+        Some(result)
+    }
+}
+
+#[test]
+fn free_runs_reuse_freed_pages() {
+    let mut runs = FreeRuns::new();
+    assert_eq!(runs.take(1), None);
+
+    assert!(runs.push(0, 3 * Pages::BYTE_SIZE.0));
+    assert!(runs.push(8 * Pages::BYTE_SIZE.0, Pages::BYTE_SIZE.0));
+
+    // The most recently freed run that fits is used first, and bigger runs
+    // are split.
+    assert_eq!(runs.take(Pages::BYTE_SIZE.0), Some(8 * Pages::BYTE_SIZE.0));
+    assert_eq!(runs.take(2 * Pages::BYTE_SIZE.0), Some(0));
+    assert_eq!(runs.take(2 * Pages::BYTE_SIZE.0), None);
+    assert_eq!(runs.take(Pages::BYTE_SIZE.0), Some(2 * Pages::BYTE_SIZE.0));
+    assert_eq!(runs.take(Pages::BYTE_SIZE.0), None);
+}
+
+#[test]
+fn free_runs_coalesce_with_their_neighbors() {
+    let page = Pages::BYTE_SIZE.0;
+    let mut runs = FreeRuns::new();
+    assert!(runs.push(0, page));
+    assert!(runs.push(2 * page, page));
+    assert!(runs.push(6 * page, page));
+
+    // Freeing the page between the first two runs joins all three pages into
+    // one run, and leaves the unrelated one alone.
+    assert_eq!(runs.coalesce(page, page), (0, 3 * page));
+    assert_eq!(runs.len, 1);
+    assert!(runs.push(0, 3 * page));
+    assert_eq!(runs.take(3 * page), Some(0));
+    assert_eq!(runs.take(page), Some(6 * page));
+    assert_eq!(runs.take(page), None);
 }
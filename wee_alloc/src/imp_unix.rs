@@ -5,17 +5,73 @@ use core::ptr;
 use libc;
 use memory_units::{Bytes, Pages};
 
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "unix";
+
+// Extra, optional `mmap` flags enabled via cargo features. These are only
+// supported on Linux, and are ignored everywhere else.
+
+#[cfg(all(
+    feature = "mmap_noreserve",
+    any(target_os = "linux", target_os = "android")
+))]
+const MAP_NORESERVE: libc::c_int = libc::MAP_NORESERVE;
+#[cfg(not(all(
+    feature = "mmap_noreserve",
+    any(target_os = "linux", target_os = "android")
+)))]
+const MAP_NORESERVE: libc::c_int = 0;
+
+// `MAP_HUGETLB` without a size flag asks for the default huge page size, which
+// is 2MiB on the common platforms. Mappings must be a multiple of it.
+#[cfg(all(
+    feature = "mmap_huge_pages",
+    any(target_os = "linux", target_os = "android")
+))]
+const HUGE_PAGE_SIZE: Bytes = Bytes(2 * 1024 * 1024);
+
+unsafe fn mmap(bytes: Bytes, flags: libc::c_int) -> *mut libc::c_void {
+    libc::mmap(
+        ptr::null_mut(),
+        bytes.0,
+        libc::PROT_WRITE | libc::PROT_READ,
+        libc::MAP_ANON | libc::MAP_PRIVATE | flags,
+        -1,
+        0,
+    )
+}
+
+#[cfg(all(
+    feature = "mmap_huge_pages",
+    any(target_os = "linux", target_os = "android")
+))]
+unsafe fn mmap_with_hints(bytes: Bytes) -> *mut libc::c_void {
+    if bytes.0.is_multiple_of(HUGE_PAGE_SIZE.0) {
+        let addr = mmap(bytes, libc::MAP_HUGETLB | MAP_NORESERVE);
+        if addr != libc::MAP_FAILED {
+            return addr;
+        }
+        // There might not be any huge pages configured. Try again with
+        // regular pages, but keep the other hints.
+    }
+    mmap(bytes, MAP_NORESERVE)
+}
+
+// `MAP_NORESERVE` is only a hint that the kernel ignores when it doesn't
+// overcommit memory, so it can't be the reason for `mmap` failing, and there
+// is nothing to retry without.
+#[cfg(not(all(
+    feature = "mmap_huge_pages",
+    any(target_os = "linux", target_os = "android")
+)))]
+unsafe fn mmap_with_hints(bytes: Bytes) -> *mut libc::c_void {
+    mmap(bytes, MAP_NORESERVE)
+}
+
 pub(crate) fn alloc_pages(pages: Pages) -> Result<ptr::NonNull<u8>, AllocErr> {
     unsafe {
         let bytes: Bytes = pages.into();
-        let addr = libc::mmap(
-            ptr::null_mut(),
-            bytes.0,
-            libc::PROT_WRITE | libc::PROT_READ,
-            libc::MAP_ANON | libc::MAP_PRIVATE,
-            -1,
-            0,
-        );
+        let addr = mmap_with_hints(bytes);
         if addr == libc::MAP_FAILED {
             Err(AllocErr)
         } else {
@@ -24,6 +80,11 @@ pub(crate) fn alloc_pages(pages: Pages) -> Result<ptr::NonNull<u8>, AllocErr> {
     }
 }
 
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    unsafe { libc::abort() }
+}
+
 // Align to the cache line size on an i7 to prevent false sharing.
 #[repr(align(64))]
 pub(crate) struct Exclusive<T> {
@@ -39,6 +100,21 @@ impl<T: ConstInit> ConstInit for Exclusive<T> {
 }
 
 impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            lock: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
     /// Get exclusive, mutable access to the inner value.
     ///
     /// # Safety
@@ -62,4 +138,28 @@ impl<T> Exclusive<T> {
 
         result
     }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of blocking if
+    /// the lock is already held.
+    ///
+    /// # Safety
+    ///
+    /// Same as `with_exclusive_access`.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<F, U>(&self, f: F) -> Option<U>
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        let code = libc::pthread_mutex_trylock(&mut *self.lock.get());
+        if code != 0 {
+            return None;
+        }
+
+        let result = f(&mut *self.inner.get());
+
+        let code = libc::pthread_mutex_unlock(&mut *self.lock.get());
+        extra_assert_eq!(code, 0, "pthread_mutex_unlock should run OK");
+
+        Some(result)
+    }
 }
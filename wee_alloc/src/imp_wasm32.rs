@@ -6,7 +6,21 @@ use core::cell::UnsafeCell;
 use core::ptr::NonNull;
 use memory_units::Pages;
 
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "wasm32";
+
+// The declared maximum size of this module's memory, in pages, from the
+// `WEE_ALLOC_MAX_PAGES` build-time environment variable, or zero if there is no
+// maximum.
+const MAX_PAGES: usize = include!(concat!(env!("OUT_DIR"), "/wee_alloc_max_pages.txt"));
+
 pub(crate) unsafe fn alloc_pages(n: Pages) -> Result<NonNull<u8>, AllocErr> {
+    // Growing memory beyond its maximum is bound to fail, so don't bother
+    // trying.
+    if MAX_PAGES != 0 && n.0 > MAX_PAGES.saturating_sub(wasm32::memory_size(0)) {
+        return Err(AllocErr);
+    }
+
     let ptr = wasm32::memory_grow(0, n.0);
     if ptr != usize::max_value() {
         let ptr = (ptr * PAGE_SIZE.0) as *mut u8;
@@ -17,6 +31,11 @@ pub(crate) unsafe fn alloc_pages(n: Pages) -> Result<NonNull<u8>, AllocErr> {
     }
 }
 
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    unsafe { wasm32::unreachable() }
+}
+
 pub(crate) struct Exclusive<T> {
     inner: UnsafeCell<T>,
 
@@ -52,6 +71,23 @@ extra_only! {
 }
 
 impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            inner: UnsafeCell::new(inner),
+
+            #[cfg(feature = "extra_assertions")]
+            in_use: Cell::new(false),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
     /// Get exclusive, mutable access to the inner value.
     ///
     /// # Safety
@@ -72,4 +108,26 @@ impl<T> Exclusive<T> {
         set_not_in_use(self);
         result
     }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of re-entering
+    /// if this `Exclusive` is already in use. Re-entrance can only be detected
+    /// when the "extra_assertions" feature is enabled.
+    ///
+    /// # Safety
+    ///
+    /// Same as `with_exclusive_access`.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<'a, F, U>(&'a self, f: F) -> Option<U>
+    where
+        for<'x> F: FnOnce(&'x mut T) -> U,
+    {
+        #[cfg(feature = "extra_assertions")]
+        {
+            if self.in_use.get() {
+                return None;
+            }
+        }
+
+        Some(self.with_exclusive_access(f))
+    }
 }
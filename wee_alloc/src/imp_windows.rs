@@ -6,17 +6,32 @@ use memory_units::{Bytes, Pages};
 
 use winapi::shared::ntdef::NULL;
 use winapi::um::memoryapi::VirtualAlloc;
+#[cfg(feature = "abort_on_oom")]
+use winapi::um::processthreadsapi::{GetCurrentProcess, TerminateProcess};
 use winapi::um::synchapi::{
     SRWLOCK, SRWLOCK_INIT, AcquireSRWLockExclusive, ReleaseSRWLockExclusive,
+    TryAcquireSRWLockExclusive,
 };
 use winapi::um::winnt::{MEM_COMMIT, PAGE_READWRITE};
 
+// This backend's name, for `wee_alloc::build_info`.
+pub(crate) const NAME: &str = "windows";
+
 pub(crate) fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr> {
     let bytes: Bytes = pages.into();
     let ptr = unsafe { VirtualAlloc(NULL, bytes.0, MEM_COMMIT, PAGE_READWRITE) };
     NonNull::new(ptr as *mut u8).ok_or(AllocErr)
 }
 
+#[cfg(feature = "abort_on_oom")]
+pub(crate) fn abort() -> ! {
+    // Exit with the same code as the C runtime's `abort`.
+    unsafe {
+        TerminateProcess(GetCurrentProcess(), 3);
+    }
+    loop {}
+}
+
 // Align to the cache line size on an i7 to avoid false sharing.
 #[repr(align(64))]
 pub(crate) struct Exclusive<T> {
@@ -32,6 +47,21 @@ impl<T: ConstInit> ConstInit for Exclusive<T> {
 }
 
 impl<T> Exclusive<T> {
+    /// Create a new `Exclusive` at runtime.
+    pub(crate) fn new(inner: T) -> Self {
+        Exclusive {
+            lock: UnsafeCell::new(SRWLOCK_INIT),
+            inner: UnsafeCell::new(inner),
+        }
+    }
+
+    /// Get mutable access to the inner value without taking the lock, which
+    /// `&mut self` already guarantees nobody else holds.
+    #[inline]
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
     /// Get exclusive, mutable access to the inner value.
     #[inline]
     pub(crate) unsafe fn with_exclusive_access<'a, F, U>(&'a self, f: F) -> U
@@ -46,4 +76,22 @@ impl<T> Exclusive<T> {
 
         result
     }
+
+    /// Like `with_exclusive_access`, but returns `None` instead of blocking if
+    /// the lock is already held.
+    #[inline]
+    pub(crate) unsafe fn try_with_exclusive_access<'a, F, U>(&'a self, f: F) -> Option<U>
+    where
+        F: FnOnce(&'a mut T) -> U,
+    {
+        if TryAcquireSRWLockExclusive(self.lock.get()) == 0 {
+            return None;
+        }
+
+        let result = f(&mut *self.inner.get());
+
+        ReleaseSRWLockExclusive(self.lock.get());
+
+        Some(result)
+    }
 }
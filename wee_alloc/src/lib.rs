@@ -20,8 +20,9 @@ for small code size. In contrast, `wee_alloc` would be a poor choice for a
 scenario where allocation is a performance bottleneck.
 
 Although WebAssembly is the primary target, `wee_alloc` also has an `mmap` based
-implementation for unix systems, a `VirtualAlloc` implementation for Windows,
-and a static array-based backend for OS-independent environments. This enables
+implementation for unix systems, an `fmap` based implementation for Redox, a
+`VirtualAlloc` implementation for Windows, and a static array-based backend for
+OS-independent environments. This enables
 testing `wee_alloc`, and code using `wee_alloc`, without a browser or
 WebAssembly engine.
 
@@ -50,29 +51,202 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
   provide amortized *O(1)* allocation for them. Increases uncompressed `.wasm`
   code size by about 450 bytes (up to a total of ~1.2K).
 
+- **log_spaced_size_classes**: Implies `size_classes`. Rather than giving every
+  size up to 256 words its own size class, space the size classes
+  logarithmically (1, 2, 3, 4, 6, 8, 12, ..., 192, 256 words), rounding
+  allocations up to the next size class. This shrinks the static array of size
+  classes from 256 free lists to 16, at the cost of some internal
+  fragmentation.
+
+- **buddy_classes**: An alternative to `size_classes`, which has to be turned
+  off with `default-features = false` to use this. Serve allocations of up to
+  4KiB from free lists of power-of-two sized blocks, splitting bigger blocks in
+  half as needed. Freeing a block merges it with its buddy, the other half of
+  the block it was split from, whenever that is free too, so freed memory is
+  never stranded in the free list of one size. The trade off is more internal
+  fragmentation, since every allocation is rounded up to a power of two.
+
+- **address_ordered_free_list**: Keep the main free list sorted by address,
+  rather than pushing freed cells onto its front. Physically adjacent free cells
+  then end up next to each other in the free list, which lets `wee_alloc` merge
+  them back together far more often and reduces fragmentation. The trade off is
+  that freeing a large allocation becomes an *O(n)* operation.
+
+- **deterministic**: Refuse to build with anything that would break this
+  guarantee: starting from a fresh allocator, the same sequence of
+  allocations, reallocations, and frees from a single thread puts every
+  allocation at the same offset from the start of its segment, wherever the
+  backend or `donate` placed the segments. This is for reproducible snapshots,
+  e.g. of a WebAssembly module's memory for a replay debugger. The feature
+  itself changes no code, because `wee_alloc` already keeps the guarantee by
+  default: freed cells are reused last in, first out, and cells are never
+  merged across segments. What it does is turn into build errors the features
+  that would break it, which are `address_ordered_free_list`, which orders free
+  cells by address, `buddy_classes`, which aligns its arenas by address, and
+  `thread_local_cache`, which depends on which thread runs what. The same goes
+  for the `WEE_ALLOC_INITIAL_PAGES` and `WEE_ALLOC_GROW_PERCENT` build-time
+  environment variables, which size a grow by what happened earlier in the
+  process. Allocations aligned to more than their segment's start may still be
+  placed differently, and the guarantee holds as long as every allocation
+  succeeds.
+
 - **extra_assertions**: Enable various extra, expensive integrity assertions and
   defensive mechanisms, such as poisoning freed memory. This incurs a large
   runtime overhead. It is useful when debugging a use-after-free or `wee_alloc`
   itself.
 
+- **track_peak**: Keep track of the most bytes `wee_alloc` has had from the
+  WebAssembly engine / operating system at once, available via
+  `WeeAlloc::peak_bytes`. This is useful for sizing a `.wasm` module's initial
+  and maximum memory.
+
+- **count_allocations**: Count calls to `alloc` and `dealloc`, and how often
+  allocations were satisfied straight from a free list versus needing to refill
+  it first, available via `WeeAlloc::counters`. A high ratio of refills to
+  allocations points at fragmentation.
+
+- **runtime_histogram**: Count how many allocations of each size, bucketed
+  by powers of two, `wee_alloc` has served, available via
+  `WeeAlloc::alloc_size_histogram`. This is useful for profiling the allocation
+  pattern of a real program, for example to tune size classes.
+
+- **trace_events**: Call a hook, set with `WeeAlloc::set_event_hook`, with an
+  `AllocEvent` on every allocation, deallocation, and growth of the heap. This
+  gives some visibility into the allocator on targets without better tools,
+  for example by forwarding the events to `defmt`. The hook must not allocate
+  or panic.
+
+- **fault_injection**: Provide `WeeAlloc::fail_next_n_allocations` and
+  `WeeAlloc::fail_every_nth_allocation`, which make allocations fail on
+  purpose. This is useful for testing how a program handles running out of
+  memory, without actually exhausting it.
+
+- **zero_header_on_free**: Stamp a magic word into every freed cell, just after
+  the free list links that overwrite the start of its data, and panic when
+  freeing a cell that still has it. This catches most double frees for much
+  less than the cost of `extra_assertions`, which poisons entire cells.
+
+- **inline_hot**: Always inline the allocation and deallocation paths into
+  their callers. This trades a bigger `.wasm` for skipping a call on every
+  allocation. On a small `wasm32-unknown-unknown` module that only allocates
+  through `GlobalAlloc`, it costs 73 bytes at `opt-level = "s"` and 91 bytes at
+  `opt-level = 3`, and was not measurably faster natively (within 2% on the
+  `dogfood` trace and on many small allocations), so measure before using it.
+
+- **outline_hot**: Never inline the allocation and deallocation paths, so that
+  there is exactly one copy of them. At `opt-level = "s"` or `"z"` the compiler
+  already does that, and the `.wasm` is byte for byte the same. At
+  `opt-level = 3` it saves 126 bytes on the same module, or 794 bytes when
+  `try_alloc` and `try_dealloc` are also called directly from six places, for
+  about 2% more time per allocation. Mutually exclusive with `inline_hot`. With
+  neither, the compiler decides.
+
+- **header_checksum**: Keep a checksum of every cell header's pointers, and
+  panic as soon as a header is read whose checksum doesn't match. This catches
+  heap corruption at the first access after it happened, rather than much
+  later, at the cost of an extra word in every cell's header.
+
+- **alloc_tags**: Provide `WeeAlloc::alloc_tagged`, which tags an allocation
+  with a number of the caller's choosing, such as an id for the call site, and
+  `WeeAlloc::retained_by_tag`, which reports how many bytes the live
+  allocations with each tag hold. Comparing the reports from before and after
+  a piece of code runs shows which tags it leaked. This costs an extra word in
+  every cell's header, and is not compatible with `buddy_classes`.
+
+- **cacheline_align_cells**: Start every allocation from the main free list on
+  a 64 byte cache line of its own, and round its size up to whole cache lines,
+  so that threads writing to separate allocations don't contend for the same
+  cache line. This costs memory: replaying the traces in `test/traces` needed
+  1-8% more pages with `size_classes`, which keeps small allocations out of the
+  main free list, and 4-85% more without it. Does nothing on `wasm32`, which is
+  single threaded.
+
+- **abort_on_oom**: Abort the process (or trap, on WebAssembly) when
+  `GlobalAlloc::alloc` or `GlobalAlloc::realloc` can't satisfy an allocation,
+  rather than returning null. Returning null is only safe when the
+  `alloc_error_handler` is set up correctly, whereas aborting never lets a null
+  pointer escape. Any hook set with `WeeAlloc::set_oom_hook` still runs first.
+  Without an operating system, the `static_array_backend` traps on WebAssembly,
+  aborts on nightly Rust, and otherwise loops forever. `WeeAlloc::try_alloc`
+  still returns an error instead of aborting.
+
+- **guard_words**: Allocate one extra word after every allocation, fill it
+  with a known pattern, and check that the pattern is intact when the
+  allocation is freed or reallocated, panicking if it isn't. This catches
+  writes just past the end of an allocation, which would otherwise silently
+  corrupt the header of the next cell. Useful for debugging, but it makes
+  every allocation bigger.
+
+- **std**: Build against the standard library, rather than only `core`. This
+  isn't needed to use `wee_alloc` on any target, but the features below that
+  need `std` turn it on.
+
+- **thread_local_cache**: Provide `ThreadCachedWeeAlloc`, a `WeeAlloc` with a
+  small per-thread cache of recently freed cells in front of it. Small
+  allocations are served from the cache without taking any lock, which reduces
+  contention when many threads allocate at once. A thread's cache is given back
+  to the allocator when the thread exits. This feature turns on `std`.
+
+- **no_merge**: Never merge adjacent free cells in the main free list, so that
+  deallocating is always just pushing the cell onto the front of the free list.
+  This speeds up deallocation in programs that allocate more or less
+  monotonically and are torn down rather than freeing much, such as short-lived
+  WebAssembly modules. The trade-off is fragmentation: freed cells stay as
+  small as they were allocated, so a long-running program that frees and
+  allocates different sizes will grow its heap more. `WeeAlloc::compact` does
+  nothing with this feature.
+
+- **doubly_linked_free_list**: Link free cells to both their next and previous
+  cells in the free list. Then a free cell can be taken out of the middle of
+  the free list in constant time, so deallocation merges a cell with both of
+  its free neighbors right away, instead of leaving the merge with the next
+  neighbor for a later allocation to find. The cost is one more word per free
+  cell, which means every allocation takes at least two words.
+
 - **static_array_backend**: Force the use of an OS-independent backing
   implementation with a global maximum size fixed at compile time.  Suitable for
   deploying to non-WASM/Unix/Windows `#![no_std]` environments, such as on
   embedded devices with esoteric or effectively absent operating systems. The
   size defaults to 32 MiB (33554432 bytes), and may be controlled at build-time
   by supplying an optional environment variable to cargo,
-  `WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES`. Note that this feature requires
-  nightly Rust.
+  `WEE_ALLOC_STATIC_ARRAY_BACKEND_BYTES`. Where setting an environment variable
+  is impractical, such as for a transitive dependency, enable one of the
+  `static_8mib`, `static_32mib`, or `static_128mib` features instead. The
+  environment variable takes precedence over these features. Segments of the
+  heap that become entirely free are given back to the backend, which hands
+  them out again before using up the rest of the array. Note that this feature
+  requires nightly Rust.
+
+- **growable_backend**: Force the use of a backend that keeps the whole heap in
+  one contiguous region of host memory, growing it a page at a time up to
+  1 GiB. This lets tests and fuzzers run the same free list logic used on
+  WebAssembly and inspect the entire heap with `with_growable_backend_heap`.
+  This is only intended for testing on the host, and turns on `std`.
+
+- **mmap_huge_pages**: On Linux, ask `mmap` to back the heap with huge pages
+  (`MAP_HUGETLB`) whenever it grows by a multiple of 2 MiB, the usual huge page
+  size. If that fails, for example because no huge pages are configured,
+  `wee_alloc` falls back to regular pages. Ignored on other targets.
+
+- **mmap_noreserve**: On Linux, ask `mmap` not to reserve swap space for the
+  heap (`MAP_NORESERVE`). Ignored on other targets.
 
 - **nightly**: Enable usage of nightly-only Rust features, such as implementing
   the `Alloc` trait (not to be confused with the stable `GlobalAlloc` trait!)
 
+- **allocator_api**: Implement the `Allocator` trait, which replaced `Alloc`
+  on newer nightly Rust, for `&WeeAlloc`, so that it can be used with
+  collections' `_in` constructors, e.g. `Vec::new_in(&ALLOC)`. The slices that
+  it hands out cover the whole cell, not just the requested size. Note that
+  this feature requires nightly Rust.
+
 ## Implementation Notes and Constraints
 
 - `wee_alloc` imposes two words of overhead on each allocation for maintaining
   its internal free lists.
 
-- Deallocation is an *O(1)* operation.
+- Deallocation is an *O(1)* operation, unless the `address_ordered_free_list`
+  feature is enabled.
 
 - `wee_alloc` will never return freed pages to the WebAssembly engine /
   operating system. Currently, WebAssembly can only grow its heap, and can never
@@ -80,6 +254,53 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
   free lists for potential future allocations, even when running on unix
   targets.
 
+- Modules with a declared maximum memory size can tell `wee_alloc` about it
+  with the optional `WEE_ALLOC_MAX_PAGES` environment variable at build time.
+  Then, allocations that would need to grow memory beyond that maximum fail
+  right away on WebAssembly, without attempting a doomed `memory.grow`.
+
+- Splitting a large cell is only done when the leftover piece is at least a
+  minimum size, which is 512 words with the `size_classes` feature and 16
+  words otherwise. Workloads with huge numbers of tiny allocations can pack
+  them more tightly by lowering it with the optional `WEE_ALLOC_MIN_CELL_WORDS`
+  environment variable at build time, at the cost of more fragmentation.
+
+- Shrinking an allocation with `realloc` splits the end off its cell and frees
+  it under the same rule, so by default with the `size_classes` feature an
+  allocation from the main free list has to shrink by more than 4KiB (2KiB on
+  32-bit targets) to give any memory back in place. A word-aligned allocation
+  shrunk down to a size class's sizes, such as 4KiB down to 64 bytes, is moved
+  into the size class instead, and its whole old cell is freed.
+
+- By default, `wee_alloc` grows the heap lazily, only requesting as many pages
+  as the allocation at hand needs. Growing memory has a cost, so to pay it just
+  once at startup, supply the optional `WEE_ALLOC_INITIAL_PAGES` environment
+  variable to cargo at build time. The first time the heap grows, `wee_alloc`
+  will request at least that many pages, and later allocations are carved out
+  of them without growing memory again.
+
+- Similarly, to grow the heap in bigger steps every time, supply the optional
+  `WEE_ALLOC_MIN_GROW_PAGES` environment variable at build time. Each time the
+  heap grows, `wee_alloc` then requests at least that many pages, rather than
+  just one, so bursts of small allocations need fewer, bigger, contiguous
+  segments.
+
+- To grow the heap in geometrically bigger batches when it has to grow several
+  times in a row, the way `Vec` grows its buffer, supply the optional
+  `WEE_ALLOC_GROW_PERCENT` environment variable at build time. Each grow then
+  requests at least that percentage of the pages that the allocator's previous
+  grow did, so a burst of allocations at startup doesn't grow memory over and
+  over again, and the surplus stays in the free list for the allocations that
+  follow. The batches are capped at 16 pages (1 MiB), and when a batch can't be
+  had, `wee_alloc` falls back to requesting just what is needed. A value of
+  `150` is a good start. Batching is off by default: WebAssembly memory is
+  never given back once grown, so every surplus page a batch asks for is kept
+  for the life of the program, whether or not it is ever used. Small
+  percentages aren't a safe default either, since at `100` or below a grow is
+  never bigger than the one before it; they only make the grows that follow a
+  big allocation big too. And `deterministic` builds need the heap to grow by
+  exactly what was asked for.
+
 - `wee_alloc` uses a simple, first-fit free list implementation. This means that
   allocation is an *O(n)* operation.
 
@@ -90,6 +311,12 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
   uses the same first-fit routines that allocating from the main free list does,
   which avoids introducing more code bloat than necessary.
 
+  Using the `buddy_classes` feature instead puts a buddy allocator in front of
+  the main free list. It carves 8KiB arenas out of the main free list, and
+  serves allocations of up to 4KiB from them without walking any free list.
+  Once every block of an arena is free again, the arena goes back to the main
+  free list, except for the last one, which is kept for the next allocation.
+
 Finally, here is a diagram giving an overview of `wee_alloc`'s implementation:
 
 ```text
@@ -170,8 +397,12 @@ for hacking!
  */
 
 #![deny(missing_docs)]
-#![cfg_attr(not(feature = "use_std_for_test_debugging"), no_std)]
-#![cfg_attr(feature = "nightly", feature(allocator_api, core_intrinsics))]
+#![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(
+    any(feature = "nightly", feature = "allocator_api"),
+    feature(allocator_api)
+)]
+#![cfg_attr(feature = "nightly", feature(core_intrinsics))]
 
 #[macro_use]
 extern crate cfg_if;
@@ -179,9 +410,9 @@ extern crate cfg_if;
 #[cfg(feature = "nightly")]
 extern crate alloc;
 
-#[cfg(feature = "use_std_for_test_debugging")]
+#[cfg(feature = "std")]
 extern crate core;
-#[cfg(feature = "static_array_backend")]
+#[cfg(any(feature = "static_array_backend", feature = "growable_backend"))]
 extern crate spin;
 
 extern crate memory_units;
@@ -193,9 +424,16 @@ cfg_if! {
     if #[cfg(feature = "static_array_backend")] {
         mod imp_static_array;
         use imp_static_array as imp;
+    } else if #[cfg(feature = "growable_backend")] {
+        mod imp_growable;
+        use imp_growable as imp;
     } else if #[cfg(target_arch = "wasm32")] {
         mod imp_wasm32;
         use imp_wasm32 as imp;
+    } else if #[cfg(target_os = "redox")] {
+        extern crate syscall;
+        mod imp_redox;
+        use imp_redox as imp;
     } else if #[cfg(unix)] {
         extern crate libc;
         mod imp_unix;
@@ -211,32 +449,218 @@ cfg_if! {
     }
 }
 
+#[cfg(all(feature = "inline_hot", feature = "outline_hot"))]
+compile_error! {
+    "the `inline_hot` and `outline_hot` features are mutually exclusive"
+}
+
+#[cfg(all(feature = "size_classes", feature = "buddy_classes"))]
+compile_error! {
+    "the `buddy_classes` feature replaces `size_classes`; disable the default features to use it"
+}
+
+#[cfg(all(feature = "alloc_tags", feature = "buddy_classes"))]
+compile_error! {
+    "the `alloc_tags` feature needs a cell header to keep tags in, which buddy blocks don't have"
+}
+
+#[cfg(all(feature = "deterministic", feature = "address_ordered_free_list"))]
+compile_error! {
+    "the `address_ordered_free_list` feature orders free cells by address, so it can't be `deterministic`"
+}
+
+#[cfg(all(feature = "deterministic", feature = "buddy_classes"))]
+compile_error! {
+    "the `buddy_classes` feature aligns its arenas by address, so it can't be `deterministic`"
+}
+
+#[cfg(all(feature = "deterministic", feature = "thread_local_cache"))]
+compile_error! {
+    "the `thread_local_cache` feature depends on which thread frees what, so it can't be `deterministic`"
+}
+
+#[cfg(feature = "buddy_classes")]
+mod buddy;
 mod const_init;
 mod neighbors;
+pub mod policy;
 #[cfg(feature = "size_classes")]
 mod size_classes;
+#[cfg(feature = "thread_local_cache")]
+mod thread_cache;
 
 cfg_if! {
     if #[cfg(feature = "nightly")] {
-        use core::alloc::{Alloc, AllocErr};
+        use core::alloc::{Alloc, AllocErr, CannotReallocInPlace, Excess};
     } else {
         pub(crate) struct AllocErr;
     }
 }
 
 use const_init::ConstInit;
+#[cfg(feature = "allocator_api")]
+use core::alloc::Allocator;
 use core::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
 use core::cmp;
+use core::fmt;
 use core::marker::Sync;
 use core::mem;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use memory_units::{size_of, ByteSize, Bytes, Pages, RoundUpTo, Words};
 use neighbors::Neighbors;
 
+/// Call `f` with the whole heap of the `growable_backend`, i.e. all of the pages
+/// that have been allocated so far.
+///
+/// # Safety
+///
+/// No other thread may be allocating or deallocating while `f` runs, and `f`
+/// must not allocate with `wee_alloc` itself.
+#[cfg(feature = "growable_backend")]
+pub unsafe fn with_growable_backend_heap<F, T>(f: F) -> T
+where
+    F: FnOnce(&[u8]) -> T,
+{
+    imp::with_heap(f)
+}
+
 /// The WebAssembly page size, in bytes.
 pub const PAGE_SIZE: Bytes = Bytes(65536);
 
+#[cfg(feature = "thread_local_cache")]
+pub use thread_cache::ThreadCachedWeeAlloc;
+
+/// The version of this `wee_alloc` crate.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// How this copy of `wee_alloc` was built, as returned by [`build_info`].
+///
+/// Its `Debug` output is meant to be pasted into bug reports, since many
+/// of `wee_alloc`'s features change its behavior.
+///
+/// [`build_info`]: fn.build_info.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The version of this `wee_alloc` crate.
+    pub version: &'static str,
+    /// Where the allocator gets its memory from: one of `"static_array"`,
+    /// `"growable"`, `"wasm32"`, `"redox"`, `"unix"`, or `"windows"`.
+    pub backend: &'static str,
+    /// Whether the `size_classes` feature is enabled.
+    pub size_classes: bool,
+    /// Whether the `extra_assertions` feature is enabled.
+    pub extra_assertions: bool,
+    /// Whether the `static_array_backend` feature is enabled.
+    pub static_array_backend: bool,
+    /// Whether the `nightly` feature is enabled.
+    pub nightly: bool,
+    /// The number of size classes, or zero without the `size_classes`
+    /// feature.
+    pub num_size_classes: usize,
+    /// The size of the static array backend's heap in bytes, or `None` when
+    /// another backend is in use.
+    pub static_array_bytes: Option<usize>,
+}
+
+/// Report how this copy of `wee_alloc` was built.
+///
+/// This doesn't allocate, so it can be used from `#![no_std]` code, and even
+/// after the heap is corrupted.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: version(),
+        backend: imp::NAME,
+        size_classes: cfg!(feature = "size_classes"),
+        extra_assertions: cfg!(feature = "extra_assertions"),
+        static_array_backend: cfg!(feature = "static_array_backend"),
+        nightly: cfg!(feature = "nightly"),
+        #[cfg(feature = "size_classes")]
+        num_size_classes: size_classes::SizeClasses::NUM_SIZE_CLASSES,
+        #[cfg(not(feature = "size_classes"))]
+        num_size_classes: 0,
+        #[cfg(feature = "static_array_backend")]
+        static_array_bytes: Some(imp::SCRATCH_LEN_BYTES),
+        #[cfg(not(feature = "static_array_backend"))]
+        static_array_bytes: None,
+    }
+}
+
+/// The error returned by [`WeeAlloc::try_alloc`] when an allocation request
+/// cannot be satisfied.
+///
+/// [`WeeAlloc::try_alloc`]: struct.WeeAlloc.html#method.try_alloc
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocError;
+
+/// A violation of `wee_alloc`'s internal invariants, as found by
+/// [`WeeAlloc::verify_integrity`]. Each variant holds the address of the
+/// offending cell.
+///
+/// [`WeeAlloc::verify_integrity`]: struct.WeeAlloc.html#method.verify_integrity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// A cell is misaligned, has an impossible size, or has inconsistent tag
+    /// bits.
+    MalformedCell(usize),
+    /// A cell's adjacent neighbor does not point back at it.
+    BrokenNeighbors(usize),
+    /// A free list contains a cycle.
+    FreeListCycle(usize),
+    /// A free list contains a cell that is marked allocated.
+    AllocatedCellInFreeList(usize),
+    /// A free cell's data was written to after it was freed. This is only
+    /// detected with the `extra_assertions` feature, which poisons freed data.
+    FreeCellNotPoisoned(usize),
+    /// With the `address_ordered_free_list` feature, the main free list is out
+    /// of order.
+    UnorderedFreeList(usize),
+    /// With the `doubly_linked_free_list` feature, a free cell's link to the
+    /// previous cell in its free list is wrong.
+    BrokenFreeList(usize),
+    /// A free list is currently locked, for example because `verify_integrity`
+    /// was called while allocating, so it could not be checked.
+    Busy,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntegrityError::MalformedCell(cell) => write!(f, "malformed cell at {:#x}", cell),
+            IntegrityError::BrokenNeighbors(cell) => write!(
+                f,
+                "the neighbors of the cell at {:#x} do not point back at it",
+                cell
+            ),
+            IntegrityError::FreeListCycle(cell) => {
+                write!(f, "free list has a cycle through the cell at {:#x}", cell)
+            }
+            IntegrityError::AllocatedCellInFreeList(cell) => {
+                write!(f, "free list contains the allocated cell at {:#x}", cell)
+            }
+            IntegrityError::FreeCellNotPoisoned(cell) => write!(
+                f,
+                "the free cell at {:#x} was written to after it was freed",
+                cell
+            ),
+            IntegrityError::UnorderedFreeList(cell) => write!(
+                f,
+                "free list is not sorted by descending address at the cell at {:#x}",
+                cell
+            ),
+            IntegrityError::BrokenFreeList(cell) => write!(
+                f,
+                "the free cell at {:#x} does not link back to the previous free cell",
+                cell
+            ),
+            IntegrityError::Busy => f.write_str("a free list is locked"),
+        }
+    }
+}
+
 extra_only! {
     fn assert_is_word_aligned<T>(ptr: *const T) {
         assert_aligned_to(ptr, size_of::<usize>());
@@ -268,10 +692,75 @@ where
     }
 }
 
+/// The counterpart to `memory_units::RoundUpTo`: the number of whole `T`s that
+/// fit in `self`, ignoring any remainder.
+trait RoundDownTo<T> {
+    fn round_down_to(self) -> T;
+}
+
+impl<T: Into<Bytes>> RoundDownTo<Words> for T {
+    #[inline]
+    fn round_down_to(self) -> Words {
+        let bytes: Bytes = self.into();
+        Words(round_down_to(bytes.0, Words::BYTE_SIZE.0))
+    }
+}
+
+impl<T: Into<Bytes>> RoundDownTo<Pages> for T {
+    #[inline]
+    fn round_down_to(self) -> Pages {
+        let bytes: Bytes = self.into();
+        Pages(round_down_to(bytes.0, Pages::BYTE_SIZE.0))
+    }
+}
+
+#[inline]
+fn round_down_to(n: usize, divisor: usize) -> usize {
+    n / divisor
+}
+
+/// Arithmetic on `memory_units` types that clamps at zero and at the largest
+/// representable amount, rather than wrapping around.
+#[allow(dead_code)]
+trait Saturating: Sized {
+    fn saturating_add(self, rhs: Self) -> Self;
+    fn saturating_sub(self, rhs: Self) -> Self;
+    fn saturating_mul(self, rhs: usize) -> Self;
+}
+
+macro_rules! impl_saturating {
+    ( $( $unit:ident ),* ) => {
+        $(
+            impl Saturating for $unit {
+                #[inline]
+                fn saturating_add(self, rhs: Self) -> Self {
+                    $unit(self.0.saturating_add(rhs.0))
+                }
+
+                #[inline]
+                fn saturating_sub(self, rhs: Self) -> Self {
+                    $unit(self.0.saturating_sub(rhs.0))
+                }
+
+                #[inline]
+                fn saturating_mul(self, rhs: usize) -> Self {
+                    $unit(self.0.saturating_mul(rhs))
+                }
+            }
+        )*
+    }
+}
+
+impl_saturating!(Bytes, Words, Pages);
+
 #[repr(C)]
 #[derive(Default, Debug)]
 struct CellHeader<'a> {
     neighbors: Neighbors<'a, CellHeader<'a>>,
+    // The tag that the cell was allocated with, for `WeeAlloc::retained_by_tag`.
+    // Zero for untagged allocations.
+    #[cfg(feature = "alloc_tags")]
+    tag: Cell<u16>,
 }
 
 impl<'a> AsRef<Neighbors<'a, CellHeader<'a>>> for CellHeader<'a> {
@@ -298,7 +787,10 @@ unsafe impl<'a> neighbors::HasNeighbors<'a, CellHeader<'a>> for CellHeader<'a> {
         _neighbors: &Neighbors<'a, CellHeader<'a>>,
         prev: *const CellHeader<'a>,
     ) -> Option<&'a CellHeader<'a>> {
-        if prev.is_null() {
+        // The first cell of a segment has no previous cell. Even if its link
+        // somehow pointed at the last cell of the segment before it, don't
+        // follow it into another segment.
+        if prev.is_null() || CellHeader::next_cell_is_invalid(&(*prev).neighbors) {
             None
         } else {
             Some(&*prev)
@@ -306,6 +798,19 @@ unsafe impl<'a> neighbors::HasNeighbors<'a, CellHeader<'a>> for CellHeader<'a> {
     }
 }
 
+#[test]
+#[cfg_attr(feature = "extra_assertions", should_panic)]
+fn size_of_corrupted_cell_does_not_underflow() {
+    let mut words = [0usize; 8];
+    unsafe {
+        let cell = &*(words.as_mut_ptr().offset(4) as *const CellHeader);
+        // Point `next` backwards, before the cell itself.
+        cell.neighbors
+            .set_next(words.as_ptr() as *const CellHeader);
+        assert_eq!(cell.size(), Bytes(0));
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct AllocatedCell<'a> {
@@ -331,14 +836,44 @@ fn allocated_cell_layout() {
 struct FreeCell<'a> {
     header: CellHeader<'a>,
     next_free_raw: Cell<*const FreeCell<'a>>,
+    // The previous cell in the free list, or null if this is the first one.
+    // Costs every cell a word, but lets a cell be removed from the middle of
+    // its free list without walking the list.
+    #[cfg(feature = "doubly_linked_free_list")]
+    prev_free_raw: Cell<*const FreeCell<'a>>,
+}
+
+// The smallest data a cell can have: enough for the free list links that it
+// needs once it is free.
+const MIN_CELL_DATA_SIZE: Bytes =
+    Bytes(mem::size_of::<FreeCell>() - mem::size_of::<CellHeader>());
+
+#[test]
+fn cell_header_layout() {
+    let words = if cfg!(feature = "header_checksum") {
+        Words(3)
+    } else {
+        Words(2)
+    };
+    let words = if cfg!(feature = "alloc_tags") {
+        words + Words(1)
+    } else {
+        words
+    };
+    assert_eq!(size_of::<CellHeader>(), words.into());
 }
 
 #[test]
 fn free_cell_layout() {
+    let links = if cfg!(feature = "doubly_linked_free_list") {
+        Words(2)
+    } else {
+        Words(1)
+    };
     assert_eq!(
-        size_of::<CellHeader>() + Words(1),
+        size_of::<CellHeader>() + links,
         size_of::<FreeCell>(),
-        "Safety and correctness depends on FreeCell being only one word larger than CellHeader"
+        "Safety and correctness depends on FreeCell being only its links larger than CellHeader"
     );
 
     assert_eq!(
@@ -380,6 +915,10 @@ impl<'a> CellHeader<'a> {
     // this cell. If the `self.neighbors.next_bit_2` bit is not set, then it
     // points to the next cell. If that bit is set, then it points to the
     // invalid memory that follows this cell.
+    //
+    // If `self.neighbors.prev_bit_1` is set, then the cell is an allocated
+    // cell in the main free list whose data is a whole size class segment.
+    // The bit is cleared whenever the cell is freed.
 
     fn is_allocated(&self) -> bool {
         self.neighbors.get_next_bit_1()
@@ -409,7 +948,39 @@ impl<'a> CellHeader<'a> {
         neighbors.clear_next_bit_2();
     }
 
+    #[cfg(any(feature = "alloc_tags", feature = "size_classes"))]
+    fn holds_size_class_segment(&self) -> bool {
+        self.neighbors.get_prev_bit_1()
+    }
+
+    #[cfg(feature = "size_classes")]
+    fn set_holds_size_class_segment(&self) {
+        self.neighbors.set_prev_bit_1();
+    }
+
+    // Merge `next`, this cell's next adjacent cell, into this cell, handing
+    // over whether the memory after `next` is invalid.
+    //
+    // Every merge goes through here. A cell whose next cell is invalid is the
+    // last of its segment, and whatever follows it in memory, even another
+    // segment, must never be merged into it.
+    fn absorb_next(&self, next: &CellHeader<'a>) {
+        extra_assert!(
+            !CellHeader::next_cell_is_invalid(&self.neighbors),
+            "the cell at {:p} is the last of its segment, and can't absorb {:p}",
+            self,
+            next
+        );
+        extra_assert!(ptr::eq(self.neighbors.next_unchecked(), next));
+
+        next.neighbors.remove();
+        if CellHeader::next_cell_is_invalid(&next.neighbors) {
+            CellHeader::set_next_cell_is_invalid(&self.neighbors);
+        }
+    }
+
     fn size(&self) -> Bytes {
+        self.neighbors.verify_checksum();
         let data = unsafe { (self as *const CellHeader<'a>).offset(1) };
         assert_is_word_aligned(data);
         let data = data as usize;
@@ -424,7 +995,12 @@ impl<'a> CellHeader<'a> {
             next as *const (),
             data as *const ()
         );
-        Bytes(next - data)
+
+        // If the header has been corrupted so that the next cell comes before
+        // our data, then treat this cell as empty, rather than wrapping around
+        // to a gigantic size that would have us scribble over everything after
+        // it.
+        Bytes(next.saturating_sub(data))
     }
 
     fn as_free_cell(&self) -> Option<&FreeCell<'a>> {
@@ -463,14 +1039,22 @@ impl<'a> FreeCell<'a> {
     // Therefore, this free cell can be merged into a single, larger, contiguous
     // free cell with its previous neighbor, which is also the next cell in the
     // free list.
+    // With the `doubly_linked_free_list` feature, cells are merged as soon as
+    // they are freed, and this bit is never set.
+    //
+    // If `SEGMENT_START` is set, then this free cell is the first cell of a
+    // segment that was minted by an `AllocPolicy`, i.e. it has no previous
+    // adjacent cell. This bit is lost while the cell is allocated, and is
+    // restored when the cell is freed again.
     const NEXT_FREE_CELL_CAN_MERGE: usize = 0b01;
-    const _RESERVED: usize = 0b10;
+    const SEGMENT_START: usize = 0b10;
     const MASK: usize = !0b11;
 
     fn next_free_can_merge(&self) -> bool {
         self.next_free_raw.get() as usize & Self::NEXT_FREE_CELL_CAN_MERGE != 0
     }
 
+    #[cfg(not(feature = "doubly_linked_free_list"))]
     fn set_next_free_can_merge(&self) {
         let next_free = self.next_free_raw.get() as usize;
         let next_free = next_free | Self::NEXT_FREE_CELL_CAN_MERGE;
@@ -483,11 +1067,78 @@ impl<'a> FreeCell<'a> {
         self.next_free_raw.set(next_free as *const FreeCell);
     }
 
+    fn is_segment_start(&self) -> bool {
+        self.next_free_raw.get() as usize & Self::SEGMENT_START != 0
+    }
+
+    fn set_segment_start(&self) {
+        let next_free = self.next_free_raw.get() as usize;
+        let next_free = next_free | Self::SEGMENT_START;
+        self.next_free_raw.set(next_free as *const FreeCell);
+    }
+
+    #[allow(dead_code)]
+    fn clear_segment_start(&self) {
+        let next_free = self.next_free_raw.get() as usize;
+        let next_free = next_free & !Self::SEGMENT_START;
+        self.next_free_raw.set(next_free as *const FreeCell);
+    }
+
     fn next_free(&self) -> *const FreeCell<'a> {
         let next_free = self.next_free_raw.get() as usize & Self::MASK;
         next_free as *const FreeCell<'a>
     }
 
+    // Set the next free cell without clobbering the low bits.
+    fn set_next_free(&self, next_free: *const FreeCell<'a>) {
+        extra_assert_eq!(next_free as usize & !Self::MASK, 0);
+        let bits = self.next_free_raw.get() as usize & !Self::MASK;
+        let next_free = next_free as usize | bits;
+        self.next_free_raw.set(next_free as *const FreeCell);
+    }
+
+    // The previous cell in the free list. Always null when free lists are
+    // singly-linked.
+    #[cfg(feature = "doubly_linked_free_list")]
+    fn prev_free(&self) -> *const FreeCell<'a> {
+        self.prev_free_raw.get()
+    }
+
+    #[cfg(not(feature = "doubly_linked_free_list"))]
+    fn prev_free(&self) -> *const FreeCell<'a> {
+        ptr::null()
+    }
+
+    #[cfg(feature = "doubly_linked_free_list")]
+    fn set_prev_free(&self, prev_free: *const FreeCell<'a>) {
+        self.prev_free_raw.set(prev_free);
+    }
+
+    #[cfg(not(feature = "doubly_linked_free_list"))]
+    #[inline(always)]
+    fn set_prev_free(&self, _prev_free: *const FreeCell<'a>) {}
+
+    // Remove this cell from the middle of the free list starting at `head`, in
+    // O(1).
+    #[cfg(feature = "doubly_linked_free_list")]
+    unsafe fn remove_from_free_list(&self, head: &Cell<*const FreeCell<'a>>) {
+        extra_assert!(!self.next_free_can_merge());
+
+        let prev = self.prev_free();
+        let next = self.next_free();
+        match prev.as_ref() {
+            Some(prev) => prev.set_next_free(next),
+            None => {
+                extra_assert_eq!(get_free_list_link(head), self as *const FreeCell);
+                set_free_list_link(head, next);
+            }
+        }
+        set_prev_free_link(next, prev);
+
+        self.set_next_free(ptr::null());
+        self.set_prev_free(ptr::null());
+    }
+
     unsafe fn from_uninitialized(
         raw: NonNull<u8>,
         size: Bytes,
@@ -504,6 +1155,8 @@ impl<'a> FreeCell<'a> {
             FreeCell {
                 header: CellHeader::default(),
                 next_free_raw: Cell::new(next_free),
+                #[cfg(feature = "doubly_linked_free_list")]
+                prev_free_raw: Cell::new(ptr::null()),
             },
         );
 
@@ -515,8 +1168,14 @@ impl<'a> FreeCell<'a> {
     fn into_allocated_cell(&self, policy: &dyn AllocPolicy<'a>) -> &AllocatedCell<'a> {
         assert_local_cell_invariants(&self.header);
         assert_is_poisoned_with_free_pattern(self, policy);
+        unsafe {
+            clear_free_magic(self.header.unchecked_data(), self.header.size());
+        }
 
         CellHeader::set_allocated(&self.header.neighbors);
+        #[cfg(feature = "alloc_tags")]
+        self.header.tag.set(0);
+        policy.cell_allocated();
         unsafe { mem::transmute(self) }
     }
 
@@ -547,10 +1206,8 @@ impl<'a> FreeCell<'a> {
         // choose to split at some alignment and return the aligned cell at the
         // end.
         let next = self.header.neighbors.next_unchecked() as usize;
-        let split_and_aligned = (next - size.0) & !(align.0 - 1);
-        let data = unsafe { self.header.unchecked_data() } as usize;
         let min_cell_size: Bytes = policy.min_cell_size(alloc_size).into();
-        if data + size_of::<CellHeader>().0 + min_cell_size.0 <= split_and_aligned {
+        if let Some(split_and_aligned) = self.split_point(size, align, min_cell_size) {
             let split_cell_head = split_and_aligned - size_of::<CellHeader>().0;
             let split_cell = unsafe {
                 &*FreeCell::from_uninitialized(
@@ -576,15 +1233,50 @@ impl<'a> FreeCell<'a> {
         // is large enough to fit the requested size, but is the cell's data
         // properly aligned?
         if self.header.is_aligned_to(align) {
-            previous.set(self.next_free());
+            set_free_list_link(previous, self.next_free());
+            unsafe {
+                set_prev_free_link(self.next_free(), self.prev_free());
+            }
             let allocated = self.into_allocated_cell(policy);
-            assert_is_valid_free_list(previous.get(), policy);
+            assert_is_valid_free_list(get_free_list_link(previous), policy);
             return Some(allocated);
         }
 
         None
     }
 
+    // Where to split this cell, so that the second half's data holds `size`
+    // bytes aligned to `align`, if that leaves at least `min_cell_size` bytes
+    // of data in the first half.
+    //
+    // Rounding the start of the new cell's data down to `align` is the least
+    // we can do: its data must start aligned and run up to the next cell, so it
+    // only ever gets less than `align` bytes more than `size`. Those extra
+    // bytes are too few to split off into a cell of their own, since `size` is
+    // already a whole number of words and the next cell is word-aligned.
+    fn split_point(&self, size: Bytes, align: Bytes, min_cell_size: Bytes) -> Option<usize> {
+        let next = self.header.neighbors.next_unchecked() as usize;
+        let split_and_aligned = (next - size.0) & !(align.0 - 1);
+        let data = unsafe { self.header.unchecked_data() } as usize;
+        if data + size_of::<CellHeader>().0 + min_cell_size.0 <= split_and_aligned {
+            Some(split_and_aligned)
+        } else {
+            None
+        }
+    }
+
+    // Whether `try_alloc` would succeed with the main free list's policy,
+    // without changing anything.
+    fn fits(&self, alloc_size: Words, align: Bytes) -> bool {
+        let size: Bytes = alloc_size.into();
+        if self.header.size() < size {
+            return false;
+        }
+
+        let min_cell_size: Bytes = LargeAllocPolicy::MIN_CELL_SIZE.into();
+        self.split_point(size, align, min_cell_size).is_some() || self.header.is_aligned_to(align)
+    }
+
     fn insert_into_free_list<'b>(
         &'b self,
         head: &'b Cell<*const FreeCell<'a>>,
@@ -592,8 +1284,41 @@ impl<'a> FreeCell<'a> {
     ) -> &'b Cell<*const FreeCell<'a>> {
         extra_assert!(!self.next_free_can_merge());
         extra_assert!(self.next_free().is_null());
-        self.next_free_raw.set(head.get());
+
+        // Keep free lists that merge cells sorted by descending address, so
+        // that a cell is followed in the free list by its previous adjacent
+        // cell whenever that cell is also free. That is exactly the situation
+        // where the two can be merged. There's no point in paying for the
+        // ordering when the policy doesn't merge cells.
+        #[cfg(feature = "address_ordered_free_list")]
+        {
+            if policy.should_merge_adjacent_free_cells() {
+                let mut link = head;
+                let mut prev = ptr::null();
+                unsafe {
+                    while let Some(next) = get_free_list_link(link).as_ref() {
+                        if (next as *const FreeCell) < (self as *const FreeCell) {
+                            break;
+                        }
+                        link = &next.next_free_raw;
+                        prev = next;
+                    }
+                    self.set_next_free(get_free_list_link(link));
+                    self.set_prev_free(prev);
+                    set_free_list_link(link, self);
+                    set_prev_free_link(self.next_free(), self);
+                }
+                assert_is_valid_free_list(head.get(), policy);
+                return head;
+            }
+        }
+
+        self.set_next_free(head.get());
+        self.set_prev_free(ptr::null());
         head.set(self);
+        unsafe {
+            set_prev_free_link(self.next_free(), self);
+        }
         assert_is_valid_free_list(head.get(), policy);
         head
     }
@@ -602,26 +1327,34 @@ impl<'a> FreeCell<'a> {
     fn tail_data(&self) -> *const u8 {
         let data = unsafe { (self as *const FreeCell as *const FreeCell).offset(1) as *const u8 };
         assert_is_word_aligned(data);
-        data
+        unsafe { data.add(FREE_MAGIC_SIZE.0) }
     }
 
     #[cfg(feature = "extra_assertions")]
     fn tail_data_size(&self) -> Bytes {
         let size = self.header.size();
-        extra_assert!(size >= size_of::<usize>());
-        // Subtract a word from the size, since `FreeCell::next_free` uses it.
-        size - size_of::<usize>()
+        extra_assert!(size >= MIN_CELL_DATA_SIZE);
+        // Subtract the free list links, and the free magic word if there is
+        // room for it, from the size.
+        size.saturating_sub(MIN_CELL_DATA_SIZE + FREE_MAGIC_SIZE)
     }
 }
 
 impl<'a> AllocatedCell<'a> {
     unsafe fn into_free_cell(&self, policy: &dyn AllocPolicy<'a>) -> &FreeCell<'a> {
+        self.header.neighbors.verify_checksum();
         assert_local_cell_invariants(&self.header);
 
         CellHeader::set_free(&self.header.neighbors);
+        self.header.neighbors.clear_prev_bit_1();
         let free: &FreeCell = mem::transmute(self);
         write_free_pattern(free, free.header.size(), policy);
+        stamp_free_magic(free.header.unchecked_data(), free.header.size());
         free.next_free_raw.set(ptr::null_mut());
+        free.set_prev_free(ptr::null());
+        if free.header.neighbors.prev_unchecked().is_null() {
+            free.set_segment_start();
+        }
         free
     }
 
@@ -640,59 +1373,123 @@ extra_only! {
             ptr::write_bytes(
                 data as *mut u8,
                 pattern,
-                (size - (size_of::<FreeCell>() - size_of::<CellHeader>())).0
+                size.saturating_sub(MIN_CELL_DATA_SIZE + FREE_MAGIC_SIZE).0,
             );
         }
     }
 }
 
+#[cfg(feature = "extra_assertions")]
+fn check_is_poisoned_with_free_pattern(
+    cell: &FreeCell,
+    policy: &dyn AllocPolicy,
+) -> Result<(), IntegrityError> {
+    use core::slice;
+    unsafe {
+        let size: Bytes = cell.tail_data_size();
+        let data = cell.tail_data();
+        let data = slice::from_raw_parts(data, size.0);
+        let pattern = policy.free_pattern();
+        if data.iter().all(|byte| *byte == pattern) {
+            Ok(())
+        } else {
+            Err(IntegrityError::FreeCellNotPoisoned(cell as *const _ as usize))
+        }
+    }
+}
+
+// Without "extra_assertions", freed data isn't poisoned, so there is nothing to
+// check.
+#[cfg(not(feature = "extra_assertions"))]
+#[inline(always)]
+fn check_is_poisoned_with_free_pattern(
+    _cell: &FreeCell,
+    _policy: &dyn AllocPolicy,
+) -> Result<(), IntegrityError> {
+    Ok(())
+}
+
+// Poison the data of every cell in the free list starting at `head` with
+// `byte`.
+#[cfg(feature = "extra_assertions")]
+unsafe fn repoison_free_list(head: *const FreeCell, byte: u8) {
+    let mut current = head;
+    while let Some(cell) = current.as_ref() {
+        ptr::write_bytes(cell.tail_data() as *mut u8, byte, cell.tail_data_size().0);
+        current = cell.next_free();
+    }
+}
+
 extra_only! {
     fn assert_is_poisoned_with_free_pattern(cell: &FreeCell, policy: &dyn AllocPolicy) {
-        use core::slice;
-        unsafe {
-            let size: Bytes = cell.tail_data_size();
-            let data = cell.tail_data();
-            let data = slice::from_raw_parts(data, size.0);
-            let pattern = policy.free_pattern();
-            extra_assert!(data.iter().all(|byte| *byte == pattern));
+        if let Err(e) = check_is_poisoned_with_free_pattern(cell, policy) {
+            panic!("{}", e);
+        }
+    }
+}
+
+// Check the invariants of a single cell and its links with its adjacent
+// neighbors.
+fn check_local_cell_invariants(cell: &CellHeader) -> Result<(), IntegrityError> {
+    let addr = cell as *const CellHeader as usize;
+    let malformed = IntegrityError::MalformedCell(addr);
+    let broken = IntegrityError::BrokenNeighbors(addr);
+
+    if addr % size_of::<usize>().0 != 0 || cell.size() < size_of::<usize>() {
+        return Err(malformed);
+    }
+
+    // Look at the raw link, since `prev` hides links into another segment.
+    if let Some(prev) = unsafe { cell.neighbors.prev_unchecked().as_ref() } {
+        if prev.size() < size_of::<usize>() {
+            return Err(IntegrityError::MalformedCell(prev as *const _ as usize));
+        }
+        // next(prev(cell)) == cell
+        if CellHeader::next_cell_is_invalid(&prev.neighbors)
+            || prev.neighbors.next_unchecked() as usize != addr
+        {
+            return Err(broken);
+        }
+    }
+
+    if let Some(next) = cell.neighbors.next() {
+        if next.size() < size_of::<usize>() {
+            return Err(IntegrityError::MalformedCell(next as *const _ as usize));
+        }
+        // prev(next(cell)) == cell
+        if next.neighbors.prev_unchecked() as usize != addr {
+            return Err(broken);
+        }
+    }
+
+    if let Some(free) = cell.as_free_cell() {
+        // A free cell is the start of a segment iff it has no previous
+        // adjacent cell.
+        if free.is_segment_start() != cell.neighbors.prev().is_none() {
+            return Err(malformed);
+        }
+
+        // If the next free cell (aka the previous adjacent cell) can merge,
+        // then it had better exist and be free.
+        if free.next_free_can_merge() {
+            match cell.neighbors.prev() {
+                Some(prev)
+                    if prev.is_free() && ptr::eq(free.next_free() as *const CellHeader, prev) => {}
+                _ => return Err(malformed),
+            }
         }
     }
+
+    Ok(())
 }
 
 extra_only! {
     fn assert_local_cell_invariants(cell: *const CellHeader) {
         assert_is_word_aligned(cell);
         unsafe {
-            if let Some(cell_ref) = cell.as_ref() {
-                assert!(cell_ref.size() >= size_of::<usize>());
-
-                if let Some(prev) = cell_ref.neighbors.prev() {
-                    assert!(prev.size() >= size_of::<usize>());
-                    assert!(!CellHeader::next_cell_is_invalid(&prev.neighbors));
-                    assert_eq!(prev.neighbors.next_unchecked(), cell, "next(prev(cell)) == cell");
-                }
-
-                if let Some(next) = cell_ref.neighbors.next() {
-                    assert!(next.size() >= size_of::<usize>());
-                    assert_eq!(next.neighbors.prev_unchecked(), cell, "prev(next(cell)) == cell");
-                }
-
-                if let Some(free) = cell_ref.as_free_cell() {
-                    if free.next_free_can_merge() {
-                        let prev_cell = free.header.neighbors.prev().expect(
-                            "if the next free cell (aka prev_cell) can merge, \
-                             prev_cell had better exist"
-                        );
-                        assert!(
-                            prev_cell.is_free(),
-                            "prev_cell is free, when NEXT_FREE_CELL_CAN_MERGE bit is set"
-                        );
-                        assert_eq!(
-                            free.next_free() as *const CellHeader,
-                            prev_cell as *const _,
-                            "next_free == prev_cell, when NEXT_FREE_CAN_MERGE bit is set"
-                        );
-                    }
+            if let Some(cell) = cell.as_ref() {
+                if let Err(e) = check_local_cell_invariants(cell) {
+                    panic!("{}", e);
                 }
             }
         }
@@ -700,58 +1497,172 @@ extra_only! {
 }
 
 extra_only! {
-    // Assert global invariants of the given free list:
-    //
-    // - The free list does not have cycles
-    //
-    // - None of the cells within the free list are marked allocated
-    //
-    // - The freed cell's data is properly poisoned, i.e. there has not been any
-    //   use-after-free.
-    //
-    // This is O(size of free list) and can be pretty slow, so try to restrict
-    // its usage to verifying that a free list is still valid after mutation.
-    fn assert_is_valid_free_list(head: *const FreeCell, policy: &dyn AllocPolicy) {
+    // Assert that the given cell is currently allocated, and therefore can be
+    // deallocated, with a helpful message for double frees.
+    fn assert_can_dealloc(cell: *const CellHeader, policy: &dyn AllocPolicy) {
+        use core::slice;
         unsafe {
-            let mut left = head;
-            assert_local_cell_invariants(left as *const CellHeader);
-            if left.is_null() {
-                return;
+            if cell as usize % size_of::<usize>().0 != 0 {
+                panic!("not a wee_alloc pointer: cell at {:p} is not word-aligned", cell);
             }
-            assert_is_poisoned_with_free_pattern(&*left, policy);
 
-            let mut right = (*left).next_free();
+            if !policy.owns(cell as *const u8) {
+                panic!(
+                    "not a wee_alloc pointer: cell at {:p} is outside of this allocator's segments",
+                    cell
+                );
+            }
 
-            loop {
-                assert_local_cell_invariants(right as *const CellHeader);
-                if right.is_null() {
-                    return;
-                }
-                assert_is_poisoned_with_free_pattern(&*right, policy);
+            // If the cell was freed and then merged into its previous adjacent
+            // cell, its header is now part of that cell's poisoned data.
+            let header = slice::from_raw_parts(cell as *const u8, size_of::<CellHeader>().0);
+            let pattern = policy.free_pattern();
+            if header.iter().all(|byte| *byte == pattern) {
+                panic!(
+                    "double free or corruption: cell at {:p} was already freed and merged \
+                     into a neighbor",
+                    cell
+                );
+            }
 
-                assert!(left != right, "free list should not have cycles");
-                assert!((*right).header.is_free(), "cells in free list should never be allocated");
-                assert!((*left).header.is_free(), "cells in free list should never be allocated");
+            let cell_ref = &*cell;
+
+            // Every cell, allocated or free, knows where it ends.
+            if cell_ref.neighbors.next_unchecked().is_null() {
+                panic!("not a wee_alloc pointer: cell at {:p} has no size", cell);
+            }
+
+            if cell_ref.is_free() {
+                panic!("double free or corruption: cell at {:p} is already free", cell);
+            }
 
-                right = (*right).next_free();
-                assert_local_cell_invariants(right as *const CellHeader);
-                if right.is_null() {
-                    return;
+            // A cell we never allocated is unlikely to be properly linked with
+            // its neighbors.
+            if let Some(prev) = cell_ref.neighbors.prev() {
+                if prev.neighbors.next_unchecked() != cell {
+                    panic!(
+                        "not a wee_alloc pointer: cell at {:p} is not linked with its neighbors",
+                        cell
+                    );
                 }
-                assert_is_poisoned_with_free_pattern(&*right, policy);
+            }
+            if let Some(next) = cell_ref.neighbors.next() {
+                if next.neighbors.prev_unchecked() != cell {
+                    panic!(
+                        "not a wee_alloc pointer: cell at {:p} is not linked with its neighbors",
+                        cell
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Check the global invariants of the given free list:
+//
+// - The free list does not have cycles
+//
+// - None of the cells within the free list are marked allocated
+//
+// - The freed cell's data is properly poisoned, i.e. there has not been any
+//   use-after-free. This is only checked with "extra_assertions".
+//
+// - With the "address_ordered_free_list" feature, free lists of policies
+//   that merge cells are sorted by descending address.
+//
+// This is O(size of free list) and can be pretty slow, so try to restrict
+// its usage to verifying that a free list is still valid after mutation.
+unsafe fn check_free_list(
+    head: *const FreeCell,
+    policy: &dyn AllocPolicy,
+) -> Result<(), IntegrityError> {
+    let check_order =
+        cfg!(feature = "address_ordered_free_list") && policy.should_merge_adjacent_free_cells();
+
+    // `slow` follows `current` at half speed, so if there is a cycle, `current`
+    // will eventually lap it.
+    let mut slow = head;
+    let mut steps = 0;
+    // This may be called with a suffix of a free list, so trust the first
+    // cell's link back.
+    let mut prev = head.as_ref().map_or(ptr::null(), |h| h.prev_free());
+    let mut current = head;
+
+    while let Some(cell) = current.as_ref() {
+        let addr = current as usize;
+        check_local_cell_invariants(&cell.header)?;
+        if cell.header.is_allocated() {
+            return Err(IntegrityError::AllocatedCellInFreeList(addr));
+        }
+        check_is_poisoned_with_free_pattern(cell, policy)?;
+        if cfg!(feature = "doubly_linked_free_list") && cell.prev_free() != prev {
+            return Err(IntegrityError::BrokenFreeList(addr));
+        }
+        prev = current;
+
+        let next = cell.next_free();
+        if check_order && !next.is_null() && next >= current {
+            return Err(IntegrityError::UnorderedFreeList(addr));
+        }
+        current = next;
+
+        steps += 1;
+        if steps % 2 == 0 {
+            slow = (*slow).next_free();
+        }
+        if !current.is_null() && current == slow {
+            return Err(IntegrityError::FreeListCycle(current as usize));
+        }
+    }
+
+    Ok(())
+}
 
-                left = (*left).next_free();
-                assert_local_cell_invariants(left as *const CellHeader);
-                assert_is_poisoned_with_free_pattern(&*left, policy);
+extra_only! {
+    fn assert_is_valid_free_list(head: *const FreeCell, policy: &dyn AllocPolicy) {
+        unsafe {
+            if let Err(e) = check_free_list(head, policy) {
+                panic!("{}", e);
+            }
+        }
+    }
+}
 
-                assert!(left != right, "free list should not have cycles");
-                assert!((*right).header.is_free(), "cells in free list should never be allocated");
-                assert!((*left).header.is_free(), "cells in free list should never be allocated");
+// Check the given free list, and also every cell that is adjacent to one of its
+// cells, i.e. all of the cells in each segment that has any free cells.
+//
+// Each run of allocated cells between two free cells is visited at most twice,
+// so this is O(number of cells in those segments).
+unsafe fn verify_free_list(
+    head: *const FreeCell,
+    policy: &dyn AllocPolicy,
+) -> Result<(), IntegrityError> {
+    check_free_list(head, policy)?;
+
+    let mut current = head;
+    while let Some(cell) = current.as_ref() {
+        let mut prev = cell.header.neighbors.prev();
+        while let Some(p) = prev {
+            if p.is_free() {
+                break;
+            }
+            check_local_cell_invariants(p)?;
+            prev = p.neighbors.prev();
+        }
 
-                right = (*right).next_free();
+        let mut next = cell.header.neighbors.next();
+        while let Some(n) = next {
+            if n.is_free() {
+                break;
             }
+            check_local_cell_invariants(n)?;
+            next = n.neighbors.next();
         }
+
+        current = cell.next_free();
     }
+
+    Ok(())
 }
 
 trait AllocPolicy<'a> {
@@ -765,50 +1676,511 @@ trait AllocPolicy<'a> {
 
     fn should_merge_adjacent_free_cells(&self) -> bool;
 
-    #[cfg(feature = "extra_assertions")]
-    fn free_pattern(&self) -> u8;
-}
-
-struct LargeAllocPolicy;
-static LARGE_ALLOC_POLICY: LargeAllocPolicy = LargeAllocPolicy;
+    // Given a free cell from the free list that starts at `head`, try to
+    // reclaim the whole segment that the cell belongs to, removing all of that
+    // segment's cells from the free list. Returns `true` if the segment was
+    // reclaimed.
+    unsafe fn try_reclaim_segment(
+        &self,
+        _head: &Cell<*const FreeCell<'a>>,
+        _cell: &FreeCell<'a>,
+    ) -> bool {
+        false
+    }
 
-impl LargeAllocPolicy {
-    #[cfg(feature = "size_classes")]
-    const MIN_CELL_SIZE: Words = Words(size_classes::SizeClasses::NUM_SIZE_CLASSES * 2);
+    // Called whenever a cell is allocated from this policy's free list.
+    fn cell_allocated(&self) {}
 
-    #[cfg(not(feature = "size_classes"))]
-    const MIN_CELL_SIZE: Words = Words(16);
-}
+    // Called whenever a cell has been freed into the free list that starts at
+    // `head`.
+    unsafe fn cell_freed(&self, _head: &Cell<*const FreeCell<'a>>) {}
 
-impl<'a> AllocPolicy<'a> for LargeAllocPolicy {
-    unsafe fn new_cell_for_free_list(
+    // Try to allocate `size` words by popping the first cell off of the free
+    // list that starts at `head`, without walking the free list. Policies whose
+    // free cells are all the same size can do this in constant time; the rest
+    // fall back to a first-fit search.
+    unsafe fn try_pop_head(
         &self,
-        size: Words,
-        align: Bytes,
-    ) -> Result<*const FreeCell<'a>, AllocErr> {
+        _head: &Cell<*const FreeCell<'a>>,
+        _size: Words,
+    ) -> Option<NonNull<u8>> {
+        None
+    }
+
+    // The alignment that every cell allocated from this free list gets, even
+    // when less was asked for. Their sizes are rounded up to a multiple of it,
+    // as well.
+    #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+    fn min_align(&self) -> Bytes {
+        size_of::<usize>()
+    }
+
+    #[cfg(feature = "extra_assertions")]
+    fn free_pattern(&self) -> u8;
+
+    // Whether `ptr` is in one of the segments of the allocator that this
+    // policy's free list belongs to.
+    #[cfg(feature = "extra_assertions")]
+    fn owns(&self, ptr: *const u8) -> bool;
+}
+
+/// Lifetime counters of allocator activity, returned by `WeeAlloc::counters`.
+#[cfg(feature = "count_allocations")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocCounters {
+    /// The number of calls to allocate memory.
+    pub allocs: usize,
+    /// The number of calls to deallocate memory.
+    pub deallocs: usize,
+    /// The number of allocations that were satisfied by a free list without
+    /// refilling it.
+    pub first_fit_hits: usize,
+    /// The number of times a free list had to be refilled with a new cell,
+    /// either from the main free list or by growing the heap.
+    pub refills: usize,
+    /// The number of bytes that `realloc` copied because it had to move an
+    /// allocation.
+    pub realloc_bytes_copied: usize,
+    /// The number of bytes by which `realloc` grew allocations without moving
+    /// them.
+    pub realloc_bytes_grown_in_place: usize,
+}
+
+#[cfg(feature = "count_allocations")]
+struct AtomicAllocCounters {
+    allocs: AtomicUsize,
+    deallocs: AtomicUsize,
+    first_fit_hits: AtomicUsize,
+    refills: AtomicUsize,
+    realloc_bytes_copied: AtomicUsize,
+    realloc_bytes_grown_in_place: AtomicUsize,
+}
+
+#[cfg(feature = "count_allocations")]
+static COUNTERS: AtomicAllocCounters = AtomicAllocCounters {
+    allocs: AtomicUsize::new(0),
+    deallocs: AtomicUsize::new(0),
+    first_fit_hits: AtomicUsize::new(0),
+    refills: AtomicUsize::new(0),
+    realloc_bytes_copied: AtomicUsize::new(0),
+    realloc_bytes_grown_in_place: AtomicUsize::new(0),
+};
+
+// The number of allocations served of each size, rounded up to whole words.
+// Bucket `i` counts sizes of at least `2^i` bytes and less than `2^(i + 1)`.
+#[cfg(feature = "runtime_histogram")]
+const NUM_SIZE_BUCKETS: usize = 64;
+
+#[cfg(feature = "runtime_histogram")]
+static ALLOC_SIZE_HISTOGRAM: [AtomicUsize; NUM_SIZE_BUCKETS] = {
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; NUM_SIZE_BUCKETS]
+};
+
+#[cfg(feature = "runtime_histogram")]
+fn record_alloc_size(size: Bytes) {
+    extra_assert!(size.0 > 0);
+    let bucket = mem::size_of::<usize>() * 8 - 1 - size.0.leading_zeros() as usize;
+    ALLOC_SIZE_HISTOGRAM[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+// Bump one of the `COUNTERS`, by one or by the given amount, or do nothing if
+// the "count_allocations" feature is disabled.
+macro_rules! count {
+    ( $counter:ident ) => {
+        count!($counter, 1)
+    };
+    ( $counter:ident, $n:expr ) => {
+        #[cfg(feature = "count_allocations")]
+        {
+            COUNTERS.$counter.fetch_add($n, Ordering::Relaxed);
+        }
+    };
+}
+
+/// Something the allocator did, passed to the hook set with
+/// `WeeAlloc::set_event_hook`.
+#[cfg(feature = "trace_events")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocEvent {
+    /// An allocation of `size` bytes was made at `ptr`.
+    Alloc {
+        /// The requested size, in bytes.
+        size: usize,
+        /// The new allocation.
+        ptr: *mut u8,
+    },
+    /// The allocation at `ptr` was freed.
+    Dealloc {
+        /// The freed allocation.
+        ptr: *mut u8,
+    },
+    /// The heap grew by `pages` pages.
+    GrowPages {
+        /// The number of new pages.
+        pages: Pages,
+    },
+}
+
+// Pass an `AllocEvent` to the event hook of the given `LargeAllocPolicy`, or do
+// nothing if the "trace_events" feature is disabled.
+macro_rules! trace_event {
+    ( $policy:expr, $event:expr ) => {
+        #[cfg(feature = "trace_events")]
+        {
+            $policy.trace_event($event);
+        }
+    };
+}
+
+// All of the bookkeeping of pages obtained from the backend. It is only touched
+// when pages change hands, never when an allocation is served from a free list.
+struct PageCounts {
+    // The pages that haven't been given back. Only the static array backend
+    // can take pages back.
+    in_use: AtomicUsize,
+
+    // The most pages that have ever been in use at once. Pages that are given
+    // back and then handed out again only count once.
+    #[cfg(feature = "track_peak")]
+    peak: AtomicUsize,
+}
+
+impl PageCounts {
+    const fn new() -> PageCounts {
+        PageCounts {
+            in_use: AtomicUsize::new(0),
+            #[cfg(feature = "track_peak")]
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn in_use(&self) -> Pages {
+        Pages(self.in_use.load(Ordering::Relaxed))
+    }
+
+    #[cfg(feature = "track_peak")]
+    fn peak(&self) -> Pages {
+        Pages(self.peak.load(Ordering::Relaxed))
+    }
+
+    fn granted(&self, pages: Pages) {
+        let in_use = self.in_use.fetch_add(pages.0, Ordering::Relaxed) + pages.0;
+        self.raise_peak(in_use);
+    }
+
+    #[cfg(feature = "track_peak")]
+    fn raise_peak(&self, in_use: usize) {
+        let mut current = self.peak.load(Ordering::Relaxed);
+        while in_use > current {
+            let result = self.peak.compare_exchange_weak(
+                current,
+                in_use,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            match result {
+                Ok(_) => break,
+                Err(x) => current = x,
+            }
+        }
+    }
+
+    #[cfg(not(feature = "track_peak"))]
+    #[inline(always)]
+    fn raise_peak(&self, _in_use: usize) {}
+
+    #[cfg(feature = "static_array_backend")]
+    fn given_back(&self, pages: Pages) {
+        self.in_use.fetch_sub(pages.0, Ordering::Relaxed);
+    }
+}
+
+static PAGE_COUNTS: PageCounts = PageCounts::new();
+
+// The most bytes that the pages in use may add up to, set with
+// `WeeAlloc::set_memory_limit`, or zero for no limit.
+static MEMORY_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+// Whether getting `pages` more pages would go over `MEMORY_LIMIT`.
+fn exceeds_memory_limit(pages: Pages) -> bool {
+    let limit = MEMORY_LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return false;
+    }
+    let in_use: Bytes = PAGE_COUNTS.in_use().into();
+    let bytes: Bytes = pages.into();
+    in_use.saturating_add(bytes) > Bytes(limit)
+}
+
+unsafe fn alloc_pages(pages: Pages) -> Result<NonNull<u8>, AllocErr> {
+    if exceeds_memory_limit(pages) {
+        return Err(AllocErr);
+    }
+
+    let result = imp::alloc_pages(pages);
+
+    if result.is_ok() {
+        PAGE_COUNTS.granted(pages);
+    }
+
+    result
+}
+
+// The minimum number of pages to request the first time the heap grows. See the
+// `WEE_ALLOC_INITIAL_PAGES` build-time environment variable.
+const INITIAL_PAGES: Pages = Pages(include!(concat!(
+    env!("OUT_DIR"),
+    "/wee_alloc_initial_pages.txt"
+)));
+
+// The minimum number of pages to request every time the heap grows. See the
+// `WEE_ALLOC_MIN_GROW_PAGES` build-time environment variable.
+const MIN_GROW_PAGES: Pages = Pages(include!(concat!(
+    env!("OUT_DIR"),
+    "/wee_alloc_min_grow_pages.txt"
+)));
+
+// The percentage of the previous grow's pages that the next grow requests at
+// least. See the `WEE_ALLOC_GROW_PERCENT` build-time environment variable.
+const GROW_PERCENT: usize = include!(concat!(env!("OUT_DIR"), "/wee_alloc_grow_percent.txt"));
+
+// The most pages that `GROW_PERCENT` makes a grow request, so that a single
+// large allocation doesn't make every later grow large too.
+const MAX_BATCHED_GROW_PAGES: Pages = Pages(16);
+
+// How many pages to grow the heap by, when `pages` are needed and the last grow
+// was by `last` pages: at least `percent` of the last grow, up to
+// `MAX_BATCHED_GROW_PAGES`.
+fn batched_grow_pages(pages: Pages, last: Pages, percent: usize) -> Pages {
+    // Round up, or else growing by a single page would never get any bigger.
+    let batch = last.0.saturating_mul(percent).saturating_add(99) / 100;
+    cmp::max(pages, cmp::min(Pages(batch), MAX_BATCHED_GROW_PAGES))
+}
+
+// Whether the heap has already grown once, and `INITIAL_PAGES` has been
+// requested. This is global, like the backend it guards.
+static GREW_INITIAL_PAGES: AtomicBool = AtomicBool::new(false);
+
+// The most pages we will ever request from the backend at once: everything that
+// fits in the address space.
+const MAX_ALLOC_PAGES: Pages = Pages(usize::max_value() / PAGE_SIZE.0);
+
+// Every segment of memory that an allocator gets, either from the backend or by
+// donation, starts with one of these, right before the segment's first cell.
+// They form a list of all of the allocator's segments.
+#[repr(C)]
+struct SegmentHeader {
+    next: *const SegmentHeader,
+    // The address just past the end of the segment.
+    end: usize,
+}
+
+#[cfg(feature = "static_array_backend")]
+impl SegmentHeader {
+    // The header of the segment that `cell` starts.
+    unsafe fn of_segment_start(cell: &FreeCell) -> *const SegmentHeader {
+        extra_assert!(cell.is_segment_start());
+        (cell as *const FreeCell as *const SegmentHeader).offset(-1)
+    }
+}
+
+// Remove `segment` from the list starting at `segments`, if it is there.
+#[cfg(feature = "static_array_backend")]
+unsafe fn unlink_segment(segments: &mut *const SegmentHeader, segment: *const SegmentHeader) {
+    let mut link = segments;
+    while !link.is_null() {
+        if *link == segment {
+            *link = (*segment).next;
+            return;
+        }
+        link = &mut (*(*link as *mut SegmentHeader)).next;
+    }
+}
+
+struct LargeAllocPolicy {
+    // Whether we may request more pages from the backend when the free list
+    // can't satisfy an allocation.
+    can_grow: bool,
+
+    // The list of every segment this allocator has, most recent first.
+    segments: imp::Exclusive<*const SegmentHeader>,
+
+    // How many pages this allocator grew the heap by the last time, which
+    // `GROW_PERCENT` scales the next grow by.
+    last_grow_pages: AtomicUsize,
+
+    // The byte that free cells are poisoned with instead of the default
+    // patterns, set by `WeeAlloc::set_poison_byte`, or `NO_POISON_BYTE`.
+    #[cfg(feature = "extra_assertions")]
+    poison_byte: AtomicUsize,
+
+    // The `fn(AllocEvent)` set with `WeeAlloc::set_event_hook`, or zero if
+    // there is none.
+    #[cfg(feature = "trace_events")]
+    event_hook: AtomicUsize,
+}
+
+#[cfg(feature = "extra_assertions")]
+const NO_POISON_BYTE: usize = usize::max_value();
+
+// The size of a cache line, which every large allocation starts on with the
+// `cacheline_align_cells` feature. WebAssembly is single threaded, so there is
+// no false sharing to avoid there.
+#[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+const CACHE_LINE_SIZE: Bytes = Bytes(64);
+
+impl LargeAllocPolicy {
+    #[cfg(feature = "trace_events")]
+    fn trace_event(&self, event: AllocEvent) {
+        let hook = self.event_hook.load(Ordering::Acquire);
+        if hook != 0 {
+            let hook: fn(AllocEvent) = unsafe { mem::transmute(hook) };
+            hook(event);
+        }
+    }
+
+    // Whether `ptr` is in any of this allocator's segments. See `WeeAlloc::owns`.
+    fn owns(&self, ptr: *const u8) -> bool {
+        let addr = ptr as usize;
+        unsafe {
+            self.segments.with_exclusive_access(|segments| {
+                let mut segment = *segments;
+                while !segment.is_null() {
+                    if segment as usize <= addr && addr < (*segment).end {
+                        return true;
+                    }
+                    segment = (*segment).next;
+                }
+                false
+            })
+        }
+    }
+}
+
+#[cfg(feature = "extra_assertions")]
+impl LargeAllocPolicy {
+    // The poison byte that overrides the default free patterns of this policy
+    // and of its size classes' policies, if any.
+    fn poison_byte(&self) -> Option<u8> {
+        match self.poison_byte.load(Ordering::Relaxed) {
+            NO_POISON_BYTE => None,
+            byte => Some(byte as u8),
+        }
+    }
+}
+
+// The minimum cell size, in words, from the `WEE_ALLOC_MIN_CELL_WORDS`
+// build-time environment variable, or zero to use the default.
+const CONFIGURED_MIN_CELL_WORDS: usize = include!(concat!(
+    env!("OUT_DIR"),
+    "/wee_alloc_min_cell_words.txt"
+));
+
+impl LargeAllocPolicy {
+    #[cfg(feature = "size_classes")]
+    const DEFAULT_MIN_CELL_SIZE: Words = Words(size_classes::SizeClasses::MAX_SIZE_CLASS_WORDS * 2);
+
+    #[cfg(not(feature = "size_classes"))]
+    const DEFAULT_MIN_CELL_SIZE: Words = Words(16);
+
+    const MIN_CELL_SIZE: Words = Words(
+        [CONFIGURED_MIN_CELL_WORDS, Self::DEFAULT_MIN_CELL_SIZE.0]
+            [(CONFIGURED_MIN_CELL_WORDS == 0) as usize],
+    );
+}
+
+// A free cell's data must have room for its `next_free` link. If
+// `WEE_ALLOC_MIN_CELL_WORDS` is too small, this fails to type check because the
+// array lengths don't match.
+#[allow(dead_code)]
+const ASSERT_MIN_CELL_SIZE_FITS_FREE_CELL: [(); 1] = [(); (LargeAllocPolicy::MIN_CELL_SIZE.0
+    >= (mem::size_of::<FreeCell>() - mem::size_of::<CellHeader>()) / mem::size_of::<usize>())
+    as usize];
+
+// The largest alignment that `LargeAllocPolicy::pages_for` is sure not to
+// overflow on, with plenty to spare for the minimum cell size and headers.
+const MAX_SUPPORTED_ALIGN: usize = {
+    let max_bytes = MAX_ALLOC_PAGES.0 * <Pages as ByteSize>::BYTE_SIZE.0;
+    let max_align = max_bytes / (4 * mem::size_of::<usize>());
+    1 << (mem::size_of::<usize>() * 8 - 1 - max_align.leading_zeros() as usize)
+};
+
+impl LargeAllocPolicy {
+    // The number of pages that a new segment needs so that an allocation of
+    // `size` words with the given alignment is sure to fit in it, or `None` if
+    // that is more than the address space.
+    fn pages_for(size: Words, align: Bytes) -> Option<Pages> {
         // To assure that an allocation will always succeed after refilling the
         // free list with this new cell, make sure that we allocate enough to
         // fulfill the requested alignment, and still have the minimum cell size
         // left over.
-        let size: Bytes = cmp::max(size.into(), (align + Self::MIN_CELL_SIZE) * Words(2));
+        //
+        // Pathological layouts can overflow this arithmetic, and a wrapped
+        // around size would have us hand out a cell that is much smaller than
+        // what was requested, so check every step.
+        let min_cell_size: Bytes = Self::MIN_CELL_SIZE.into();
+        let two_words: Bytes = Words(2).into();
+        let min_size = align
+            .0
+            .checked_add(min_cell_size.0)
+            .and_then(|s| s.checked_mul(two_words.0))?;
+        let size: Bytes = size.into();
+        // An over-aligned allocation is split off from the end of the new
+        // cell, so a big one needs room for the padding in front of it, and a
+        // cell to hold that padding, beyond its own size.
+        let size = if align > size_of::<usize>() {
+            size.0
+                .checked_add(align.0 + size_of::<CellHeader>().0 + min_cell_size.0)?
+        } else {
+            size.0
+        };
+        let size = cmp::max(size, min_size);
+        let size = size.checked_add(size_of::<SegmentHeader>().0 + size_of::<CellHeader>().0)?;
 
-        let pages: Pages = (size + size_of::<CellHeader>()).round_up_to();
-        let new_pages = imp::alloc_pages(pages)?;
-        let allocated_size: Bytes = pages.into();
+        let pages: Pages = checked_round_up_to(Bytes(size))?;
+        if pages > MAX_ALLOC_PAGES {
+            return None;
+        }
+        Some(pages)
+    }
+}
 
-        let free_cell = &*FreeCell::from_uninitialized(
-            new_pages,
-            allocated_size - size_of::<CellHeader>(),
-            None,
-            self as &dyn AllocPolicy<'a>,
-        );
+impl<'a> AllocPolicy<'a> for LargeAllocPolicy {
+    unsafe fn new_cell_for_free_list(
+        &self,
+        size: Words,
+        align: Bytes,
+    ) -> Result<*const FreeCell<'a>, AllocErr> {
+        if !self.can_grow {
+            return Err(AllocErr);
+        }
 
-        let next_cell = (new_pages.as_ptr() as *const u8).add(allocated_size.0);
-        free_cell
-            .header
-            .neighbors
-            .set_next(next_cell as *const CellHeader);
-        CellHeader::set_next_cell_is_invalid(&free_cell.header.neighbors);
+        let pages = Self::pages_for(size, align).ok_or(AllocErr)?;
+        let pages = if INITIAL_PAGES.0 != 0 && !GREW_INITIAL_PAGES.swap(true, Ordering::Relaxed) {
+            cmp::max(pages, INITIAL_PAGES)
+        } else {
+            pages
+        };
+        // Whatever is left over after the allocation at hand stays in the new
+        // cell, ready for the allocations after it.
+        let pages = cmp::max(pages, MIN_GROW_PAGES);
+        // Ask for a bigger batch if we keep having to grow, but don't fail the
+        // allocation just because the batch doesn't fit.
+        let last = Pages(self.last_grow_pages.load(Ordering::Relaxed));
+        let batch = batched_grow_pages(pages, last, GROW_PERCENT);
+        let (new_pages, pages) = match alloc_pages(batch) {
+            Ok(new_pages) => (new_pages, batch),
+            Err(_) if batch > pages => (alloc_pages(pages)?, pages),
+            Err(e) => return Err(e),
+        };
+        self.last_grow_pages.store(pages.0, Ordering::Relaxed);
+        trace_event!(self, AllocEvent::GrowPages { pages });
+        let allocated_size: Bytes = pages.into();
+
+        let free_cell = self.segments.with_exclusive_access(|segments| {
+            new_segment(new_pages, allocated_size, segments, self)
+        });
         Ok(free_cell)
     }
 
@@ -816,13 +2188,93 @@ impl<'a> AllocPolicy<'a> for LargeAllocPolicy {
         Self::MIN_CELL_SIZE
     }
 
+    // Start every large allocation on a cache line of its own, so that threads
+    // writing to separate allocations don't contend for the same line.
+    #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+    fn min_align(&self) -> Bytes {
+        CACHE_LINE_SIZE
+    }
+
     fn should_merge_adjacent_free_cells(&self) -> bool {
-        true
+        // With the "no_merge" feature, freeing a cell only ever pushes it onto
+        // the free list, trading fragmentation for speed.
+        !cfg!(feature = "no_merge")
+    }
+
+    #[cfg(not(feature = "static_array_backend"))]
+    unsafe fn try_reclaim_segment(
+        &self,
+        _head: &Cell<*const FreeCell<'a>>,
+        _cell: &FreeCell<'a>,
+    ) -> bool {
+        // There is nowhere to return our pages to.
+        false
+    }
+
+    // The static array backend can hand pages out again, so give back whole
+    // segments that are free.
+    #[cfg(feature = "static_array_backend")]
+    unsafe fn try_reclaim_segment(
+        &self,
+        head: &Cell<*const FreeCell<'a>>,
+        cell: &FreeCell<'a>,
+    ) -> bool {
+        // Only segments we got from the backend can go back to it.
+        if !self.can_grow
+            || !cell.is_segment_start()
+            || !CellHeader::next_cell_is_invalid(&cell.header.neighbors)
+        {
+            return false;
+        }
+
+        // Keep the first cell in the free list. It is either a segment that was
+        // just requested from the backend for the allocation being made, or
+        // the most recently freed cell, which is the most likely to be reused.
+        if ptr::eq(get_free_list_link(head), cell) {
+            return false;
+        }
+
+        // Segments from the backend are whole pages; donated ones might not be.
+        let segment = SegmentHeader::of_segment_start(cell);
+        let start = segment as usize;
+        let size = Bytes((*segment).end - start);
+        let pages: Pages = size.round_up_to();
+        if Bytes::from(pages) != size {
+            return false;
+        }
+
+        // Remove the cell from the free list before giving its pages away,
+        // and put it back if the backend doesn't take them.
+        let mut link = head;
+        while !ptr::eq(get_free_list_link(link), cell) {
+            link = &(*get_free_list_link(link)).next_free_raw;
+        }
+        set_free_list_link(link, cell.next_free());
+        set_prev_free_link(cell.next_free(), cell.prev_free());
+
+        self.segments.with_exclusive_access(|segments| {
+            unlink_segment(segments, segment);
+            if imp::free_pages(unchecked_unwrap(NonNull::new(start as *mut u8)), pages) {
+                PAGE_COUNTS.given_back(pages);
+                true
+            } else {
+                (*(segment as *mut SegmentHeader)).next = *segments;
+                *segments = segment;
+                set_free_list_link(link, cell);
+                set_prev_free_link(cell.next_free(), cell);
+                false
+            }
+        })
     }
 
     #[cfg(feature = "extra_assertions")]
     fn free_pattern(&self) -> u8 {
-        CellHeader::LARGE_FREE_PATTERN
+        self.poison_byte().unwrap_or(CellHeader::LARGE_FREE_PATTERN)
+    }
+
+    #[cfg(feature = "extra_assertions")]
+    fn owns(&self, ptr: *const u8) -> bool {
+        LargeAllocPolicy::owns(self, ptr)
     }
 }
 
@@ -850,13 +2302,14 @@ unsafe fn walk_free_list<'a, F, T>(
 where
     F: FnMut(&Cell<*const FreeCell<'a>>, &FreeCell<'a>) -> Option<T>,
 {
-    // The previous cell in the free list (not to be confused with the current
-    // cell's previously _adjacent_ cell).
-    let previous_free = head;
+    // The link to the current cell from the previous cell in the free list (not
+    // to be confused with the current cell's previously _adjacent_ cell), or
+    // the head of the free list.
+    let mut previous_free = head;
 
     loop {
-        let current_free = previous_free.get();
-        assert_local_cell_invariants(&(*current_free).header);
+        let current_free = get_free_list_link(previous_free);
+        assert_local_cell_invariants(current_free as *const CellHeader);
 
         if current_free.is_null() {
             return Err(AllocErr);
@@ -870,11 +2323,14 @@ where
         // We don't re-check `policy.should_merge_adjacent_free_cells()` because
         // the `NEXT_FREE_CELL_CAN_MERGE` bit only gets set after checking with
         // the policy.
-        while (*current_free.get()).next_free_can_merge() {
+        while (*current_free.get()).next_free_can_merge()
+            || can_merge_eagerly(&*current_free.get(), policy)
+        {
             extra_assert!(policy.should_merge_adjacent_free_cells());
 
             let current = &*current_free.get();
             current.clear_next_free_can_merge();
+            extra_assert!(!current.is_segment_start());
 
             let prev_neighbor = unchecked_unwrap(
                 current
@@ -884,12 +2340,10 @@ where
                     .and_then(|p| p.as_free_cell()),
             );
 
-            current.header.neighbors.remove();
-            if CellHeader::next_cell_is_invalid(&current.header.neighbors) {
-                CellHeader::set_next_cell_is_invalid(&prev_neighbor.header.neighbors);
-            }
+            prev_neighbor.header.absorb_next(&current.header);
 
-            previous_free.set(prev_neighbor);
+            set_free_list_link(previous_free, prev_neighbor);
+            set_prev_free_link(prev_neighbor, current.prev_free());
             current_free.set(prev_neighbor);
 
             write_free_pattern(
@@ -904,7 +2358,64 @@ where
             return Ok(result);
         }
 
-        previous_free.set(&*(*current_free.get()).next_free_raw.get());
+        // Only give a segment back once it has failed to satisfy this
+        // allocation, or else we would hand back the very pages that we are
+        // about to grow the heap by again.
+        if policy.try_reclaim_segment(head, &*current_free.get()) {
+            // The current cell, and any number of cells before it, were just
+            // removed from the free list. Start over from the beginning.
+            previous_free = head;
+            continue;
+        }
+
+        previous_free = &(*current_free.get()).next_free_raw;
+    }
+}
+
+// Whether `cell` is directly followed in the free list by its previous adjacent
+// cell, and can be merged into it just as if its `NEXT_FREE_CELL_CAN_MERGE` bit
+// were set. Keeping the free list sorted by address makes that common, even
+// when `dealloc_cell` didn't set the bit, e.g. for cells that were inserted
+// into the free list without merging.
+#[cfg(feature = "address_ordered_free_list")]
+#[inline]
+unsafe fn can_merge_eagerly<'a>(cell: &FreeCell<'a>, policy: &dyn AllocPolicy<'a>) -> bool {
+    if !policy.should_merge_adjacent_free_cells() {
+        return false;
+    }
+    match cell.header.neighbors.prev() {
+        Some(prev) => prev as *const CellHeader<'a> as *const FreeCell<'a> == cell.next_free(),
+        None => false,
+    }
+}
+
+#[cfg(not(feature = "address_ordered_free_list"))]
+#[inline(always)]
+unsafe fn can_merge_eagerly<'a>(_cell: &FreeCell<'a>, _policy: &dyn AllocPolicy<'a>) -> bool {
+    false
+}
+
+// A link in a free list is either the free list's head, or some free cell's
+// `next_free_raw`. The latter may have low bits set, which must be preserved.
+
+fn get_free_list_link<'a>(link: &Cell<*const FreeCell<'a>>) -> *const FreeCell<'a> {
+    let next_free = link.get() as usize & FreeCell::MASK;
+    next_free as *const FreeCell<'a>
+}
+
+fn set_free_list_link<'a>(link: &Cell<*const FreeCell<'a>>, next_free: *const FreeCell<'a>) {
+    extra_assert_eq!(next_free as usize & !FreeCell::MASK, 0);
+    let bits = link.get() as usize & !FreeCell::MASK;
+    let next_free = next_free as usize | bits;
+    link.set(next_free as *const FreeCell<'a>);
+}
+
+// With the `doubly_linked_free_list` feature, point the back link of `cell`, if
+// any, at `prev_free`. Call this whenever `cell` gets a new predecessor in the
+// free list.
+unsafe fn set_prev_free_link<'a>(cell: *const FreeCell<'a>, prev_free: *const FreeCell<'a>) {
+    if let Some(cell) = cell.as_ref() {
+        cell.set_prev_free(prev_free);
     }
 }
 
@@ -918,7 +2429,7 @@ unsafe fn alloc_first_fit<'a>(
     extra_assert!(size.0 > 0);
 
     walk_free_list(head, policy, |previous, current| {
-        extra_assert_eq!(previous.get(), current);
+        extra_assert_eq!(get_free_list_link(previous), current as *const FreeCell);
 
         if let Some(allocated) = current.try_alloc(previous, size, align, policy) {
             assert_aligned_to(allocated.data(), align);
@@ -929,16 +2440,38 @@ unsafe fn alloc_first_fit<'a>(
     })
 }
 
+// Round `size` up to a multiple of the policy's minimum alignment, or `None`
+// on overflow.
+#[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+fn round_up_to_min_align<'a>(size: Words, policy: &dyn AllocPolicy<'a>) -> Option<Words> {
+    let align: Words = policy.min_align().round_up_to();
+    let size = size.0.checked_add(align.0 - 1)?;
+    Some(Words(size / align.0 * align.0))
+}
+
 unsafe fn alloc_with_refill<'a, 'b>(
     size: Words,
     align: Bytes,
     head: &'b Cell<*const FreeCell<'a>>,
     policy: &dyn AllocPolicy<'a>,
 ) -> Result<NonNull<u8>, AllocErr> {
+    #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+    let (size, align) = (
+        round_up_to_min_align(size, policy).ok_or(AllocErr)?,
+        cmp::max(align, policy.min_align()),
+    );
+
+    if let Some(result) = policy.try_pop_head(head, size) {
+        count!(first_fit_hits);
+        return Ok(result);
+    }
+
     if let Ok(result) = alloc_first_fit(size, align, head, policy) {
+        count!(first_fit_hits);
         return Ok(result);
     }
 
+    count!(refills);
     let cell = policy.new_cell_for_free_list(size, align)?;
     let head = (*cell).insert_into_free_list(head, policy);
 
@@ -951,48 +2484,1272 @@ unsafe fn alloc_with_refill<'a, 'b>(
     result
 }
 
-/// A wee allocator.
-///
-/// # Safety
-///
-/// When used in unix environments, cannot move in memory. Typically not an
-/// issue if you're just using this as a `static` global allocator.
-pub struct WeeAlloc<'a> {
-    head: imp::Exclusive<*const FreeCell<'a>>,
+/// Allocate `size` words from the main free list, out of the cell that
+/// `placement` chooses, or out of a new cell if it doesn't choose one that fits.
+unsafe fn alloc_with_placement<'a, P>(
+    size: Words,
+    align: Bytes,
+    layout: Layout,
+    head: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+    placement: &P,
+) -> Result<NonNull<u8>, AllocErr>
+where
+    P: policy::Placement + ?Sized,
+{
+    #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+    let (size, align) = (
+        round_up_to_min_align(size, policy).ok_or(AllocErr)?,
+        cmp::max(align, policy.min_align()),
+    );
 
-    #[cfg(feature = "size_classes")]
-    size_classes: size_classes::SizeClasses<'a>,
-}
+    // Show the placement whole cells, rather than ones that are about to be
+    // merged with their neighbors.
+    if policy.should_merge_adjacent_free_cells() {
+        compact_free_list(head, policy);
+    }
 
-unsafe impl<'a> Sync for WeeAlloc<'a> {}
+    let cells = policy::FreeListCursor::new(get_free_list_link(head) as *const _, size, align);
+    if let Some(chosen) = placement.choose(layout, cells) {
+        let chosen = chosen.as_ptr().cast::<FreeCell<'a>>();
+        let result = walk_free_list(head, policy, |previous, current| {
+            if !ptr::eq(current, chosen) {
+                return None;
+            }
+            let allocated = current.try_alloc(previous, size, align, policy);
+            Some(allocated.map(|allocated| {
+                assert_aligned_to(allocated.data(), align);
+                unchecked_unwrap(NonNull::new(allocated.data() as *mut u8))
+            }))
+        });
+        if let Ok(Some(ptr)) = result {
+            return Ok(ptr);
+        }
+    }
 
-impl<'a> ConstInit for WeeAlloc<'a> {
-    const INIT: WeeAlloc<'a> = WeeAlloc {
-        head: imp::Exclusive::INIT,
+    count!(refills);
+    let cell = policy.new_cell_for_free_list(size, align)?;
+    let head = (*cell).insert_into_free_list(head, policy);
+    alloc_first_fit(size, align, head, policy)
+}
 
-        #[cfg(feature = "size_classes")]
-        size_classes: size_classes::SizeClasses::INIT,
-    };
+/// Free the given allocated cell into the given free list, merging it with its
+/// adjacent cells if the policy allows it.
+unsafe fn dealloc_cell<'a>(
+    cell: &AllocatedCell<'a>,
+    head: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+) {
+    let free = cell.into_free_cell(policy);
+
+    let merged =
+        policy.should_merge_adjacent_free_cells() && merge_with_free_neighbors(free, head, policy);
+    if !merged {
+        // Either we don't want to merge cells for the current policy, or we
+        // didn't have the opportunity to do any merging with our adjacent
+        // neighbors. In either case, insert this cell into the free list.
+        let _head = free.insert_into_free_list(head, policy);
+    }
+
+    policy.cell_freed(head);
 }
 
-impl<'a> WeeAlloc<'a> {
-    /// An initial `const` default construction of a `WeeAlloc` allocator.
-    ///
-    /// This is usable for initializing `static`s that get set as the global
-    /// allocator.
-    pub const INIT: Self = <Self as ConstInit>::INIT;
+// Merge the freshly freed cell `free` with its adjacent free neighbors. Returns
+// whether that took care of its place in the free list; otherwise, it still
+// needs to be inserted.
+#[cfg(not(feature = "doubly_linked_free_list"))]
+unsafe fn merge_with_free_neighbors<'a>(
+    free: &FreeCell<'a>,
+    head: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+) -> bool {
+    // Merging with the _previous_ adjacent cell is easy: it is
+    // already in the free list, so folding this cell into it is all
+    // that needs to be done. The free list can be left alone.
+    //
+    // Merging with the _next_ adjacent cell is a little harder. It
+    // is already in the free list, but we need to splice it out
+    // from the free list, since its header will become invalid
+    // after consolidation, and it is *this* cell's header that
+    // needs to be in the free list. But we don't have access to the
+    // pointer pointing to the soon-to-be-invalid header, and
+    // therefore can't adjust that pointer. So we have a delayed
+    // consolidation scheme. We insert this cell just after the next
+    // adjacent cell in the free list, and set the next adjacent
+    // cell's `NEXT_FREE_CAN_MERGE` bit. The next time that we walk
+    // the free list for allocation, the bit will be checked and the
+    // consolidation will happen at that time.
+    //
+    // If _both_ the previous and next adjacent cells are free, we
+    // first greedily merge this cell into the previous adjacent
+    // cell. The (now larger) previous cell's next adjacent cell is
+    // then the free next cell, and we try to merge the two of
+    // them as well. Our singly-linked free list doesn't allow for
+    // arbitrary splicing, so we can only do this when the two
+    // cells are already neighbors in the free list:
+    //
+    // * If the previous cell is directly followed by the next cell
+    //   in the free list, we splice the next cell out of the free
+    //   list and consolidate immediately.
+    //
+    // * If the next cell is directly followed by the previous cell
+    //   in the free list, then that is exactly the delayed
+    //   consolidation scheme described above, and we set the next
+    //   cell's `NEXT_FREE_CAN_MERGE` bit.
+    //
+    // Otherwise, we leave the next cell alone and live with a
+    // little fragmentation.
+
+    if let Some(prev) = free
+        .header
+        .neighbors
+        .prev()
+        .and_then(|p| (*p).as_free_cell())
+    {
+        prev.header.absorb_next(&free.header);
 
-    #[cfg(feature = "size_classes")]
-    unsafe fn with_free_list_and_policy_for_size<F, T>(&self, size: Words, align: Bytes, f: F) -> T
-    where
-        F: for<'b> FnOnce(&'b Cell<*const FreeCell<'a>>, &'b dyn AllocPolicy<'a>) -> T,
+        if let Some(next) = prev
+            .header
+            .neighbors
+            .next()
+            .and_then(|n| (*n).as_free_cell())
+        {
+            if ptr::eq(prev.next_free(), next) {
+                extra_assert!(!prev.next_free_can_merge());
+                extra_assert!(!next.next_free_can_merge());
+                extra_assert!(!next.is_segment_start());
+                prev.set_next_free(next.next_free());
+
+                prev.header.absorb_next(&next.header);
+            } else if ptr::eq(next.next_free(), prev) {
+                next.set_next_free_can_merge();
+            }
+        }
+
+        write_free_pattern(prev, prev.header.size(), policy);
+        assert_is_valid_free_list(head.get(), policy);
+        return true;
+    }
+
+    if let Some(next) = free
+        .header
+        .neighbors
+        .next()
+        .and_then(|n| (*n).as_free_cell())
     {
-        extra_assert!(size.0 > 0);
-        extra_assert!(align.0 > 0);
+        free.set_next_free(next.next_free());
+        next.set_next_free(free);
+        next.set_next_free_can_merge();
+
+        assert_is_valid_free_list(head.get(), policy);
+        return true;
+    }
+    false
+}
+
+// With a doubly-linked free list, the next adjacent cell can be spliced out of
+// the free list wherever it is, so there is no need for the delayed
+// consolidation scheme above: merge with both adjacent cells right away. The
+// next cell is folded into this one, and then this one into the previous cell,
+// which is already in the free list.
+#[cfg(feature = "doubly_linked_free_list")]
+unsafe fn merge_with_free_neighbors<'a>(
+    free: &FreeCell<'a>,
+    head: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+) -> bool {
+    if let Some(next) = free
+        .header
+        .neighbors
+        .next()
+        .and_then(|n| (*n).as_free_cell())
+    {
+        extra_assert!(!next.is_segment_start());
+        next.remove_from_free_list(head);
+
+        free.header.absorb_next(&next.header);
+        write_free_pattern(free, free.header.size(), policy);
+    }
+
+    if let Some(prev) = free
+        .header
+        .neighbors
+        .prev()
+        .and_then(|p| (*p).as_free_cell())
+    {
+        prev.header.absorb_next(&free.header);
+
+        write_free_pattern(prev, prev.header.size(), policy);
+        assert_is_valid_free_list(head.get(), policy);
+        return true;
+    }
+
+    false
+}
+
+/// Free the cell at `ptr` into the given free list, which it was allocated from
+/// with at least `size` words.
+unsafe fn dealloc_from_free_list<'a>(
+    ptr: NonNull<u8>,
+    size: Words,
+    head: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+) {
+    let cell = (ptr.as_ptr() as *mut CellHeader<'a> as *const CellHeader<'a>).offset(-1);
+    assert_can_dealloc(cell, policy);
+    assert_local_cell_invariants(cell);
+    let cell = &*cell;
+
+    extra_assert!(cell.size() >= size.into());
+    let cell: &AllocatedCell<'a> = mem::transmute(cell);
+
+    dealloc_cell(cell, head, policy);
+}
+
+// Write each segment in the list starting at `segment`, and its cells, for
+// `WeeAlloc::dump_to`.
+unsafe fn dump_segments<W: fmt::Write>(
+    mut segment: *const SegmentHeader,
+    w: &mut W,
+) -> fmt::Result {
+    while let Some(seg) = segment.as_ref() {
+        let start = segment as usize;
+        writeln!(w, "segment {:p} {}", segment, seg.end - start)?;
+
+        let mut cell = segment.offset(1) as *const CellHeader;
+        while (cell as usize) < seg.end {
+            let next = (*cell).neighbors.next_unchecked();
+            writeln!(
+                w,
+                "  +{} {} {}",
+                cell as usize - start,
+                (*cell).size().0,
+                if (*cell).is_allocated() { "a" } else { "f" }
+            )?;
+            if CellHeader::next_cell_is_invalid(&(*cell).neighbors) || next <= cell {
+                break;
+            }
+            cell = next;
+        }
+
+        segment = seg.next;
+    }
+    Ok(())
+}
+
+// Free every cell in `segment` and the segments after it that holds a size
+// class segment into the main free list, for `WeeAlloc::reset_size_classes`.
+#[cfg(feature = "size_classes")]
+unsafe fn free_size_class_segments<'a>(
+    head: &mut *const FreeCell<'a>,
+    mut segment: *const SegmentHeader,
+    policy: &LargeAllocPolicy,
+) {
+    let head_cell = Cell::new(*head);
+    while let Some(seg) = segment.as_ref() {
+        let mut cell = segment.offset(1) as *const CellHeader<'a>;
+        loop {
+            let next = (*cell).neighbors.next_unchecked();
+            let last = CellHeader::next_cell_is_invalid(&(*cell).neighbors);
+            if (*cell).is_allocated() && (*cell).holds_size_class_segment() {
+                let allocated = &*(cell as *const AllocatedCell<'a>);
+                allocated
+                    .into_free_cell(policy)
+                    .insert_into_free_list(&head_cell, policy);
+            }
+            if last {
+                break;
+            }
+            cell = next;
+        }
+        segment = seg.next;
+    }
+    if policy.should_merge_adjacent_free_cells() {
+        compact_free_list(&head_cell, policy);
+    }
+    *head = head_cell.get();
+}
+
+// The tag of the allocation whose data starts at `ptr`, which must not be
+// zero-sized.
+#[cfg(feature = "alloc_tags")]
+unsafe fn tag_of<'c>(ptr: NonNull<u8>) -> &'c Cell<u16> {
+    &(*(ptr.as_ptr() as *const CellHeader).offset(-1)).tag
+}
+
+// Carry the tag of the allocation at `from` over to the one at `to`, that it
+// was reallocated to.
+#[cfg(feature = "alloc_tags")]
+unsafe fn move_tag(from: NonNull<u8>, to: NonNull<u8>) {
+    tag_of(to).set(tag_of(from).get());
+}
+
+// How many tags `RetainedByTag` collects with each walk of the heap.
+#[cfg(feature = "alloc_tags")]
+const TAGS_PER_WALK: usize = 64;
+
+// Add up the sizes of the allocated cells in `segment` and the segments after
+// it, including the cells of size class segments, by tag, for the smallest
+// `tags.len()` tags that are at least `from`. They are written to the start of
+// `tags` in increasing order. Returns how many tags were written, and whether
+// some allocated cell has a greater tag that didn't fit.
+#[cfg(feature = "alloc_tags")]
+unsafe fn retained_with_tags(
+    mut segment: *const SegmentHeader,
+    from: u16,
+    tags: &mut [(u16, Bytes)],
+) -> (usize, bool) {
+    let mut len = 0;
+    let mut more = false;
+    let mut tally = |cell: &CellHeader| {
+        let tag = cell.tag.get();
+        if tag < from {
+            return;
+        }
+        match tags[..len].binary_search_by_key(&tag, |&(tag, _)| tag) {
+            Ok(i) => tags[i].1 = tags[i].1 + cell.size(),
+            Err(i) if i < tags.len() => {
+                // Make room by dropping the greatest tag, which the next walk
+                // will pick up again.
+                if len == tags.len() {
+                    len -= 1;
+                    more = true;
+                }
+                tags.copy_within(i..len, i + 1);
+                tags[i] = (tag, cell.size());
+                len += 1;
+            }
+            Err(_) => more = true,
+        }
+    };
+
+    while let Some(seg) = segment.as_ref() {
+        let mut cell = segment.offset(1) as *const CellHeader;
+        while (cell as usize) < seg.end {
+            if (*cell).is_allocated() {
+                if (*cell).holds_size_class_segment() {
+                    // A size class segment is a run of cells of its own,
+                    // whose last cell's `next` is the end of the segment.
+                    let mut inner = (*cell).unchecked_data() as *const CellHeader;
+                    loop {
+                        if (*inner).is_allocated() {
+                            tally(&*inner);
+                        }
+                        if CellHeader::next_cell_is_invalid(&(*inner).neighbors) {
+                            break;
+                        }
+                        inner = (*inner).neighbors.next_unchecked();
+                    }
+                } else {
+                    tally(&*cell);
+                }
+            }
+            if CellHeader::next_cell_is_invalid(&(*cell).neighbors) {
+                break;
+            }
+            cell = (*cell).neighbors.next_unchecked();
+        }
+        segment = seg.next;
+    }
+
+    (len, more)
+}
+
+/// An iterator over the bytes retained by each tag, returned by
+/// [`WeeAlloc::retained_by_tag`].
+///
+/// [`WeeAlloc::retained_by_tag`]: struct.WeeAlloc.html#method.retained_by_tag
+#[cfg(feature = "alloc_tags")]
+pub struct RetainedByTag<'b, 'a: 'b> {
+    alloc: &'b WeeAlloc<'a>,
+    // The tags found by the last walk of the heap, of which the first `len`
+    // are valid, and the first `index` have been reported.
+    tags: [(u16, Bytes); TAGS_PER_WALK],
+    len: usize,
+    index: usize,
+    // The tag that the next walk of the heap starts from, or `None` once the
+    // last walk found every remaining tag.
+    next_walk: Option<u16>,
+}
+
+#[cfg(feature = "alloc_tags")]
+impl<'b, 'a: 'b> Iterator for RetainedByTag<'b, 'a> {
+    type Item = (u16, Bytes);
+
+    fn next(&mut self) -> Option<(u16, Bytes)> {
+        if self.index == self.len {
+            let from = self.next_walk?;
+            let tags = &mut self.tags;
+            let (len, more) = unsafe {
+                self.alloc
+                    .with_heap_locked(|segments| retained_with_tags(segments, from, tags))
+            };
+            self.len = len;
+            self.index = 0;
+            self.next_walk = if more {
+                self.tags[len - 1].0.checked_add(1)
+            } else {
+                None
+            };
+            if len == 0 {
+                return None;
+            }
+        }
+
+        self.index += 1;
+        Some(self.tags[self.index - 1])
+    }
+}
+
+// Call `f` with every one of `classes` locked, so that none of their cells
+// change while `f` reads them.
+#[cfg(all(feature = "alloc_tags", feature = "size_classes"))]
+unsafe fn with_size_classes_locked<'a, F, T>(
+    classes: &[imp::Exclusive<*const FreeCell<'a>>],
+    f: F,
+) -> T
+where
+    F: FnOnce() -> T,
+{
+    match classes.split_first() {
+        None => f(),
+        Some((first, rest)) => first.with_exclusive_access(|_| with_size_classes_locked(rest, f)),
+    }
+}
+
+/// Turn the given region of memory into a new segment holding a single free
+/// cell, ready to be inserted into the main free list, and add the segment to
+/// `segments`.
+///
+/// The region must be word-aligned, and big enough for a segment header and a
+/// cell.
+unsafe fn new_segment<'a>(
+    ptr: NonNull<u8>,
+    size: Bytes,
+    segments: &mut *const SegmentHeader,
+    policy: &dyn AllocPolicy<'a>,
+) -> &'a FreeCell<'a> {
+    let end = ptr.as_ptr() as usize + size.0;
+    let segment = ptr.as_ptr() as *mut SegmentHeader;
+    ptr::write(
+        segment,
+        SegmentHeader {
+            next: *segments,
+            end,
+        },
+    );
+    *segments = segment;
+
+    let free_cell = &*FreeCell::from_uninitialized(
+        unchecked_unwrap(NonNull::new(segment.offset(1) as *mut u8)),
+        size - size_of::<SegmentHeader>() - size_of::<CellHeader>(),
+        None,
+        policy,
+    );
+
+    free_cell
+        .header
+        .neighbors
+        .set_next(end as *const CellHeader);
+    CellHeader::set_next_cell_is_invalid(&free_cell.header.neighbors);
+    free_cell.set_segment_start();
+    free_cell
+}
+
+/// Turn the given donated region of memory into a new segment, like
+/// `new_segment`.
+///
+/// Panics if the region is not word-aligned or too small to hold a cell.
+unsafe fn new_donated_cell<'a>(
+    ptr: NonNull<u8>,
+    size: Bytes,
+    segments: &mut *const SegmentHeader,
+    policy: &dyn AllocPolicy<'a>,
+) -> &'a FreeCell<'a> {
+    let word: Bytes = size_of::<usize>();
+    let size = Bytes(size.0 & !(word.0 - 1));
+
+    assert!(
+        ptr.as_ptr() as usize & (word.0 - 1) == 0,
+        "donated region must be word-aligned"
+    );
+    let min_size: Bytes = LargeAllocPolicy::MIN_CELL_SIZE.into();
+    assert!(
+        size >= min_size + size_of::<SegmentHeader>() + size_of::<CellHeader>(),
+        "donated region is too small"
+    );
+
+    new_segment(ptr, size, segments, policy)
+}
+
+/// Move every cell in the free list starting at `from` into the free list
+/// starting at `to`, leaving `from` empty.
+///
+/// This is O(number of cells moved), times the length of `to` when the free
+/// list is address-ordered.
+unsafe fn move_free_list<'a>(
+    from: &Cell<*const FreeCell<'a>>,
+    to: &Cell<*const FreeCell<'a>>,
+    policy: &dyn AllocPolicy<'a>,
+) {
+    let mut current = get_free_list_link(from);
+    set_free_list_link(from, ptr::null());
+    while let Some(cell) = current.as_ref() {
+        current = cell.next_free();
+        // A pending merge would be with the next cell in `from`, which might not
+        // follow this cell in `to`.
+        cell.clear_next_free_can_merge();
+        cell.set_next_free(ptr::null());
+        cell.set_prev_free(ptr::null());
+        cell.insert_into_free_list(to, policy);
+    }
+}
+
+/// Merge every run of physically adjacent free cells in the given free list
+/// into a single cell, including the ones that the lazy merging in
+/// `dealloc_cell` left alone.
+///
+/// This is O(number of free cells).
+unsafe fn compact_free_list<'a>(head: &Cell<*const FreeCell<'a>>, policy: &dyn AllocPolicy<'a>) {
+    extra_assert!(policy.should_merge_adjacent_free_cells());
+
+    // First, rebuild the free list from only the cells that start a run of
+    // free cells, preserving their relative order. Every other free cell is
+    // about to be merged into its previous adjacent cell. We don't touch the
+    // headers of the cells we drop yet, since we still need to read their
+    // free list links.
+    let mut current = get_free_list_link(head);
+    head.set(ptr::null());
+    let mut tail = head;
+    let mut prev = ptr::null();
+
+    while let Some(cell) = current.as_ref() {
+        current = cell.next_free();
+
+        let prev_is_free = cell.header.neighbors.prev().map_or(false, |p| p.is_free());
+        if prev_is_free {
+            continue;
+        }
+
+        // A pending merge means the previous adjacent cell is free.
+        extra_assert!(!cell.next_free_can_merge());
+        cell.set_next_free(ptr::null());
+        cell.set_prev_free(prev);
+        set_free_list_link(tail, cell);
+        tail = &cell.next_free_raw;
+        prev = cell;
+    }
+
+    // Then, fold each run of free cells into its first cell.
+    let mut current = get_free_list_link(head);
+    while let Some(cell) = current.as_ref() {
+        while let Some(next) = cell
+            .header
+            .neighbors
+            .next()
+            .and_then(|n| n.as_free_cell())
+        {
+            cell.header.absorb_next(&next.header);
+        }
+
+        write_free_pattern(cell, cell.header.size(), policy);
+        current = cell.next_free();
+    }
+
+    assert_is_valid_free_list(head.get(), policy);
+}
+
+/// A wee allocator.
+///
+/// # Safety
+///
+/// When used in unix environments, cannot move in memory. Typically not an
+/// issue if you're just using this as a `static` global allocator.
+pub struct WeeAlloc<'a> {
+    head: imp::Exclusive<*const FreeCell<'a>>,
+
+    // The policy for the main free list. It may only refill the main free list
+    // with more pages from the backend if we weren't created with
+    // `from_slice`.
+    large_alloc_policy: LargeAllocPolicy,
+
+    oom_hook: imp::Exclusive<Option<fn(Layout)>>,
+
+    #[cfg(feature = "fault_injection")]
+    faults: FaultInjection,
+
+    #[cfg(feature = "size_classes")]
+    size_classes: size_classes::SizeClasses<'a>,
+
+    #[cfg(feature = "buddy_classes")]
+    buddy_classes: buddy::BuddyClasses,
+}
+
+// Which allocations to fail on purpose, set with
+// `WeeAlloc::fail_next_n_allocations` and `WeeAlloc::fail_every_nth_allocation`.
+#[cfg(feature = "fault_injection")]
+struct FaultInjection {
+    // How many of the next allocations to fail.
+    fail_next: AtomicUsize,
+    // Fail every allocation whose number is a multiple of this, unless it is
+    // zero.
+    fail_every: AtomicUsize,
+    // The number of allocations counted towards `fail_every`.
+    allocs: AtomicUsize,
+}
+
+#[cfg(feature = "fault_injection")]
+impl ConstInit for FaultInjection {
+    const INIT: FaultInjection = FaultInjection {
+        fail_next: AtomicUsize::new(0),
+        fail_every: AtomicUsize::new(0),
+        allocs: AtomicUsize::new(0),
+    };
+}
+
+#[cfg(feature = "fault_injection")]
+impl FaultInjection {
+    // Whether the allocation being made now should fail.
+    fn should_fail(&self) -> bool {
+        let mut next = self.fail_next.load(Ordering::Relaxed);
+        while next > 0 {
+            match self.fail_next.compare_exchange_weak(
+                next,
+                next - 1,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(x) => next = x,
+            }
+        }
+
+        let every = self.fail_every.load(Ordering::Relaxed);
+        if every == 0 {
+            return false;
+        }
+        let n = self.allocs.fetch_add(1, Ordering::Relaxed) + 1;
+        n % every == 0
+    }
+}
+
+unsafe impl<'a> Sync for WeeAlloc<'a> {}
+
+impl<'a> Default for WeeAlloc<'a> {
+    fn default() -> Self {
+        WeeAlloc::new()
+    }
+}
+
+// The layout of `[T; n]`, or `None` if its size overflows.
+fn array_layout<T>(n: usize) -> Option<Layout> {
+    let size = mem::size_of::<T>().checked_mul(n)?;
+    Layout::from_size_align(size, mem::align_of::<T>()).ok()
+}
+
+// The alignment of `layout`, in bytes. `Layout` never has a zero alignment,
+// but if one shows up anyway, treat it as byte alignment. Every entry point
+// must normalize alignment the same way, so that deallocating and
+// reallocating pick the same free list that allocating did.
+fn layout_align(layout: &Layout) -> Bytes {
+    if layout.align() == 0 {
+        Bytes(1)
+    } else {
+        Bytes(layout.align())
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "guard_words")] {
+        // Written just past the end of every allocation, and checked when it is
+        // freed.
+        const GUARD_WORD: usize = 0xDEAD_BEEF_DEAD_BEEF_u64 as usize;
+
+        // How many bytes to allocate for `size` bytes of user data: one extra
+        // word for the guard. Zero-sized allocations don't have a cell, so they
+        // don't get a guard word either.
+        fn guarded_size(size: usize) -> usize {
+            if size == 0 {
+                0
+            } else {
+                size.saturating_add(mem::size_of::<usize>())
+            }
+        }
+
+        // The guard word goes immediately after the `size` bytes of user data,
+        // so it isn't necessarily aligned.
+        unsafe fn write_guard_word(ptr: NonNull<u8>, size: usize) {
+            if size != 0 {
+                let guard = ptr.as_ptr().add(size) as *mut usize;
+                ptr::write_unaligned(guard, GUARD_WORD);
+            }
+        }
+
+        unsafe fn check_guard_word(ptr: NonNull<u8>, size: usize) {
+            if size != 0 {
+                let guard = ptr.as_ptr().add(size) as *const usize;
+                if ptr::read_unaligned(guard) != GUARD_WORD {
+                    panic!(
+                        "heap buffer overrun: the {} byte allocation at {:p} wrote past its end",
+                        size,
+                        ptr.as_ptr()
+                    );
+                }
+            }
+        }
+    } else {
+        #[inline(always)]
+        fn guarded_size(size: usize) -> usize {
+            size
+        }
+
+        #[inline(always)]
+        unsafe fn write_guard_word(_ptr: NonNull<u8>, _size: usize) {}
+
+        #[inline(always)]
+        unsafe fn check_guard_word(_ptr: NonNull<u8>, _size: usize) {}
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "zero_header_on_free")] {
+        // Stamped into every free cell that has room for it, just after its
+        // free list links, and cleared again when the cell is allocated.
+        // Finding it in a cell that is being freed means that the cell is
+        // already free.
+        const FREE_MAGIC: usize = 0xF3EE_F3EE_F3EE_F3EE_u64 as usize;
+        const FREE_MAGIC_SIZE: Bytes = Bytes(mem::size_of::<usize>());
+
+        // Where the free magic goes in a cell with `size` bytes of data
+        // starting at `data`, if there is room for it.
+        unsafe fn free_magic_slot(data: *const u8, size: Bytes) -> Option<*mut usize> {
+            if size >= MIN_CELL_DATA_SIZE + FREE_MAGIC_SIZE {
+                Some(data.add(MIN_CELL_DATA_SIZE.0) as *mut usize)
+            } else {
+                None
+            }
+        }
+
+        unsafe fn stamp_free_magic(data: *const u8, size: Bytes) {
+            if let Some(slot) = free_magic_slot(data, size) {
+                ptr::write(slot, FREE_MAGIC);
+            }
+        }
+
+        unsafe fn clear_free_magic(data: *const u8, size: Bytes) {
+            if let Some(slot) = free_magic_slot(data, size) {
+                ptr::write(slot, 0);
+            }
+        }
+
+        // Freeing the cell's data overwrites its first words with the free
+        // list links anyway, so the magic word is all that needs checking.
+        unsafe fn check_free_magic(ptr: NonNull<u8>, size: usize) {
+            if let Some(slot) = free_magic_slot(ptr.as_ptr(), cell_data_size(size)) {
+                if ptr::read(slot) == FREE_MAGIC {
+                    panic!(
+                        "double free: the {} byte allocation at {:p} is already free",
+                        size,
+                        ptr.as_ptr()
+                    );
+                }
+            }
+        }
+    } else {
+        // Only needed to skip over the free magic when poisoning free cells.
+        #[cfg(feature = "extra_assertions")]
+        const FREE_MAGIC_SIZE: Bytes = Bytes(0);
+
+        #[inline(always)]
+        unsafe fn stamp_free_magic(_data: *const u8, _size: Bytes) {}
+
+        #[inline(always)]
+        unsafe fn clear_free_magic(_data: *const u8, _size: Bytes) {}
+
+        #[inline(always)]
+        unsafe fn check_free_magic(_ptr: NonNull<u8>, _size: usize) {}
+    }
+}
+
+// How much cell data an allocation of `size` bytes needs: its guard word, if
+// any, and room for the free list links once the cell is freed. Zero-sized
+// allocations don't have a cell at all.
+fn cell_data_size(size: usize) -> Bytes {
+    if size == 0 {
+        Bytes(0)
+    } else {
+        cmp::max(Bytes(guarded_size(size)), MIN_CELL_DATA_SIZE)
+    }
+}
+
+impl<'a> fmt::Debug for WeeAlloc<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("WeeAlloc");
+        s.field("head", &FreeListDebug(&self.head));
+        #[cfg(feature = "size_classes")]
+        s.field("size_classes", &self.size_classes);
+        #[cfg(feature = "buddy_classes")]
+        s.field("buddy_classes", &self.buddy_classes);
+        s.finish()
+    }
+}
+
+// Formats every cell in a free list, or `<locked>` if the free list is
+// currently in use and we can't get access to it without blocking.
+//
+// The cells are copied out while we hold the lock, and only formatted once it
+// is released: the formatter may well allocate, and if this allocator is the
+// global allocator, that needs the lock too.
+struct FreeListDebug<'a, 'b>(&'b imp::Exclusive<*const FreeCell<'a>>)
+where
+    'a: 'b;
+
+// How many cells of each free list `FreeListDebug` shows. The rest are only
+// counted.
+const MAX_DEBUG_CELLS: usize = 32;
+
+impl<'a, 'b> fmt::Debug for FreeListDebug<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let snapshot = unsafe {
+            self.0.try_with_exclusive_access(|head| {
+                let mut cells = [CellDebug::EMPTY; MAX_DEBUG_CELLS];
+                let mut len = 0;
+                let mut current = *head;
+                while !current.is_null() {
+                    if len < MAX_DEBUG_CELLS {
+                        cells[len] = CellDebug::of(&(*current).header);
+                    }
+                    len += 1;
+                    current = (*current).next_free();
+                }
+                (cells, len)
+            })
+        };
+
+        let (cells, len) = match snapshot {
+            Some(snapshot) => snapshot,
+            None => return f.write_str("<locked>"),
+        };
+        let mut list = f.debug_list();
+        list.entries(&cells[..cmp::min(len, MAX_DEBUG_CELLS)]);
+        if len > MAX_DEBUG_CELLS {
+            list.entry(&format_args!("... {} more", len - MAX_DEBUG_CELLS));
+        }
+        list.finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CellDebug {
+    address: usize,
+    size: usize,
+    is_allocated: bool,
+    next_cell_is_invalid: bool,
+}
+
+impl CellDebug {
+    const EMPTY: CellDebug = CellDebug {
+        address: 0,
+        size: 0,
+        is_allocated: false,
+        next_cell_is_invalid: false,
+    };
+
+    fn of(cell: &CellHeader) -> CellDebug {
+        CellDebug {
+            address: cell as *const CellHeader as usize,
+            size: cell.size().0,
+            is_allocated: cell.is_allocated(),
+            next_cell_is_invalid: CellHeader::next_cell_is_invalid(&cell.neighbors),
+        }
+    }
+}
+
+impl fmt::Debug for CellDebug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cell")
+            .field("address", &(self.address as *const u8))
+            .field("size", &self.size)
+            .field("is_allocated", &self.is_allocated)
+            .field("next_cell_is_invalid", &self.next_cell_is_invalid)
+            .finish()
+    }
+}
+
+impl<'a> ConstInit for WeeAlloc<'a> {
+    const INIT: WeeAlloc<'a> = WeeAlloc {
+        head: imp::Exclusive::INIT,
+
+        large_alloc_policy: LargeAllocPolicy {
+            can_grow: true,
+            segments: imp::Exclusive::INIT,
+            last_grow_pages: AtomicUsize::new(0),
+            #[cfg(feature = "extra_assertions")]
+            poison_byte: AtomicUsize::new(NO_POISON_BYTE),
+            #[cfg(feature = "trace_events")]
+            event_hook: AtomicUsize::new(0),
+        },
+
+        oom_hook: imp::Exclusive::INIT,
+
+        #[cfg(feature = "fault_injection")]
+        faults: FaultInjection::INIT,
+
+        #[cfg(feature = "size_classes")]
+        size_classes: size_classes::SizeClasses::INIT,
+
+        #[cfg(feature = "buddy_classes")]
+        buddy_classes: buddy::BuddyClasses::INIT,
+    };
+}
+
+impl<'a> WeeAlloc<'a> {
+    /// An initial `const` default construction of a `WeeAlloc` allocator.
+    ///
+    /// This is usable for initializing `static`s that get set as the global
+    /// allocator.
+    pub const INIT: Self = <Self as ConstInit>::INIT;
+
+    /// The fewest bytes that any allocation of at least one byte gets. Asking
+    /// for less than this wastes the difference.
+    pub const MIN_ALLOC_SIZE: Bytes = MIN_CELL_DATA_SIZE;
+
+    /// The largest alignment that an allocation may ask for. Larger ones always
+    /// fail, since the padding they might need doesn't fit in the address
+    /// space.
+    pub const MAX_SUPPORTED_ALIGN: Bytes = Bytes(MAX_SUPPORTED_ALIGN);
+
+    /// Whether an allocation with the given layout can be represented at all:
+    /// its alignment is at most `MAX_SUPPORTED_ALIGN`, and its size, once
+    /// rounded up and padded for the alignment, doesn't overflow the address
+    /// space.
+    ///
+    /// This does the same checks that allocating does, so when it returns
+    /// `true`, allocating with `layout` can only fail because memory ran out.
+    /// Use it to validate untrusted layouts up front.
+    pub fn can_allocate(layout: Layout) -> bool {
+        let align = layout_align(&layout);
+        if align > Self::MAX_SUPPORTED_ALIGN {
+            return false;
+        }
+        if layout.size() == 0 {
+            return true;
+        }
+
+        let size: Words = match checked_round_up_to(cell_data_size(layout.size())) {
+            Some(size) => size,
+            None => return false,
+        };
+
+        // Every cell from the main free list may be padded out to a whole
+        // cache line, and a size class is refilled with a segment from the
+        // main free list much smaller than any size that could overflow.
+        #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+        let (size, align) = {
+            let line: Words = CACHE_LINE_SIZE.round_up_to();
+            match size.0.checked_add(line.0 - 1) {
+                Some(size) => (Words(size / line.0 * line.0), cmp::max(align, CACHE_LINE_SIZE)),
+                None => return false,
+            }
+        };
+
+        LargeAllocPolicy::pages_for(size, align).is_some()
+    }
+
+    /// Create a new, empty `WeeAlloc` allocator at runtime.
+    ///
+    /// Each `WeeAlloc` has its own, independent free lists, so separate
+    /// subsystems can each use their own allocator. However, all allocators
+    /// still get their pages from the same global WebAssembly engine /
+    /// operating system backend, and memory is never given back to it, even
+    /// when a `WeeAlloc` is dropped.
+    ///
+    /// Note that on unix, a `WeeAlloc` must not be moved once it has been used.
+    pub fn new() -> Self {
+        WeeAlloc {
+            head: imp::Exclusive::new(ptr::null()),
+
+            large_alloc_policy: LargeAllocPolicy {
+                can_grow: true,
+                segments: imp::Exclusive::new(ptr::null()),
+                last_grow_pages: AtomicUsize::new(0),
+                #[cfg(feature = "extra_assertions")]
+                poison_byte: AtomicUsize::new(NO_POISON_BYTE),
+                #[cfg(feature = "trace_events")]
+                event_hook: AtomicUsize::new(0),
+            },
+
+            oom_hook: imp::Exclusive::new(None),
+
+            #[cfg(feature = "fault_injection")]
+            faults: FaultInjection::INIT,
+
+            #[cfg(feature = "size_classes")]
+            size_classes: size_classes::SizeClasses::INIT,
+
+            #[cfg(feature = "buddy_classes")]
+            buddy_classes: buddy::BuddyClasses::INIT,
+        }
+    }
+
+    /// Donate a region of memory to this allocator's main free list.
+    ///
+    /// This lets you seed the allocator with memory that was reserved up
+    /// front, so that the first allocations don't need to request more pages
+    /// from the WebAssembly engine / operating system.
+    ///
+    /// If `size` is not a multiple of the word size, the trailing bytes are
+    /// left unused.
+    ///
+    /// # Safety
+    ///
+    /// The region `ptr..ptr + size` must be valid for reads and writes, must
+    /// not be used by anything else, and must outlive this allocator. The
+    /// memory is never handed back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` is not word-aligned or if the region is too small to
+    /// hold a cell.
+    pub unsafe fn donate(&self, ptr: NonNull<u8>, size: Bytes) {
+        let policy = self.large_alloc_policy() as &dyn AllocPolicy<'a>;
+        let free_cell = self
+            .large_alloc_policy
+            .segments
+            .with_exclusive_access(|segments| new_donated_cell(ptr, size, segments, policy));
+        self.head.with_exclusive_access(|head| {
+            let head_cell = Cell::new(*head);
+            free_cell.insert_into_free_list(&head_cell, policy);
+            *head = head_cell.get();
+        });
+    }
+
+    /// Take over all of `other`'s memory: its free cells are moved into this
+    /// allocator's free lists, and its segments become this allocator's
+    /// segments, so that `owns` recognizes them.
+    ///
+    /// This is useful when tearing down a subsystem that had an allocator of
+    /// its own, e.g. one created with `from_slice`, to make its memory
+    /// available to everyone else rather than leaking it.
+    ///
+    /// This takes time proportional to the number of `other`'s free cells, or
+    /// more with the `address_ordered_free_list` feature, where every cell is
+    /// inserted in order.
+    ///
+    /// # Safety
+    ///
+    /// No allocation made with `other` may still be live.
+    pub unsafe fn absorb(&self, mut other: WeeAlloc<'a>) {
+        // `other` was just moved here, so don't take any of its locks: POSIX
+        // doesn't allow using a `pthread_mutex_t` again once it has been used
+        // and then moved. Owning `other` is exclusive access enough.
+        let other_head = other.head.get_mut();
+
+        // Settle `other`'s pending merges first, while its cells are still in
+        // its own free list.
+        if other.large_alloc_policy.should_merge_adjacent_free_cells() {
+            let other_head_cell = Cell::new(*other_head);
+            compact_free_list(&other_head_cell, &other.large_alloc_policy);
+            *other_head = other_head_cell.get();
+        }
+
+        let policy = self.large_alloc_policy() as &dyn AllocPolicy<'a>;
+        self.head.with_exclusive_access(|head| {
+            let other_head_cell = Cell::new(*other_head);
+            let head_cell = Cell::new(*head);
+            move_free_list(&other_head_cell, &head_cell, policy);
+            *other_head = other_head_cell.get();
+            *head = head_cell.get();
+        });
+
+        #[cfg(feature = "size_classes")]
+        {
+            let other_counts = &other.size_classes.2;
+            let classes = self
+                .size_classes
+                .0
+                .iter()
+                .zip(other.size_classes.0.iter_mut());
+            for (index, (to, from)) in classes.enumerate() {
+                let size = size_classes::SizeClasses::class_size(index);
+                let policy = self
+                    .size_classes
+                    .policy(size, &self.head, self.large_alloc_policy());
+                let policy = &policy as &dyn AllocPolicy<'a>;
+                let from = from.get_mut();
+                to.with_exclusive_access(|to| {
+                    let from_cell = Cell::new(*from);
+                    let to_cell = Cell::new(*to);
+                    move_free_list(&from_cell, &to_cell, policy);
+                    *from = from_cell.get();
+                    *to = to_cell.get();
+                    self.size_classes.2[index].absorb(&other_counts[index]);
+                });
+            }
+        }
+
+        #[cfg(feature = "buddy_classes")]
+        self.buddy_classes.absorb(&mut other.buddy_classes);
+
+        let other_segments = other.large_alloc_policy.segments.get_mut();
+        if other_segments.is_null() {
+            return;
+        }
+        let mut last = *other_segments as *mut SegmentHeader;
+        while !(*last).next.is_null() {
+            last = (*last).next as *mut SegmentHeader;
+        }
+        self.large_alloc_policy
+            .segments
+            .with_exclusive_access(|segments| {
+                (*last).next = *segments;
+                *segments = *other_segments;
+            });
+        *other_segments = ptr::null();
+    }
+
+    /// Allocate from the main free list, out of the cell that `placement`
+    /// chooses. See the [`policy`] module.
+    ///
+    /// This bypasses size classes, so the allocation must be freed with
+    /// `dealloc_with_placement`, not with `dealloc`.
+    ///
+    /// # Safety
+    ///
+    /// The same as `GlobalAlloc::alloc`.
+    ///
+    /// [`policy`]: policy/index.html
+    pub unsafe fn alloc_with_placement<P>(
+        &self,
+        layout: Layout,
+        placement: &P,
+    ) -> Result<NonNull<u8>, AllocError>
+    where
+        P: policy::Placement + ?Sized,
+    {
+        let size = cell_data_size(layout.size());
+        let align = layout_align(&layout);
+        if size.0 == 0 {
+            return Ok(NonNull::new_unchecked(align.0 as *mut u8));
+        }
+        let size: Words = checked_round_up_to(size).ok_or(AllocError)?;
+
+        let ptr = self
+            .with_main_free_list(|head, policy| {
+                alloc_with_placement(size, align, layout, head, policy, placement)
+            })
+            .map_err(|_| AllocError)?;
+        write_guard_word(ptr, layout.size());
+        Ok(ptr)
+    }
+
+    /// Free an allocation that was made by `alloc_with_placement` with the
+    /// same `layout`.
+    ///
+    /// # Safety
+    ///
+    /// The same as `GlobalAlloc::dealloc`.
+    pub unsafe fn dealloc_with_placement(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        check_free_magic(ptr, layout.size());
+        check_guard_word(ptr, layout.size());
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+        self.dealloc_large_words(ptr, size);
+    }
+
+    /// Whether `ptr` points into memory that belongs to this allocator: any
+    /// segment that it got from the backend or by donation, or that it was
+    /// created with by `from_slice`.
+    ///
+    /// This returns `true` for any address within such a segment, whether it
+    /// is in an allocated cell, a free cell, or a cell's header. It only
+    /// compares addresses and never dereferences `ptr`, so it is fine to call
+    /// with pointers from anywhere, such as ones received over FFI.
+    ///
+    /// This takes the lock on the allocator's list of segments, and walks the
+    /// list, so it is O(number of segments).
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        self.large_alloc_policy.owns(ptr)
+    }
+
+    /// Create an allocator whose allocations are all carved out of `buf`.
+    ///
+    /// The allocator never requests memory from the WebAssembly engine /
+    /// operating system, so once `buf` is used up, allocations fail. This is
+    /// handy as a bounded scratch allocator, e.g. in unit tests.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is too small to hold a single cell once its start is
+    /// rounded up to word alignment.
+    pub fn from_slice(buf: &'a mut [u8]) -> WeeAlloc<'a> {
+        let word: Bytes = size_of::<usize>();
+        let start = buf.as_mut_ptr() as usize;
+        let aligned_start = (start + word.0 - 1) & !(word.0 - 1);
+        let padding = aligned_start - start;
+        assert!(buf.len() > padding, "slice is too small");
+
+        let mut a = WeeAlloc {
+            head: imp::Exclusive::new(ptr::null()),
+
+            large_alloc_policy: LargeAllocPolicy {
+                can_grow: false,
+                segments: imp::Exclusive::new(ptr::null()),
+                last_grow_pages: AtomicUsize::new(0),
+                #[cfg(feature = "extra_assertions")]
+                poison_byte: AtomicUsize::new(NO_POISON_BYTE),
+                #[cfg(feature = "trace_events")]
+                event_hook: AtomicUsize::new(0),
+            },
+
+            oom_hook: imp::Exclusive::new(None),
+
+            #[cfg(feature = "fault_injection")]
+            faults: FaultInjection::INIT,
+
+            #[cfg(feature = "size_classes")]
+            size_classes: size_classes::SizeClasses::INIT,
+
+            #[cfg(feature = "buddy_classes")]
+            buddy_classes: buddy::BuddyClasses::INIT,
+        };
+
+        // Don't go through `with_exclusive_access` here, so that the returned
+        // allocator has never been used and is still free to move.
+        let mut segments = ptr::null();
+        let free_cell = unsafe {
+            new_donated_cell(
+                unchecked_unwrap(NonNull::new(aligned_start as *mut u8)),
+                Bytes(buf.len() - padding),
+                &mut segments,
+                a.large_alloc_policy(),
+            )
+        };
+        *a.head.get_mut() = free_cell;
+        *a.large_alloc_policy.segments.get_mut() = segments;
+        a
+    }
+
+    fn large_alloc_policy(&self) -> &LargeAllocPolicy {
+        &self.large_alloc_policy
+    }
+
+    #[cfg(feature = "size_classes")]
+    #[cfg_attr(feature = "inline_hot", inline(always))]
+    unsafe fn with_free_list_and_policy_for_size<F, T>(&self, size: Words, align: Bytes, f: F) -> T
+    where
+        F: for<'b> FnOnce(&'b Cell<*const FreeCell<'a>>, &'b dyn AllocPolicy<'a>) -> T,
+    {
+        extra_assert!(size.0 > 0);
+        extra_assert!(align.0 > 0);
 
         if align <= size_of::<usize>() {
             if let Some(head) = self.size_classes.get(size) {
-                let policy = size_classes::SizeClassAllocPolicy(&self.head);
+                let policy = self
+                    .size_classes
+                    .policy(size, &self.head, self.large_alloc_policy());
                 let policy = &policy as &dyn AllocPolicy<'a>;
                 return head.with_exclusive_access(|head| {
                     let head_cell = Cell::new(*head);
@@ -1002,142 +3759,965 @@ impl<'a> WeeAlloc<'a> {
                 });
             }
         }
+        self.with_main_free_list(f)
+    }
+
+    #[cfg(not(feature = "size_classes"))]
+    #[cfg_attr(feature = "inline_hot", inline(always))]
+    unsafe fn with_free_list_and_policy_for_size<F, T>(&self, size: Words, _align: Bytes, f: F) -> T
+    where
+        F: for<'b> FnOnce(&'b Cell<*const FreeCell<'a>>, &'b dyn AllocPolicy<'a>) -> T,
+    {
+        extra_assert!(size.0 > 0);
+        self.with_main_free_list(f)
+    }
+
+    #[inline]
+    unsafe fn with_main_free_list<F, T>(&self, f: F) -> T
+    where
+        F: for<'b> FnOnce(&'b Cell<*const FreeCell<'a>>, &'b dyn AllocPolicy<'a>) -> T,
+    {
+        let policy = self.large_alloc_policy() as &dyn AllocPolicy<'a>;
+        self.head.with_exclusive_access(|head| {
+            let head_cell = Cell::new(*head);
+            let result = f(&head_cell, policy);
+            *head = head_cell.get();
+            result
+        })
+    }
+
+    /// Warm up the free lists by reserving room for `count` allocations of
+    /// the given `layout`.
+    ///
+    /// This allocates `count` cells from the appropriate free list (refilling
+    /// it as needed) and then immediately frees them again, so that later
+    /// allocations of this layout don't need to grow the heap. Returns how many
+    /// allocations were actually reserved, which is less than `count` if we
+    /// ran out of memory partway through.
+    pub fn reserve(&self, layout: Layout, count: usize) -> usize {
+        if layout.size() == 0 {
+            return count;
+        }
+
+        unsafe {
+            // Thread the reserved allocations together through their first
+            // word, so we can free them all again without needing any extra
+            // storage. Every cell has room for at least a word, but make sure
+            // that the layout says so too.
+            let word: Bytes = size_of::<usize>();
+            let layout =
+                Layout::from_size_align_unchecked(cmp::max(layout.size(), word.0), layout.align());
+            let mut reserved = 0;
+            let mut last: *mut u8 = ptr::null_mut();
+            while reserved < count {
+                match self.alloc_impl(layout) {
+                    Ok(ptr) => {
+                        *(ptr.as_ptr() as *mut *mut u8) = last;
+                        last = ptr.as_ptr();
+                        reserved += 1;
+                    }
+                    Err(_) => break,
+                }
+            }
+
+            while let Some(ptr) = NonNull::new(last) {
+                last = *(ptr.as_ptr() as *mut *mut u8);
+                self.dealloc_impl(ptr, layout);
+            }
+
+            reserved
+        }
+    }
+
+    /// Warm up the size class for allocations of `words` words by adding
+    /// `count` free cells to it.
+    ///
+    /// Unlike `reserve`, this gets a single segment big enough for all of the
+    /// cells at once, and splits it up in one pass. If that segment can't be
+    /// obtained, it settles for fewer cells, halving `count` until it succeeds.
+    /// Returns how many cells were added, which is zero when `words` is too big
+    /// to have a size class.
+    #[cfg(feature = "size_classes")]
+    pub fn populate_size_class(&self, words: Words, count: usize) -> usize {
+        if words.0 == 0 {
+            return 0;
+        }
+        let size: Words = cell_data_size(Bytes::from(words).0).round_up_to();
+        unsafe {
+            self.size_classes
+                .populate(size, count, &self.head, self.large_alloc_policy())
+        }
+    }
+
+    /// Empty every size class, and give all of their segments back to the
+    /// main free list, leaving the allocations made from the main free list
+    /// alone.
+    ///
+    /// This is for programs that make lots of small, short-lived allocations
+    /// in phases, between long-lived large ones: at the end of a phase, the
+    /// small allocations are dropped all at once, in time proportional to the
+    /// number of cells in the heap, and their memory can then satisfy
+    /// allocations of any size. Returns nothing to the WebAssembly engine /
+    /// operating system.
+    ///
+    /// # Safety
+    ///
+    /// Every allocation made from a size class is freed, so none of them may be
+    /// used or deallocated afterwards, and nothing may allocate from this
+    /// allocator meanwhile. Those are the allocations of at most 256 words,
+    /// aligned to at most a word, including the cells held by a
+    /// `ThreadCachedWeeAlloc`'s caches.
+    #[cfg(feature = "size_classes")]
+    pub unsafe fn reset_size_classes(&self) {
+        for (class, counts) in self.size_classes.0.iter().zip(self.size_classes.2.iter()) {
+            class.with_exclusive_access(|head| {
+                *head = ptr::null();
+                counts.reset();
+            });
+        }
+        self.head.with_exclusive_access(|head| {
+            self.large_alloc_policy
+                .segments
+                .with_exclusive_access(|segments| {
+                    free_size_class_segments(head, *segments, self.large_alloc_policy())
+                })
+        });
+    }
+
+    /// Like `reset_size_classes`, but without taking any locks, since `&mut
+    /// self` already rules out anyone else using this allocator.
+    ///
+    /// # Safety
+    ///
+    /// The same as `reset_size_classes`.
+    #[cfg(feature = "size_classes")]
+    pub unsafe fn reset_size_classes_mut(&mut self) {
+        let counts = self.size_classes.2.iter();
+        for (class, counts) in self.size_classes.0.iter_mut().zip(counts) {
+            *class.get_mut() = ptr::null();
+            counts.reset();
+        }
+        let segments = *self.large_alloc_policy.segments.get_mut();
+        free_size_class_segments(self.head.get_mut(), segments, &self.large_alloc_policy);
+    }
+
+    /// Defragment the main free list by merging every pair of adjacent free
+    /// cells.
+    ///
+    /// Freed cells are usually merged with their free neighbors lazily, and
+    /// some merges are skipped altogether, so a long-running program can end up
+    /// with many small free cells that can't satisfy a medium-sized allocation
+    /// even though enough bytes are free in total. This does all of the
+    /// outstanding merges, which maximizes the largest allocation that can be
+    /// satisfied without growing the heap.
+    ///
+    /// This takes time proportional to the number of free cells, so call it
+    /// while idle, not on a hot path. It does not give any memory back to the
+    /// WebAssembly engine / operating system, and leaves the size classes'
+    /// free lists alone, since their cells are never merged.
+    ///
+    /// With the `no_merge` feature, cells are never merged, so this does
+    /// nothing.
+    pub fn compact(&self) {
+        if !self.large_alloc_policy().should_merge_adjacent_free_cells() {
+            return;
+        }
+        unsafe {
+            self.head.with_exclusive_access(|head| {
+                let head_cell = Cell::new(*head);
+                compact_free_list(&head_cell, self.large_alloc_policy());
+                *head = head_cell.get();
+            });
+        }
+    }
+
+    /// The size of the largest cell in the main free list, i.e. roughly the
+    /// largest allocation that can be satisfied without growing the heap.
+    ///
+    /// This doesn't account for merges that are still pending; call `compact`
+    /// first for those.
+    pub fn largest_free_cell(&self) -> Bytes {
+        unsafe {
+            self.head.with_exclusive_access(|head| {
+                let mut largest = Bytes(0);
+                let mut current = *head;
+                while let Some(cell) = current.as_ref() {
+                    largest = cmp::max(largest, cell.header.size());
+                    current = cell.next_free();
+                }
+                largest
+            })
+        }
+    }
+
+    /// Check this allocator's internal invariants, returning the first
+    /// violation found rather than panicking.
+    ///
+    /// This walks the main free list and every size class's free list, checking
+    /// for cycles and allocated cells, and then checks that each cell in a
+    /// segment with free cells is well-formed and linked to its adjacent
+    /// neighbors in both directions. With `extra_assertions`, it also checks
+    /// that freed data hasn't been written to.
+    ///
+    /// This takes every free list's lock in turn and is linear in the size of
+    /// the heap, so it is meant for fuzzers and tests, not hot paths. It never
+    /// blocks: if a free list is already locked, it returns
+    /// `IntegrityError::Busy` rather than risk a deadlock.
+    pub fn verify_integrity(&self) -> Result<(), IntegrityError> {
+        unsafe {
+            self.head
+                .try_with_exclusive_access(|head| verify_free_list(*head, self.large_alloc_policy()))
+                .unwrap_or(Err(IntegrityError::Busy))?;
+
+            #[cfg(feature = "size_classes")]
+            {
+                for (index, class) in self.size_classes.0.iter().enumerate() {
+                    let size = size_classes::SizeClasses::class_size(index);
+                    let policy = self
+                        .size_classes
+                        .policy(size, &self.head, self.large_alloc_policy());
+                    class
+                        .try_with_exclusive_access(|head| verify_free_list(*head, &policy))
+                        .unwrap_or(Err(IntegrityError::Busy))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a compact description of every segment of this allocator, and of
+    /// the cells within it, to `w`, without allocating.
+    ///
+    /// Each segment gets a `segment <address> <size>` line, followed by one
+    /// `  +<offset> <size> <a|f>` line per cell, giving the cell's offset from
+    /// the start of the segment, the size of its data in bytes, and whether it
+    /// is allocated or free. A size class's segment shows up as a single
+    /// allocated cell.
+    ///
+    /// This is meant for post-mortem analysis, e.g. from a trap handler, where
+    /// the `Debug` output is too heavy: `w` can write into a fixed buffer. If
+    /// the free list is locked, only `busy` is written, rather than reading
+    /// cells that are in the middle of being changed.
+    pub fn dump_to<W: fmt::Write>(&self, w: &mut W) -> fmt::Result {
+        unsafe {
+            self.head
+                .try_with_exclusive_access(|_| {
+                    self.large_alloc_policy
+                        .segments
+                        .try_with_exclusive_access(|segments| dump_segments(*segments, w))
+                        .unwrap_or_else(|| w.write_str("busy\n"))
+                })
+                .unwrap_or_else(|| w.write_str("busy\n"))
+        }
+    }
 
-        let policy = &LARGE_ALLOC_POLICY as &dyn AllocPolicy<'a>;
-        self.head.with_exclusive_access(|head| {
-            let head_cell = Cell::new(*head);
-            let result = f(&head_cell, policy);
-            *head = head_cell.get();
-            result
-        })
+    /// Allocate like `try_alloc`, and tag the allocation with `tag`, so that
+    /// `retained_by_tag` counts it towards `tag` for as long as it is live.
+    ///
+    /// Only available with the `alloc_tags` feature. A tag is any number that
+    /// means something to the caller, such as an id for the call site, and is
+    /// kept when the allocation is reallocated. Allocations that aren't made
+    /// with `alloc_tagged` have the tag `0`.
+    ///
+    /// # Safety
+    ///
+    /// The same as `try_alloc`.
+    #[cfg(feature = "alloc_tags")]
+    pub unsafe fn alloc_tagged(&self, layout: Layout, tag: u16) -> Result<NonNull<u8>, AllocError> {
+        let ptr = self.try_alloc(layout)?;
+        if layout.size() != 0 {
+            tag_of(ptr).set(tag);
+        }
+        Ok(ptr)
     }
 
-    #[cfg(not(feature = "size_classes"))]
-    unsafe fn with_free_list_and_policy_for_size<F, T>(&self, size: Words, _align: Bytes, f: F) -> T
+    /// The number of bytes held by live allocations, for each tag that any of
+    /// them has, in increasing order of tag.
+    ///
+    /// Only available with the `alloc_tags` feature. The bytes counted are
+    /// the size of each allocation's cell, which can be a little more than
+    /// what was asked for. Cells held by a `ThreadCachedWeeAlloc`'s caches are
+    /// still live as far as this allocator knows. Comparing the results from
+    /// before and after a piece of code runs shows which tags it leaked.
+    ///
+    /// The iteration locks the whole allocator and walks the whole heap once
+    /// for every 64 tags, so this is meant for debugging only, and must not
+    /// be interleaved with allocating from this allocator.
+    #[cfg(feature = "alloc_tags")]
+    pub fn retained_by_tag<'b>(&'b self) -> RetainedByTag<'b, 'a> {
+        RetainedByTag {
+            alloc: self,
+            tags: [(0, Bytes(0)); TAGS_PER_WALK],
+            len: 0,
+            index: 0,
+            next_walk: Some(0),
+        }
+    }
+
+    // Call `f` with this allocator's segments, with every free list locked,
+    // in the same order that allocating locks them.
+    #[cfg(feature = "alloc_tags")]
+    unsafe fn with_heap_locked<F, T>(&self, f: F) -> T
     where
-        F: for<'b> FnOnce(&'b Cell<*const FreeCell<'a>>, &'b dyn AllocPolicy<'a>) -> T,
+        F: FnOnce(*const SegmentHeader) -> T,
     {
-        extra_assert!(size.0 > 0);
-        let policy = &LARGE_ALLOC_POLICY as &dyn AllocPolicy;
-        self.head.with_exclusive_access(|head| {
-            let head_cell = Cell::new(*head);
-            let result = f(&head_cell, policy);
-            *head = head_cell.get();
-            result
-        })
+        let locked = || {
+            self.head.with_exclusive_access(|_| {
+                self.large_alloc_policy
+                    .segments
+                    .with_exclusive_access(|segments| f(*segments))
+            })
+        };
+        #[cfg(feature = "size_classes")]
+        return with_size_classes_locked(&self.size_classes.0, locked);
+        #[cfg(not(feature = "size_classes"))]
+        return locked();
+    }
+
+    /// Poison the data of this allocator's free cells with `byte`, instead of
+    /// the default `0x57` for the main free list and `0x35` for size classes.
+    ///
+    /// Only available with the `extra_assertions` feature, which poisons freed
+    /// memory and checks that it is still poisoned before reusing it. A
+    /// distinctive byte makes this allocator's freed memory easy to tell apart
+    /// from other memory in a dump. Cells that are already free are poisoned
+    /// again with the new byte.
+    ///
+    /// Call this before other threads start using the allocator: a concurrent
+    /// allocation might find a cell that is still poisoned with the old byte,
+    /// and fail the poison check.
+    #[cfg(feature = "extra_assertions")]
+    pub fn set_poison_byte(&self, byte: u8) {
+        unsafe {
+            #[cfg(feature = "size_classes")]
+            for class in self.size_classes.0.iter() {
+                class.with_exclusive_access(|head| repoison_free_list(*head, byte));
+            }
+            self.head.with_exclusive_access(|head| {
+                repoison_free_list(*head, byte);
+                self.large_alloc_policy
+                    .poison_byte
+                    .store(byte as usize, Ordering::Relaxed);
+            });
+        }
+    }
+
+    /// The most bytes of memory that have been obtained from the WebAssembly
+    /// engine / operating system at once: the high-water mark of
+    /// `pages_in_use`.
+    ///
+    /// This is global across all `WeeAlloc` instances, and doesn't include any
+    /// memory given to the allocator via `donate`.
+    #[cfg(feature = "track_peak")]
+    pub fn peak_bytes(&self) -> Bytes {
+        PAGE_COUNTS.peak().into()
+    }
+
+    /// The number of pages that have been obtained from the WebAssembly engine
+    /// / operating system so far.
+    ///
+    /// Pages are never given back, except with the `static_array_backend`, so
+    /// this usually only grows, and only when the heap does. On WebAssembly,
+    /// this is `memory.size` minus whatever pages were there before `wee_alloc`
+    /// started. Like `peak_bytes`, this is global across all `WeeAlloc`
+    /// instances, and doesn't include donated memory.
+    pub fn pages_in_use(&self) -> Pages {
+        PAGE_COUNTS.in_use()
+    }
+
+    /// Refuse to get more pages from the WebAssembly engine / operating system
+    /// once `pages_in_use` would exceed `bytes`. Allocations that can't be
+    /// satisfied from the free lists then fail, just like when memory runs out.
+    /// A limit of zero, the default, means no limit.
+    ///
+    /// Unlike a WebAssembly module's declared maximum memory, this works with
+    /// every backend and can be changed at any time. Pages that are already in
+    /// use are kept even if they exceed a new limit. Like `pages_in_use`, this
+    /// is global across all `WeeAlloc` instances, and doesn't limit donated
+    /// memory.
+    pub fn set_memory_limit(&self, bytes: Bytes) {
+        MEMORY_LIMIT.store(bytes.0, Ordering::Relaxed);
+    }
+
+    /// A snapshot of the allocation counters.
+    ///
+    /// Like `peak_bytes`, these are global across all `WeeAlloc` instances.
+    #[cfg(feature = "count_allocations")]
+    pub fn counters(&self) -> AllocCounters {
+        AllocCounters {
+            allocs: COUNTERS.allocs.load(Ordering::Relaxed),
+            deallocs: COUNTERS.deallocs.load(Ordering::Relaxed),
+            first_fit_hits: COUNTERS.first_fit_hits.load(Ordering::Relaxed),
+            refills: COUNTERS.refills.load(Ordering::Relaxed),
+            realloc_bytes_copied: COUNTERS.realloc_bytes_copied.load(Ordering::Relaxed),
+            realloc_bytes_grown_in_place: COUNTERS
+                .realloc_bytes_grown_in_place
+                .load(Ordering::Relaxed),
+        }
+    }
+
+    /// How many allocations of each size have been served so far.
+    ///
+    /// Bucket `i` counts the allocations whose size, rounded up to whole
+    /// words, is at least `2^i` and less than `2^(i + 1)` bytes. Zero-sized
+    /// allocations aren't counted. Like `peak_bytes`, this is global across all
+    /// `WeeAlloc` instances.
+    #[cfg(feature = "runtime_histogram")]
+    pub fn alloc_size_histogram(&self) -> [usize; 64] {
+        let mut histogram = [0; NUM_SIZE_BUCKETS];
+        for (n, bucket) in histogram.iter_mut().zip(ALLOC_SIZE_HISTOGRAM.iter()) {
+            *n = bucket.load(Ordering::Relaxed);
+        }
+        histogram
+    }
+
+    /// Set a hook that is called with the failing `Layout` whenever an
+    /// allocation can't be satisfied, right before the error is returned (or,
+    /// for `GlobalAlloc`, before null is returned).
+    ///
+    /// This is useful for logging out-of-memory conditions that would
+    /// otherwise just trap. Pass `None` to remove the hook.
+    ///
+    /// If the hook allocates from this allocator and that allocation fails
+    /// too, the hook is re-entered.
+    pub fn set_oom_hook(&self, hook: Option<fn(Layout)>) {
+        unsafe {
+            self.oom_hook.with_exclusive_access(|h| *h = hook);
+        }
+    }
+
+    /// Set a hook that is called with an `AllocEvent` on every allocation,
+    /// deallocation, and growth of the heap. Pass `None` to remove the hook.
+    ///
+    /// This is a lightweight way to trace what the allocator is doing, for
+    /// example by forwarding the events to `defmt` on embedded targets, or
+    /// printing them on the host. Each `WeeAlloc` has a hook of its own, which
+    /// only sees that allocator's events.
+    ///
+    /// The hook is called while the allocator may be holding its locks, so it
+    /// must neither allocate nor panic.
+    #[cfg(feature = "trace_events")]
+    pub fn set_event_hook(&self, hook: Option<fn(AllocEvent)>) {
+        let hook = hook.map_or(0, |h| h as usize);
+        self.large_alloc_policy.event_hook.store(hook, Ordering::Release);
     }
 
+    /// Make the next `n` allocations from this allocator fail, without looking
+    /// at the free lists, as if it were out of memory. Any hook set with
+    /// `set_oom_hook` still runs for them. Pass zero to stop failing.
+    ///
+    /// This is useful for testing how a program copes with running out of
+    /// memory, without actually exhausting it.
+    #[cfg(feature = "fault_injection")]
+    pub fn fail_next_n_allocations(&self, n: usize) {
+        self.faults.fail_next.store(n, Ordering::Relaxed);
+    }
+
+    /// Make every `n`th allocation from this allocator fail from now on, as if
+    /// it were out of memory. Pass zero to stop failing.
+    ///
+    /// Allocations that fail because of `fail_next_n_allocations` don't count.
+    #[cfg(feature = "fault_injection")]
+    pub fn fail_every_nth_allocation(&self, n: usize) {
+        self.faults.allocs.store(0, Ordering::Relaxed);
+        self.faults.fail_every.store(n, Ordering::Relaxed);
+    }
+
+    /// Allocate memory for the given `layout`, returning an error rather than a
+    /// null pointer on failure.
+    ///
+    /// This is the same as `GlobalAlloc::alloc`, but is usable with any
+    /// `WeeAlloc` and on stable Rust.
+    ///
+    /// # Safety
+    ///
+    /// The same as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.alloc_impl(layout).map_err(|_| AllocError)
+    }
+
+    /// Like `try_alloc`, but also return how many bytes the allocation really
+    /// has room for, which is often more than `layout.size()`, since cells are
+    /// rounded up and leftovers too small to split off stay attached.
+    ///
+    /// Growable collections can use the extra room without reallocating. The
+    /// allocation may be deallocated or reallocated with any size between
+    /// `layout.size()` and the returned size.
+    ///
+    /// # Safety
+    ///
+    /// The same as `GlobalAlloc::alloc`.
+    pub unsafe fn try_alloc_at_least(
+        &self,
+        layout: Layout,
+    ) -> Result<(NonNull<u8>, usize), AllocError> {
+        self.alloc_excess_impl(layout).map_err(|_| AllocError)
+    }
+
+    /// Deallocate memory that was allocated by `try_alloc` with the same
+    /// `layout`.
+    ///
+    /// # Safety
+    ///
+    /// The same as `GlobalAlloc::dealloc`.
+    pub unsafe fn try_dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc_impl(ptr, layout)
+    }
+
+    /// Like `try_dealloc`, but go straight to the main free list, skipping
+    /// the lookup of which free list the allocation came from.
+    ///
+    /// With the `size_classes` feature, allocations with an alignment of at
+    /// most a word and a size of at most 256 words come from size classes
+    /// instead. Without it, every allocation comes from the main free list.
+    ///
+    /// # Safety
+    ///
+    /// The same as `try_dealloc`. Additionally, the allocation must not have
+    /// come from a size class, otherwise the behavior is undefined.
+    pub unsafe fn dealloc_large_unchecked(&self, ptr: NonNull<u8>, layout: Layout) {
+        count!(deallocs);
+        if layout.size() == 0 {
+            return;
+        }
+        trace_event!(self.large_alloc_policy(), AllocEvent::Dealloc { ptr: ptr.as_ptr() });
+
+        check_free_magic(ptr, layout.size());
+        check_guard_word(ptr, layout.size());
+        #[cfg(feature = "header_checksum")]
+        (*(ptr.as_ptr() as *const CellHeader).offset(-1))
+            .neighbors
+            .verify_checksum();
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+        #[cfg(feature = "size_classes")]
+        extra_assert!(
+            layout_align(&layout) > size_of::<usize>() || self.size_classes.get(size).is_none(),
+            "dealloc_large_unchecked called on an allocation from a size class"
+        );
+        #[cfg(feature = "buddy_classes")]
+        extra_assert!(
+            buddy::BuddyClasses::order(size, layout_align(&layout)).is_none(),
+            "dealloc_large_unchecked called on a buddy block"
+        );
+        self.dealloc_large_words(ptr, size);
+    }
+
+    /// Allocate uninitialized memory for an array of `n` values of type `T`.
+    ///
+    /// Returns an error if the array's size overflows, as well as when
+    /// allocation fails.
+    ///
+    /// ```
+    /// let wee = wee_alloc::WeeAlloc::new();
+    /// unsafe {
+    ///     let array = wee.alloc_array::<u32>(100).unwrap();
+    ///     for i in 0..100 {
+    ///         *array.as_ptr().offset(i) = i as u32;
+    ///     }
+    ///     assert_eq!(*array.as_ptr().offset(99), 99);
+    ///     wee.dealloc_array(array, 100);
+    /// }
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// The same as `try_alloc`.
+    pub unsafe fn alloc_array<T>(&self, n: usize) -> Result<NonNull<T>, AllocError> {
+        let layout = array_layout::<T>(n).ok_or(AllocError)?;
+        self.alloc_impl(layout)
+            .map(|ptr| ptr.cast())
+            .map_err(|_| AllocError)
+    }
+
+    /// Deallocate an array of `n` values of type `T` that was allocated by
+    /// `alloc_array` with the same `n`.
+    ///
+    /// # Safety
+    ///
+    /// The same as `try_dealloc`.
+    pub unsafe fn dealloc_array<T>(&self, ptr: NonNull<T>, n: usize) {
+        let layout =
+            array_layout::<T>(n).expect("`n` must be the same as given to `alloc_array`");
+        self.dealloc_impl(ptr.cast(), layout)
+    }
+
+    // Every typed allocation API, `try_alloc`, `alloc_array`, and the `Alloc`
+    // and `Allocator` impls, goes through here and keeps the `NonNull` all the
+    // way. Only `GlobalAlloc` turns a failure into a null pointer.
+    #[cfg_attr(feature = "inline_hot", inline(always))]
+    #[cfg_attr(feature = "outline_hot", inline(never))]
     unsafe fn alloc_impl(&self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        let size = Bytes(layout.size());
-        let align = if layout.align() == 0 {
-            Bytes(1)
+        count!(allocs);
+        #[cfg(feature = "fault_injection")]
+        let result = if self.faults.should_fail() {
+            Err(AllocErr)
+        } else {
+            self.alloc_without_oom_hook(layout)
+        };
+        #[cfg(not(feature = "fault_injection"))]
+        let result = self.alloc_without_oom_hook(layout);
+        if result.is_err() {
+            // Don't hold the lock while calling the hook, so that it may use
+            // this allocator itself.
+            if let Some(hook) = self.oom_hook.with_exclusive_access(|h| *h) {
+                hook(layout);
+            }
+        }
+        #[cfg(feature = "trace_events")]
+        {
+            if let Ok(ptr) = result {
+                trace_event!(
+                    self.large_alloc_policy(),
+                    AllocEvent::Alloc {
+                        size: layout.size(),
+                        ptr: ptr.as_ptr(),
+                    }
+                );
+            }
+        }
+        result
+    }
+
+    // Like `alloc_impl`, but also return how many bytes the allocation really
+    // has room for. The allocation may be deallocated or reallocated with any
+    // size between the requested size and that.
+    unsafe fn alloc_excess_impl(&self, layout: Layout) -> Result<(NonNull<u8>, usize), AllocErr> {
+        let ptr = self.alloc_impl(layout)?;
+
+        // The guard word sits right after the requested size, so there is no
+        // slack to offer.
+        if layout.size() == 0 || cfg!(feature = "guard_words") {
+            return Ok((ptr, layout.size()));
+        }
+
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+
+        // Buddy blocks have no header, but they are always exactly their
+        // order's size.
+        #[cfg(feature = "buddy_classes")]
+        {
+            if let Some(order) = buddy::BuddyClasses::order(size, layout_align(&layout)) {
+                return Ok((ptr, 1 << order));
+            }
+        }
+
+        let cell = &*(ptr.as_ptr() as *const CellHeader<'a>).offset(-1);
+        let usable: Words = cell.size().round_up_to();
+
+        // Deallocating with the excess size must still pick the free list that
+        // the cell came from. Rounding up to whole words always does.
+        let usable = if self.same_free_list(size, usable, layout_align(&layout)) {
+            usable
         } else {
-            Bytes(layout.align())
+            size
         };
+        let usable: Bytes = usable.into();
+        Ok((ptr, usable.0))
+    }
+
+    unsafe fn alloc_without_oom_hook(&self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
+        let size = cell_data_size(layout.size());
+        let align = layout_align(&layout);
 
         if size.0 == 0 {
-            // Ensure that our made up pointer is properly aligned by using the
-            // alignment as the pointer.
-            extra_assert!(align.0 > 0);
-            return Ok(NonNull::new_unchecked(align.0 as *mut u8));
+            return Ok(zero_sized_alloc(align));
         }
 
         let word_size: Words = checked_round_up_to(size).ok_or(AllocErr)?;
+        #[cfg(feature = "runtime_histogram")]
+        let rounded_size: Bytes = word_size.into();
+
+        // Every cell in a size class is the same size, so allocate the whole
+        // thing.
+        #[cfg(feature = "size_classes")]
+        let word_size = if align <= size_of::<usize>() {
+            size_classes::SizeClasses::round_up_to_class(word_size)
+        } else {
+            word_size
+        };
+
+        #[cfg(feature = "buddy_classes")]
+        {
+            if let Some(order) = buddy::BuddyClasses::order(word_size, align) {
+                let ptr = self
+                    .buddy_classes
+                    .alloc(order, &self.head, self.large_alloc_policy())?;
+                write_guard_word(ptr, layout.size());
+                #[cfg(feature = "runtime_histogram")]
+                record_alloc_size(rounded_size);
+                return Ok(ptr);
+            }
+        }
 
-        self.with_free_list_and_policy_for_size(word_size, align, |head, policy| {
+        let ptr = self.with_free_list_and_policy_for_size(word_size, align, |head, policy| {
             assert_is_valid_free_list(head.get(), policy);
             alloc_with_refill(word_size, align, head, policy)
-        })
+        })?;
+        write_guard_word(ptr, layout.size());
+        #[cfg(feature = "runtime_histogram")]
+        record_alloc_size(rounded_size);
+        Ok(ptr)
     }
 
+    #[cfg_attr(feature = "inline_hot", inline(always))]
+    #[cfg_attr(feature = "outline_hot", inline(never))]
     unsafe fn dealloc_impl(&self, ptr: NonNull<u8>, layout: Layout) {
-        let size = Bytes(layout.size());
-        if size.0 == 0 {
+        count!(deallocs);
+        if layout.size() == 0 {
             return;
         }
+        trace_event!(self.large_alloc_policy(), AllocEvent::Dealloc { ptr: ptr.as_ptr() });
+
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+        let align = layout_align(&layout);
+
+        // Buddy blocks don't have a cell header to check.
+        #[cfg(feature = "buddy_classes")]
+        {
+            if buddy::BuddyClasses::order(size, align).is_some() {
+                check_guard_word(ptr, layout.size());
+                return self.dealloc_words(ptr, size, align);
+            }
+        }
+
+        check_free_magic(ptr, layout.size());
+        check_guard_word(ptr, layout.size());
+        #[cfg(feature = "header_checksum")]
+        (*(ptr.as_ptr() as *const CellHeader).offset(-1))
+            .neighbors
+            .verify_checksum();
+        self.dealloc_words(ptr, size, align);
+    }
+
+    // Free the cell at `ptr`, which was allocated with at least `size` words
+    // and the given alignment.
+    #[cfg_attr(
+        not(any(feature = "size_classes", feature = "buddy_classes")),
+        allow(unused_variables)
+    )]
+    unsafe fn dealloc_words(&self, ptr: NonNull<u8>, size: Words, align: Bytes) {
+        #[cfg(feature = "buddy_classes")]
+        {
+            if let Some(order) = buddy::BuddyClasses::order(size, align) {
+                return self.buddy_classes.dealloc(
+                    ptr,
+                    order,
+                    &self.head,
+                    self.large_alloc_policy(),
+                );
+            }
+        }
+
+        #[cfg(feature = "size_classes")]
+        {
+            if align <= size_of::<usize>() && self.size_classes.get(size).is_some() {
+                return self.with_free_list_and_policy_for_size(size, align, |head, policy| {
+                    dealloc_from_free_list(ptr, size, head, policy)
+                });
+            }
+        }
+
+        self.dealloc_large_words(ptr, size);
+    }
+
+    // Free the cell at `ptr`, which was allocated from the main free list with
+    // at least `size` words.
+    #[cfg_attr(not(feature = "size_classes"), allow(unused_variables))]
+    unsafe fn dealloc_large_words(&self, ptr: NonNull<u8>, size: Words) {
+        let cell = (ptr.as_ptr() as *mut CellHeader<'a> as *const CellHeader<'a>).offset(-1);
+        let policy = self.large_alloc_policy() as &dyn AllocPolicy<'a>;
+
+        // Merging the cell's neighbors rewrites its header, so only look at it
+        // with the main free list locked.
+        let hungry: Option<Words> = self.head.with_exclusive_access(|head| {
+            // When freeing a large cell while a size class is hungry for a new
+            // segment, claim the cell for it, rather than freeing it.
+            #[cfg(feature = "size_classes")]
+            {
+                assert_can_dealloc(cell, policy);
+                if let Some(size) = self.size_classes.claim_for_hungry_size_class(&*cell) {
+                    return Some(size);
+                }
+            }
+
+            let head_cell = Cell::new(*head);
+            dealloc_from_free_list(ptr, size, &head_cell, policy);
+            *head = head_cell.get();
+            None
+        });
+
+        // Size classes take their own lock before the main free list's when
+        // refilling, so only hand the cell over once the latter is released.
+        #[cfg(feature = "size_classes")]
+        {
+            if let Some(size) = hungry {
+                self.size_classes.feed_hungry_size_class(
+                    &*(cell as *const AllocatedCell<'a>),
+                    size,
+                    &self.head,
+                    self.large_alloc_policy(),
+                );
+            }
+        }
+    }
+
+    // Whether allocations with the two given sizes and alignment are made from
+    // the same free list, and therefore use the same policy.
+    #[cfg(feature = "size_classes")]
+    fn same_free_list(&self, size: Words, new_size: Words, align: Bytes) -> bool {
+        if align > size_of::<usize>() {
+            return true;
+        }
+        match (self.size_classes.get(size), self.size_classes.get(new_size)) {
+            (None, None) => true,
+            (Some(a), Some(b)) => ptr::eq(a, b),
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "buddy_classes")]
+    fn same_free_list(&self, size: Words, new_size: Words, align: Bytes) -> bool {
+        // Buddy blocks don't have cell headers, so the callers' in-place
+        // tricks only work for the main free list.
+        buddy::BuddyClasses::order(size, align).is_none()
+            && buddy::BuddyClasses::order(new_size, align).is_none()
+    }
+
+    #[cfg(not(any(feature = "size_classes", feature = "buddy_classes")))]
+    fn same_free_list(&self, _size: Words, _new_size: Words, _align: Bytes) -> bool {
+        true
+    }
+
+    // Try to shrink the allocation at `ptr` to `new_size` bytes without moving
+    // it, by splitting off the end of its cell and returning that to the free
+    // list. Returns whether the allocation was shrunk.
+    unsafe fn shrink_in_place_impl(&self, ptr: NonNull<u8>, layout: Layout, new_size: usize) -> bool {
+        extra_assert!(new_size <= layout.size());
+
+        // Zero-sized allocations don't have cells.
+        if new_size == 0 || layout.size() == 0 {
+            return false;
+        }
+
+        check_guard_word(ptr, layout.size());
+        let new_user_size = new_size;
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+        let new_size: Words = cell_data_size(new_size).round_up_to();
+        let align = layout_align(&layout);
 
-        let size: Words = size.round_up_to();
-        let align = Bytes(layout.align());
+        // The cell must be deallocated into the same free list that it was
+        // allocated from, whichever size it is deallocated with.
+        if !self.same_free_list(size, new_size, align) {
+            return false;
+        }
 
         self.with_free_list_and_policy_for_size(size, align, |head, policy| {
             let cell = (ptr.as_ptr() as *mut CellHeader<'a> as *const CellHeader<'a>).offset(-1);
+            assert_can_dealloc(cell, policy);
+            assert_local_cell_invariants(cell);
             let cell = &*cell;
 
-            extra_assert!(cell.size() >= size.into());
-            extra_assert!(cell.is_allocated());
-            let cell: &AllocatedCell<'a> = mem::transmute(cell);
+            // Keep the tail on a cache line of its own, like any other cell.
+            #[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+            let new_size = unchecked_unwrap(round_up_to_min_align(new_size, policy));
+
+            // Like `FreeCell::try_alloc`, only split the cell if the remainder
+            // is big enough to be a cell of its own.
+            let split = cell.unchecked_data() as usize + Bytes::from(new_size).0;
+            let next = cell.neighbors.next_unchecked() as usize;
+            let spare: Words = Bytes(next - split).round_down_to();
+            let header: Words = size_of::<CellHeader>().round_up_to();
+            if spare < header + policy.min_cell_size(new_size) {
+                return false;
+            }
 
-            let free = cell.into_free_cell(policy);
+            let tail = &*FreeCell::from_uninitialized(
+                unchecked_unwrap(NonNull::new(split as *mut u8)),
+                Bytes(next - split) - size_of::<CellHeader>(),
+                None,
+                policy,
+            );
 
-            if policy.should_merge_adjacent_free_cells() {
-                // Merging with the _previous_ adjacent cell is easy: it is
-                // already in the free list, so folding this cell into it is all
-                // that needs to be done. The free list can be left alone.
-                //
-                // Merging with the _next_ adjacent cell is a little harder. It
-                // is already in the free list, but we need to splice it out
-                // from the free list, since its header will become invalid
-                // after consolidation, and it is *this* cell's header that
-                // needs to be in the free list. But we don't have access to the
-                // pointer pointing to the soon-to-be-invalid header, and
-                // therefore can't adjust that pointer. So we have a delayed
-                // consolidation scheme. We insert this cell just after the next
-                // adjacent cell in the free list, and set the next adjacent
-                // cell's `NEXT_FREE_CAN_MERGE` bit. The next time that we walk
-                // the free list for allocation, the bit will be checked and the
-                // consolidation will happen at that time.
-                //
-                // If _both_ the previous and next adjacent cells are free, we
-                // are faced with a dilemma. We cannot merge all previous,
-                // current, and next cells together because our singly-linked
-                // free list doesn't allow for that kind of arbitrary appending
-                // and splicing. There are a few different kinds of tricks we
-                // could pull here, but they would increase implementation
-                // complexity and code size. Instead, we use a heuristic to
-                // choose whether to merge with the previous or next adjacent
-                // cell. We could choose to merge with whichever neighbor cell
-                // is smaller or larger, but we don't. We prefer the previous
-                // adjacent cell because we can greedily consolidate with it
-                // immediately, whereas the consolidating with the next adjacent
-                // cell must be delayed, as explained above.
-
-                if let Some(prev) = free
-                    .header
-                    .neighbors
-                    .prev()
-                    .and_then(|p| (*p).as_free_cell())
-                {
-                    free.header.neighbors.remove();
-                    if CellHeader::next_cell_is_invalid(&free.header.neighbors) {
-                        CellHeader::set_next_cell_is_invalid(&prev.header.neighbors);
-                    }
+            Neighbors::append(cell, &tail.header);
+            if CellHeader::next_cell_is_invalid(&cell.neighbors) {
+                CellHeader::clear_next_cell_is_invalid(&cell.neighbors);
+                CellHeader::set_next_cell_is_invalid(&tail.header.neighbors);
+            }
 
-                    write_free_pattern(prev, prev.header.size(), policy);
-                    assert_is_valid_free_list(head.get(), policy);
-                    return;
-                }
+            let tail = tail.into_allocated_cell(policy);
+            dealloc_cell(tail, head, policy);
+            write_guard_word(ptr, new_user_size);
+            true
+        })
+    }
 
-                if let Some(next) = free
-                    .header
-                    .neighbors
-                    .next()
-                    .and_then(|n| (*n).as_free_cell())
-                {
-                    free.next_free_raw.set(next.next_free());
-                    next.next_free_raw.set(free);
-                    next.set_next_free_can_merge();
-
-                    assert_is_valid_free_list(head.get(), policy);
-                    return;
+    unsafe fn realloc_impl(
+        &self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        // Zero-sized allocations don't have a cell: their pointer is just their
+        // alignment. Reallocating down to zero frees the cell and hands back
+        // such a made up pointer, and reallocating one up from zero is a fresh
+        // allocation. Neither has any bytes to copy.
+        if new_size == 0 {
+            self.dealloc_impl(ptr, layout);
+            let align = layout_align(&layout);
+            return Ok(NonNull::new_unchecked(align.0 as *mut u8));
+        }
+        if layout.size() == 0 {
+            let new_layout =
+                Layout::from_size_align(new_size, layout.align()).map_err(|_| AllocErr)?;
+            return self.alloc_impl(new_layout);
+        }
+
+        // Sizes are rounded up to whole words, and cells are often bigger than
+        // what was asked for, so the new size frequently fits in the existing
+        // cell as it is. That's the cheapest outcome, so check for it first.
+        // When shrinking, also give back the end of the cell if we can. That's
+        // only possible when the old and new sizes are served by the same free
+        // list: a cell from a size class can't become a large allocation, or
+        // the other way around, so then we have to move.
+        let size: Words = cell_data_size(layout.size()).round_up_to();
+        let new_words: Words = cell_data_size(new_size).round_up_to();
+        let align = layout_align(&layout);
+        #[cfg(feature = "buddy_classes")]
+        {
+            let order = buddy::BuddyClasses::order(size, align);
+            if order.is_some() && order == buddy::BuddyClasses::order(new_words, align) {
+                check_guard_word(ptr, layout.size());
+                write_guard_word(ptr, new_size);
+                count!(realloc_bytes_grown_in_place, new_size.saturating_sub(layout.size()));
+                return Ok(ptr);
+            }
+        }
+        if self.same_free_list(size, new_words, align) {
+            let cell = &*(ptr.as_ptr() as *const CellHeader<'a>).offset(-1);
+            let capacity: Words = cell.size().round_down_to();
+            if capacity >= new_words {
+                check_guard_word(ptr, layout.size());
+                if new_size < layout.size() {
+                    self.shrink_in_place_impl(ptr, layout, new_size);
                 }
+                write_guard_word(ptr, new_size);
+                count!(realloc_bytes_grown_in_place, new_size.saturating_sub(layout.size()));
+                return Ok(ptr);
             }
+        }
 
-            // Either we don't want to merge cells for the current policy, or we
-            // didn't have the opportunity to do any merging with our adjacent
-            // neighbors. In either case, push this cell onto the front of the
-            // free list.
-            let _head = free.insert_into_free_list(head, policy);
-        });
+        // Nothing has been modified yet, so if there is no room for the new
+        // allocation, the old one is still intact and valid, just like
+        // `realloc` promises.
+        let new_layout = Layout::from_size_align(new_size, layout.align()).map_err(|_| AllocErr)?;
+        let new_ptr = self.alloc_impl(new_layout)?;
+        let copied = cmp::min(layout.size(), new_size);
+        ptr::copy_nonoverlapping(ptr.as_ptr() as *const u8, new_ptr.as_ptr(), copied);
+        count!(realloc_bytes_copied, copied);
+        #[cfg(feature = "alloc_tags")]
+        move_tag(ptr, new_ptr);
+        self.dealloc_impl(ptr, layout);
+        Ok(new_ptr)
     }
 }
 
@@ -1150,16 +4730,111 @@ where
         self.alloc_impl(layout)
     }
 
+    unsafe fn alloc_excess(&mut self, layout: Layout) -> Result<Excess, AllocErr> {
+        let (ptr, size) = self.alloc_excess_impl(layout)?;
+        Ok(Excess(ptr, size))
+    }
+
     unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
         self.dealloc_impl(ptr, layout)
     }
+
+    unsafe fn realloc(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<NonNull<u8>, AllocErr> {
+        self.realloc_impl(ptr, layout, new_size)
+    }
+
+    unsafe fn shrink_in_place(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+    ) -> Result<(), CannotReallocInPlace> {
+        if self.shrink_in_place_impl(ptr, layout, new_size) {
+            Ok(())
+        } else {
+            Err(CannotReallocInPlace)
+        }
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+unsafe impl<'a, 'b> Allocator for &'b WeeAlloc<'a>
+where
+    'a: 'b,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let (ptr, size) =
+            unsafe { self.alloc_excess_impl(layout) }.map_err(|_| core::alloc::AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, size))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.dealloc_impl(ptr, layout)
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        self.reallocate(ptr, old_layout, new_layout)
+    }
+}
+
+#[cfg(feature = "allocator_api")]
+impl<'a> WeeAlloc<'a> {
+    // Move the allocation at `ptr` to `new_layout`, in place if possible.
+    // `realloc_impl` keeps the old alignment, so only use it when that doesn't
+    // change.
+    unsafe fn reallocate(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        if new_layout.align() == old_layout.align() {
+            let ptr = self
+                .realloc_impl(ptr, old_layout, new_layout.size())
+                .map_err(|_| core::alloc::AllocError)?;
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+        }
+
+        let (new_ptr, size) = self
+            .alloc_excess_impl(new_layout.clone())
+            .map_err(|_| core::alloc::AllocError)?;
+        let copied = cmp::min(old_layout.size(), new_layout.size());
+        ptr::copy_nonoverlapping(ptr.as_ptr() as *const u8, new_ptr.as_ptr(), copied);
+        #[cfg(feature = "alloc_tags")]
+        {
+            if old_layout.size() != 0 && new_layout.size() != 0 {
+                move_tag(ptr, new_ptr);
+            }
+        }
+        self.dealloc_impl(ptr, old_layout);
+        Ok(NonNull::slice_from_raw_parts(new_ptr, size))
+    }
 }
 
 unsafe impl GlobalAlloc for WeeAlloc<'static> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         match self.alloc_impl(layout) {
             Ok(ptr) => ptr.as_ptr(),
-            Err(AllocErr) => ptr::null_mut(),
+            Err(AllocErr) => oom(),
         }
     }
 
@@ -1168,4 +4843,40 @@ unsafe impl GlobalAlloc for WeeAlloc<'static> {
             self.dealloc_impl(ptr, layout);
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return ptr::null_mut(),
+        };
+        match self.realloc_impl(ptr, layout, new_size) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(AllocErr) => oom(),
+        }
+    }
+}
+
+// The made up pointer handed out for a zero-sized allocation. It is properly
+// aligned because it is the alignment itself, and is never a cell's data.
+#[inline]
+fn zero_sized_alloc(align: Bytes) -> NonNull<u8> {
+    extra_assert!(align.0 > 0);
+    unsafe { NonNull::new_unchecked(align.0 as *mut u8) }
+}
+
+// What `GlobalAlloc` returns when an allocation fails: either null, or, with
+// the `abort_on_oom` feature, nothing at all.
+#[cfg(not(feature = "abort_on_oom"))]
+#[inline]
+fn oom() -> *mut u8 {
+    ptr::null_mut()
 }
+
+#[cfg(feature = "abort_on_oom")]
+#[inline]
+fn oom() -> *mut u8 {
+    imp::abort()
+}
+
+#[cfg(test)]
+mod tests;
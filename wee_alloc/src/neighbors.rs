@@ -2,8 +2,29 @@
 
 use core::cell::Cell;
 use core::marker::PhantomData;
+use core::mem;
 use core::ptr;
 
+#[cfg(target_pointer_width = "16")]
+compile_error! {
+    "wee_alloc requires pointers aligned to at least 4 bytes for its tag bits; your target has 16-bit pointers"
+}
+
+#[cfg(not(any(
+    target_pointer_width = "16",
+    target_pointer_width = "32",
+    target_pointer_width = "64"
+)))]
+compile_error! {
+    "wee_alloc requires pointers aligned to at least 4 bytes for its tag bits; your target has an unsupported pointer width"
+}
+
+// We rely on being able to stick tags into the lowest two bits of pointers. If
+// pointers aren't at least 4-byte aligned, this fails to type check because the
+// array lengths don't match.
+#[allow(dead_code)]
+const ASSERT_CAN_USE_LOW_BITS: [(); 1] = [(); (mem::align_of::<*const u8>() >= 0b100) as usize];
+
 /// TODO FITZGEN
 ///
 /// ### Safety
@@ -24,6 +45,10 @@ where
 {
     next_raw: Cell<*const T>,
     prev_raw: Cell<*const T>,
+    // A checksum of `next_raw` and `prev_raw`, kept up to date by every setter
+    // and checked by `verify_checksum`. Costs every cell a word.
+    #[cfg(feature = "header_checksum")]
+    checksum: Cell<usize>,
     _phantom: PhantomData<&'a T>,
 }
 
@@ -35,14 +60,13 @@ where
         Neighbors {
             next_raw: Cell::new(ptr::null_mut()),
             prev_raw: Cell::new(ptr::null_mut()),
+            #[cfg(feature = "header_checksum")]
+            checksum: Cell::new(Self::checksum_of(ptr::null(), ptr::null())),
             _phantom: PhantomData,
         }
     }
 }
 
-// Add this `cfg` so that the build will break on platforms with bizarre word
-// sizes, where we might not have acceess to these low bits.
-#[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
 impl<'a, T> Neighbors<'a, T>
 where
     T: 'a + HasNeighbors<'a, T>,
@@ -56,11 +80,69 @@ where
 
     // Mask to get the aligned pointer.
     const PTR_MASK: usize = !0b11;
+
+    // Every write to `next_raw` or `prev_raw` goes through one of these, so
+    // that the checksum stays up to date.
+
+    #[inline]
+    fn store_next_raw(&self, next_raw: *const T) {
+        self.next_raw.set(next_raw);
+        self.update_checksum();
+    }
+
+    #[inline]
+    fn store_prev_raw(&self, prev_raw: *const T) {
+        self.prev_raw.set(prev_raw);
+        self.update_checksum();
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "header_checksum")] {
+        impl<'a, T> Neighbors<'a, T>
+        where
+            T: 'a + HasNeighbors<'a, T>,
+        {
+            // Mixed into the checksum so that a zeroed header doesn't pass.
+            const CHECKSUM_SALT: usize = 0x5A5A_5A5A_5A5A_5A5A_u64 as usize;
+
+            #[inline]
+            fn checksum_of(next_raw: *const T, prev_raw: *const T) -> usize {
+                (next_raw as usize) ^ (prev_raw as usize).rotate_left(1) ^ Self::CHECKSUM_SALT
+            }
+
+            #[inline]
+            fn update_checksum(&self) {
+                self.checksum
+                    .set(Self::checksum_of(self.next_raw.get(), self.prev_raw.get()));
+            }
+
+            /// Panic if the neighbor pointers were changed behind our back,
+            /// e.g. by a buffer overrun or a use after free.
+            #[inline]
+            pub fn verify_checksum(&self) {
+                let expected = Self::checksum_of(self.next_raw.get(), self.prev_raw.get());
+                if self.checksum.get() != expected {
+                    panic!("corrupted cell header at {:p}", self);
+                }
+            }
+        }
+    } else {
+        impl<'a, T> Neighbors<'a, T>
+        where
+            T: 'a + HasNeighbors<'a, T>,
+        {
+            #[inline(always)]
+            fn update_checksum(&self) {}
+
+            #[inline(always)]
+            pub fn verify_checksum(&self) {}
+        }
+    }
 }
 
 #[test]
 fn can_use_low_bits() {
-    use core::mem;
     assert!(
         mem::align_of::<*const u8>() >= 0b100,
         "we rely on being able to stick tags into the lowest two bits"
@@ -104,28 +186,28 @@ where
     pub fn set_next_bit_1(&self) {
         let next_raw = self.next_raw.get() as usize;
         let next_raw = next_raw | Self::BIT_1;
-        self.next_raw.set(next_raw as *const T);
+        self.store_next_raw(next_raw as *const T);
     }
 
     #[inline]
     pub fn set_next_bit_2(&self) {
         let next_raw = self.next_raw.get() as usize;
         let next_raw = next_raw | Self::BIT_2;
-        self.next_raw.set(next_raw as *const T);
+        self.store_next_raw(next_raw as *const T);
     }
 
     #[inline]
     pub fn set_prev_bit_1(&self) {
         let prev_raw = self.prev_raw.get() as usize;
         let prev_raw = prev_raw | Self::BIT_1;
-        self.prev_raw.set(prev_raw as *const T);
+        self.store_prev_raw(prev_raw as *const T);
     }
 
     #[inline]
     pub fn set_prev_bit_2(&self) {
         let prev_raw = self.prev_raw.get() as usize;
         let prev_raw = prev_raw | Self::BIT_2;
-        self.prev_raw.set(prev_raw as *const T);
+        self.store_prev_raw(prev_raw as *const T);
     }
 }
 
@@ -139,28 +221,28 @@ where
     pub fn clear_next_bit_1(&self) {
         let next_raw = self.next_raw.get() as usize;
         let next_raw = next_raw & !Self::BIT_1;
-        self.next_raw.set(next_raw as *const T);
+        self.store_next_raw(next_raw as *const T);
     }
 
     #[inline]
     pub fn clear_next_bit_2(&self) {
         let next_raw = self.next_raw.get() as usize;
         let next_raw = next_raw & !Self::BIT_2;
-        self.next_raw.set(next_raw as *const T);
+        self.store_next_raw(next_raw as *const T);
     }
 
     #[inline]
     pub fn clear_prev_bit_1(&self) {
         let prev_raw = self.prev_raw.get() as usize;
         let prev_raw = prev_raw & !Self::BIT_1;
-        self.prev_raw.set(prev_raw as *const T);
+        self.store_prev_raw(prev_raw as *const T);
     }
 
     #[inline]
     pub fn clear_prev_bit_2(&self) {
         let prev_raw = self.prev_raw.get() as usize;
         let prev_raw = prev_raw & !Self::BIT_2;
-        self.prev_raw.set(prev_raw as *const T);
+        self.store_prev_raw(prev_raw as *const T);
     }
 }
 
@@ -208,7 +290,7 @@ where
         let old_next = self.next_raw.get() as usize;
         let old_bits = old_next & Self::BITS_MASK;
         let next = next | old_bits;
-        self.next_raw.set(next as *const T);
+        self.store_next_raw(next as *const T);
     }
 
     #[inline]
@@ -218,7 +300,7 @@ where
         let old_prev = self.prev_raw.get() as usize;
         let old_bits = old_prev & Self::BITS_MASK;
         let prev = prev | old_bits;
-        self.prev_raw.set(prev as *const T);
+        self.store_prev_raw(prev as *const T);
     }
 }
 
@@ -247,12 +329,12 @@ where
 {
     #[inline]
     pub unsafe fn set_next_and_bits(&self, next_and_bits: *const T) {
-        self.next_raw.set(next_and_bits);
+        self.store_next_raw(next_and_bits);
     }
 
     #[inline]
     pub unsafe fn set_prev_and_bits(&self, prev_and_bits: *const T) {
-        self.prev_raw.set(prev_and_bits);
+        self.store_prev_raw(prev_and_bits);
     }
 }
 
@@ -295,4 +377,71 @@ where
             me.as_ref().set_next(neighbor);
         }
     }
+
+    #[inline]
+    #[allow(dead_code)]
+    pub fn insert_before(me: &T, neighbor: &T) {
+        extra_assert!(neighbor.as_ref().next_unchecked().is_null());
+        extra_assert!(neighbor.as_ref().prev_unchecked().is_null());
+
+        unsafe {
+            neighbor.as_ref().set_prev(me.as_ref().prev_unchecked());
+            if let Some(prev) = me.as_ref().prev() {
+                prev.as_ref().set_next(neighbor);
+            }
+
+            neighbor.as_ref().set_next(me);
+            me.as_ref().set_prev(neighbor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Node<'a> {
+        neighbors: Neighbors<'a, Node<'a>>,
+    }
+
+    impl<'a> AsRef<Neighbors<'a, Node<'a>>> for Node<'a> {
+        fn as_ref(&self) -> &Neighbors<'a, Node<'a>> {
+            &self.neighbors
+        }
+    }
+
+    unsafe impl<'a> HasNeighbors<'a, Node<'a>> for Node<'a> {
+        unsafe fn next_checked(
+            _: &Neighbors<'a, Node<'a>>,
+            next: *const Node<'a>,
+        ) -> Option<&'a Node<'a>> {
+            next.as_ref()
+        }
+
+        unsafe fn prev_checked(
+            _: &Neighbors<'a, Node<'a>>,
+            prev: *const Node<'a>,
+        ) -> Option<&'a Node<'a>> {
+            prev.as_ref()
+        }
+    }
+
+    #[test]
+    fn insert_before_links_both_directions() {
+        let a = Node::default();
+        let b = Node::default();
+        let c = Node::default();
+
+        // Build a <-> b <-> c back to front.
+        Neighbors::insert_before(&c, &a);
+        Neighbors::insert_before(&c, &b);
+
+        assert!(a.neighbors.prev_unchecked().is_null());
+        assert_eq!(a.neighbors.next_unchecked(), &b as *const _);
+        assert_eq!(b.neighbors.prev_unchecked(), &a as *const _);
+        assert_eq!(b.neighbors.next_unchecked(), &c as *const _);
+        assert_eq!(c.neighbors.prev_unchecked(), &b as *const _);
+        assert!(c.neighbors.next_unchecked().is_null());
+    }
 }
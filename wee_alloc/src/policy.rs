@@ -0,0 +1,139 @@
+//! Hooks for trying out strategies for placing allocations in the main free
+//! list, without forking `wee_alloc`.
+//!
+//! A [`Placement`] is shown every cell of the main free list, and picks the one
+//! that an allocation is carved out of. `wee_alloc` still does everything else:
+//! splitting and merging cells, refilling the free list from the backend, and
+//! checking its invariants. Allocate with [`WeeAlloc::alloc_with_placement`],
+//! and free with [`WeeAlloc::dealloc_with_placement`].
+//!
+//! Segregated-fit strategies can be built on top of this by routing each range
+//! of sizes to a `WeeAlloc` of its own.
+//!
+//! [`Placement`]: trait.Placement.html
+//! [`WeeAlloc::alloc_with_placement`]: ../struct.WeeAlloc.html#method.alloc_with_placement
+//! [`WeeAlloc::dealloc_with_placement`]: ../struct.WeeAlloc.html#method.dealloc_with_placement
+
+use super::FreeCell;
+use core::alloc::Layout;
+use core::fmt;
+use core::marker::PhantomData;
+use memory_units::{Bytes, Words};
+
+/// A cell in the main free list, as shown to a `Placement`.
+///
+/// This is only a handle: the cell can't be read or written through it.
+#[derive(Clone, Copy)]
+pub struct FreeCellRef<'c> {
+    cell: *const FreeCell<'static>,
+    size: Words,
+    align: Bytes,
+    _cursor: PhantomData<&'c ()>,
+}
+
+impl<'c> FreeCellRef<'c> {
+    /// The address of the start of the cell's data.
+    pub fn addr(&self) -> usize {
+        unsafe { (*self.cell).header.unchecked_data() as usize }
+    }
+
+    /// The size of the cell's data, in bytes.
+    pub fn size(&self) -> usize {
+        unsafe { (*self.cell).header.size().0 }
+    }
+
+    /// Whether the allocation being placed can be carved out of this cell,
+    /// either by splitting it, or by taking the whole thing.
+    pub fn fits(&self) -> bool {
+        unsafe { (*self.cell).fits(self.size, self.align) }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const FreeCell<'static> {
+        self.cell
+    }
+}
+
+impl<'c> fmt::Debug for FreeCellRef<'c> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FreeCellRef")
+            .field("addr", &(self.addr() as *const u8))
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+/// An iterator over the cells of the main free list, in free list order.
+///
+/// Cells that were waiting to be merged with their neighbors are merged before
+/// the iteration starts, so no two cells that it yields are adjacent.
+pub struct FreeListCursor<'c> {
+    next: *const FreeCell<'static>,
+    size: Words,
+    align: Bytes,
+    _lock: PhantomData<&'c ()>,
+}
+
+impl<'c> FreeListCursor<'c> {
+    pub(crate) fn new(head: *const FreeCell<'static>, size: Words, align: Bytes) -> Self {
+        FreeListCursor {
+            next: head,
+            size,
+            align,
+            _lock: PhantomData,
+        }
+    }
+}
+
+impl<'c> Iterator for FreeListCursor<'c> {
+    type Item = FreeCellRef<'c>;
+
+    fn next(&mut self) -> Option<FreeCellRef<'c>> {
+        let cell = unsafe { self.next.as_ref()? };
+        self.next = cell.next_free();
+        Some(FreeCellRef {
+            cell,
+            size: self.size,
+            align: self.align,
+            _cursor: PhantomData,
+        })
+    }
+}
+
+/// A strategy for picking the free cell that an allocation is carved out of.
+pub trait Placement {
+    /// Choose the cell in `cells` to carve an allocation of `layout` out of, or
+    /// return `None` to refill the free list from the backend and allocate
+    /// from the new cell instead. Choosing a cell that doesn't `fit` refills
+    /// too.
+    ///
+    /// This is called with the main free list locked, so it must not allocate
+    /// with the same `WeeAlloc`.
+    fn choose<'c>(&self, layout: Layout, cells: FreeListCursor<'c>) -> Option<FreeCellRef<'c>>;
+}
+
+/// Take the first cell that fits, which is what `wee_alloc` itself does.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FirstFit;
+
+impl Placement for FirstFit {
+    fn choose<'c>(
+        &self,
+        _layout: Layout,
+        mut cells: FreeListCursor<'c>,
+    ) -> Option<FreeCellRef<'c>> {
+        cells.find(|cell| cell.fits())
+    }
+}
+
+/// Take the smallest cell that fits, leaving the big ones for big allocations.
+/// This walks the whole free list on every allocation.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BestFit;
+
+impl Placement for BestFit {
+    fn choose<'c>(&self, _layout: Layout, cells: FreeListCursor<'c>) -> Option<FreeCellRef<'c>> {
+        cells
+            .filter(|cell| cell.fits())
+            .min_by_key(|cell| cell.size())
+    }
+}
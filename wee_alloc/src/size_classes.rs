@@ -1,26 +1,373 @@
-use super::{alloc_with_refill, AllocErr, AllocPolicy, CellHeader, FreeCell, LargeAllocPolicy};
+use super::{
+    alloc_with_refill, assert_is_valid_free_list, dealloc_cell, get_free_list_link, set_free_list_link,
+    set_prev_free_link, unchecked_unwrap,
+    AllocErr, AllocPolicy, AllocatedCell, CellHeader, FreeCell, FreeListDebug, LargeAllocPolicy,
+};
 use const_init::ConstInit;
 use core::cell::Cell;
 use core::cmp;
+use core::fmt;
+use core::mem;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use imp;
 use memory_units::{size_of, Bytes, RoundUpTo, Words};
 
 /// An array of free lists specialized for allocations of sizes
-/// `1..Self::NUM_SIZE_CLASSES + 1` words.
+/// `1..Self::MAX_SIZE_CLASS_WORDS + 1` words. See `size_to_class` for which
+/// sizes share a free list.
+///
+/// Also keeps track of the size class that most recently had to refill its free
+/// list, or zero if there is none, so that freed large cells can be handed
+/// straight to it, and of each size class's `ClassCounts`.
 pub(crate) struct SizeClasses<'a>(
     pub(crate) [imp::Exclusive<*const FreeCell<'a>>; SizeClasses::NUM_SIZE_CLASSES],
+    pub(crate) AtomicUsize,
+    pub(crate) [ClassCounts; SizeClasses::NUM_SIZE_CLASSES],
 );
 
+/// How many of a size class's cells are allocated, how many bytes its segments
+/// take up in all, and how many cells were freed since it last looked for
+/// segments to give back. These only change while holding the size class's
+/// lock.
+pub(crate) struct ClassCounts {
+    live: AtomicUsize,
+    bytes: AtomicUsize,
+    freed: AtomicUsize,
+}
+
+impl ConstInit for ClassCounts {
+    const INIT: ClassCounts = ClassCounts {
+        live: AtomicUsize::new(0),
+        bytes: AtomicUsize::new(0),
+        freed: AtomicUsize::new(0),
+    };
+}
+
+impl ClassCounts {
+    fn add(counter: &AtomicUsize, n: usize) {
+        counter.store(counter.load(Ordering::Relaxed) + n, Ordering::Relaxed);
+    }
+
+    fn sub(counter: &AtomicUsize, n: usize) -> usize {
+        let left = counter.load(Ordering::Relaxed) - n;
+        counter.store(left, Ordering::Relaxed);
+        left
+    }
+
+    /// Forget every cell and segment, for when the size class's free list is
+    /// emptied wholesale.
+    pub(crate) fn reset(&self) {
+        self.live.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.freed.store(0, Ordering::Relaxed);
+    }
+
+    /// Take over the segments of another size class, whose cells are all free.
+    pub(crate) fn absorb(&self, other: &ClassCounts) {
+        extra_assert_eq!(other.live.load(Ordering::Relaxed), 0);
+        Self::add(&self.bytes, other.bytes.load(Ordering::Relaxed));
+        other.reset();
+    }
+}
+
 impl<'a> ConstInit for SizeClasses<'a> {
-    const INIT: SizeClasses<'a> = SizeClasses(include!("size_classes_init.rs"));
+    #[cfg(not(feature = "log_spaced_size_classes"))]
+    const INIT: SizeClasses<'a> = SizeClasses(
+        include!("size_classes_init.rs"),
+        AtomicUsize::new(0),
+        [<ClassCounts as ConstInit>::INIT; NUM_SIZE_CLASSES],
+    );
+
+    #[cfg(feature = "log_spaced_size_classes")]
+    const INIT: SizeClasses<'a> = SizeClasses(
+        include!("size_classes_log_spaced_init.rs"),
+        AtomicUsize::new(0),
+        [<ClassCounts as ConstInit>::INIT; NUM_SIZE_CLASSES],
+    );
 }
 
 impl<'a> SizeClasses<'a> {
-    pub(crate) const NUM_SIZE_CLASSES: usize = 256;
+    pub(crate) const NUM_SIZE_CLASSES: usize = NUM_SIZE_CLASSES;
+
+    /// The largest allocation, in words, that is served by a size class.
+    pub(crate) const MAX_SIZE_CLASS_WORDS: usize = 256;
 
     pub(crate) fn get(&self, size: Words) -> Option<&imp::Exclusive<*const FreeCell<'a>>> {
         extra_assert!(size.0 > 0);
-        self.0.get(size.0 - 1)
+        let class = size_to_class(size)?;
+        extra_assert!(class < Self::NUM_SIZE_CLASSES);
+        Some(unsafe { self.0.get_unchecked(class) })
+    }
+
+    /// The policy for allocating `size` words from its size class's free list.
+    /// `size` must have a size class.
+    pub(crate) fn policy<'b>(
+        &'b self,
+        size: Words,
+        main_head: &'b imp::Exclusive<*const FreeCell<'a>>,
+        large_policy: &'b LargeAllocPolicy,
+    ) -> SizeClassAllocPolicy<'a, 'b> {
+        let counts = unsafe { self.2.get_unchecked(unchecked_unwrap(size_to_class(size))) };
+        SizeClassAllocPolicy(main_head, large_policy, &self.1, counts)
+    }
+
+    /// The size of the cells in the size class at `index`.
+    pub(crate) fn class_size(index: usize) -> Words {
+        class_to_size(index)
+    }
+
+    /// Round `size` up to the size of the cells in its size class, if it has
+    /// one. All cells within a size class's free list are the same size.
+    #[inline]
+    pub(crate) fn round_up_to_class(size: Words) -> Words {
+        match size_to_class(size) {
+            Some(class) => class_to_size(class),
+            None => size,
+        }
+    }
+
+    /// If a size class recently had to refill its free list, and the given
+    /// cell, which is being deallocated from the main free list, is big enough,
+    /// then claim the cell for that size class, and return the size of its
+    /// cells. This saves the size class from carving a new segment out of the
+    /// main free list the next time it runs dry.
+    ///
+    /// The cell stays allocated as far as the main free list is concerned, just
+    /// like every other size class segment, and is marked as such. Its header
+    /// links change whenever its neighbors are merged, so this must be called
+    /// with the main free list locked. Hand the cell over with
+    /// `feed_hungry_size_class` once that lock is released.
+    pub(crate) unsafe fn claim_for_hungry_size_class(&self, cell: &CellHeader<'a>) -> Option<Words> {
+        let hungry = self.1.load(Ordering::Relaxed);
+        if hungry == 0 {
+            return None;
+        }
+
+        let min_segment_size: Bytes = segment_size(Words(hungry)).into();
+        if cell.size() < min_segment_size {
+            return None;
+        }
+
+        // Only feed a hungry size class once.
+        if self
+            .1
+            .compare_exchange(hungry, 0, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            return None;
+        }
+
+        cell.set_holds_size_class_segment();
+        Some(Words(hungry))
+    }
+
+    /// Turn the data of a cell that `claim_for_hungry_size_class` claimed for
+    /// the size class of `size` into a new segment for it.
+    ///
+    /// This takes the size class's lock, so it must not be called with the
+    /// main free list locked: size classes take their own lock before the main
+    /// free list's when refilling. Only the cell's data is written, and its
+    /// size can't change while it is allocated.
+    pub(crate) unsafe fn feed_hungry_size_class(
+        &self,
+        cell: &AllocatedCell<'a>,
+        size: Words,
+        main_head: &imp::Exclusive<*const FreeCell<'a>>,
+        large_policy: &LargeAllocPolicy,
+    ) {
+        let policy = self.policy(size, main_head, large_policy);
+        let head = unchecked_unwrap(self.get(size));
+        let cell_size = cell.header.size();
+        head.with_exclusive_access(|head| {
+            ClassCounts::add(&policy.3.bytes, cell_size.0);
+            let data = unchecked_unwrap(NonNull::new(cell.data() as *mut u8));
+            let segment = new_segment(data, cell_size, &policy);
+
+            // Append the new segment to the end of the free list, rather than
+            // pushing it onto the front. Otherwise, the next allocation would
+            // find the whole segment free while there are other cells in the
+            // free list, and reclaim it right back into the main free list.
+            let head_cell = Cell::new(*head);
+            let mut link = &head_cell;
+            let mut prev = ptr::null();
+            while let Some(current) = get_free_list_link(link).as_ref() {
+                link = &current.next_free_raw;
+                prev = current;
+            }
+            set_free_list_link(link, segment);
+            (*segment).set_prev_free(prev);
+            assert_is_valid_free_list(head_cell.get(), &policy);
+            *head = head_cell.get();
+        });
+    }
+
+    /// Carve one segment, allocated from the main free list, into `count`
+    /// cells of exactly the size of `size`'s size class, and push them all onto
+    /// that size class's free list. If there isn't room for `count` of them,
+    /// try again with half as many. Returns how many cells were added.
+    pub(crate) unsafe fn populate(
+        &self,
+        size: Words,
+        count: usize,
+        main_head: &imp::Exclusive<*const FreeCell<'a>>,
+        large_policy: &LargeAllocPolicy,
+    ) -> usize {
+        let head = match self.get(size) {
+            Some(head) => head,
+            None => return 0,
+        };
+        let size = Self::round_up_to_class(size);
+        let size_of_header: Words = size_of::<CellHeader>().round_up_to();
+        let stride = size + size_of_header;
+
+        let mut count = count;
+        let segment = loop {
+            if count == 0 {
+                return 0;
+            }
+            let segment_size = stride
+                .0
+                .checked_mul(count)
+                .filter(|w| w.checked_mul(size_of::<usize>().0).is_some());
+            if let Some(segment_size) = segment_size {
+                let segment = main_head.with_exclusive_access(|head| {
+                    let head_cell = Cell::new(*head);
+                    let result = alloc_with_refill(
+                        Words(segment_size),
+                        size_of::<usize>(),
+                        &head_cell,
+                        large_policy,
+                    );
+                    *head = head_cell.get();
+                    result
+                });
+                if let Ok(segment) = segment {
+                    break segment;
+                }
+            }
+            count /= 2;
+        };
+
+        (*(segment.as_ptr() as *const CellHeader).offset(-1)).set_holds_size_class_segment();
+
+        let policy = self.policy(size, main_head, large_policy);
+        let stride: Bytes = stride.into();
+        let size: Bytes = size.into();
+
+        // Lay the cells out back to back, linking each one to its neighbors in
+        // memory and in the free list at the same time.
+        let mut first: *const FreeCell<'a> = ptr::null();
+        let mut prev: *const FreeCell<'a> = ptr::null();
+        for i in 0..count {
+            let raw = segment.as_ptr().add(i * stride.0);
+            let cell = FreeCell::from_uninitialized(
+                unchecked_unwrap(NonNull::new(raw)),
+                size,
+                None,
+                &policy,
+            );
+            match prev.as_ref() {
+                Some(prev) => {
+                    (*cell).header.neighbors.set_prev(&prev.header);
+                    prev.set_next_free(cell);
+                }
+                None => {
+                    (*cell).set_segment_start();
+                    first = cell;
+                }
+            }
+            (*cell).set_prev_free(prev);
+            let next_cell = raw.add(stride.0);
+            (*cell)
+                .header
+                .neighbors
+                .set_next(next_cell as *const CellHeader);
+            prev = cell;
+        }
+        CellHeader::set_next_cell_is_invalid(&(*prev).header.neighbors);
+
+        head.with_exclusive_access(|head| {
+            ClassCounts::add(&policy.3.bytes, stride.0 * count);
+            (*prev).set_next_free(*head);
+            set_prev_free_link(*head, prev);
+            *head = first;
+            assert_is_valid_free_list(*head, &policy);
+        });
+
+        count
+    }
+}
+
+impl<'a> fmt::Debug for SizeClasses<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only show the size classes that have free cells, otherwise the output
+        // is mostly 256 empty lists.
+        let mut map = f.debug_map();
+        for (i, head) in self.0.iter().enumerate() {
+            let is_empty = unsafe { head.try_with_exclusive_access(|head| head.is_null()) };
+            if is_empty != Some(true) {
+                map.entry(&class_to_size(i).0, &FreeListDebug(head));
+            }
+        }
+        map.finish()
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "log_spaced_size_classes")] {
+        const NUM_SIZE_CLASSES: usize = 16;
+
+        // Size classes are spaced logarithmically, with two classes per power of
+        // two: 1, 2, 3, 4, 6, 8, 12, 16, ..., 128, 192, 256 words. This wastes
+        // at most a third of each allocation, in exchange for many fewer free
+        // lists.
+        #[inline]
+        fn size_to_class(size: Words) -> Option<usize> {
+            if size.0 > SizeClasses::MAX_SIZE_CLASS_WORDS {
+                return None;
+            }
+            if size.0 <= 2 {
+                return Some(size.0 - 1);
+            }
+
+            // The number of bits needed for `size - 1`, so that
+            // `2^(p - 1) < size <= 2^p`.
+            let p = mem::size_of::<usize>() * 8 - (size.0 - 1).leading_zeros() as usize;
+            if size.0 <= 3 << (p - 2) {
+                Some(2 * p - 2)
+            } else {
+                Some(2 * p - 1)
+            }
+        }
+
+        #[inline]
+        fn class_to_size(class: usize) -> Words {
+            if class == 0 {
+                Words(1)
+            } else if class % 2 == 1 {
+                Words(1 << ((class + 1) / 2))
+            } else {
+                Words(3 << (class / 2 - 1))
+            }
+        }
+    } else {
+        const NUM_SIZE_CLASSES: usize = 256;
+
+        // Every size gets its own size class.
+        #[inline]
+        fn size_to_class(size: Words) -> Option<usize> {
+            if size.0 <= SizeClasses::MAX_SIZE_CLASS_WORDS {
+                Some(size.0 - 1)
+            } else {
+                None
+            }
+        }
+
+        #[inline]
+        fn class_to_size(class: usize) -> Words {
+            Words(class + 1)
+        }
     }
 }
 
@@ -28,10 +375,152 @@ impl<'a> SizeClasses<'a> {
 // `LargeAllocPolicy`.
 const MIN_NEW_CELL_SIZE: Bytes = Bytes(8192);
 
-pub(crate) struct SizeClassAllocPolicy<'a, 'b>(pub(crate) &'b imp::Exclusive<*const FreeCell<'a>>)
+// The size of a new segment for allocations of the given size.
+fn segment_size(size: Words) -> Words {
+    // Need room for at least size^2 allocations.
+    let size_of_header: Words = size_of::<CellHeader>().round_up_to();
+    let size_with_header = size + size_of_header;
+    cmp::max(
+        size_with_header * size_with_header,
+        MIN_NEW_CELL_SIZE.round_up_to(),
+    )
+}
+
+// Turn the `size` bytes at `ptr` into a segment consisting of a single free
+// cell.
+unsafe fn new_segment<'a>(
+    ptr: NonNull<u8>,
+    size: Bytes,
+    policy: &dyn AllocPolicy<'a>,
+) -> *const FreeCell<'a> {
+    let free_cell = FreeCell::from_uninitialized(ptr, size - size_of::<CellHeader>(), None, policy);
+    let next_cell = (ptr.as_ptr() as *const u8).add(size.0);
+    (*free_cell)
+        .header
+        .neighbors
+        .set_next(next_cell as *const CellHeader);
+    CellHeader::set_next_cell_is_invalid(&(*free_cell).header.neighbors);
+    (*free_cell).set_segment_start();
+    free_cell
+}
+
+pub(crate) struct SizeClassAllocPolicy<'a, 'b>(
+    pub(crate) &'b imp::Exclusive<*const FreeCell<'a>>,
+    pub(crate) &'b LargeAllocPolicy,
+    // The size classes' record of which size class is hungry.
+    pub(crate) &'b AtomicUsize,
+    // This size class's counts of live cells and of segment bytes.
+    pub(crate) &'b ClassCounts,
+)
 where
     'a: 'b;
 
+impl<'a, 'b> SizeClassAllocPolicy<'a, 'b>
+where
+    'a: 'b,
+{
+    // How many of the size class's bytes are free, if each of its cells takes
+    // up `stride` bytes. Cells at the end of a segment can be bigger than the
+    // rest, so this can be a little more than the truth.
+    fn free_bytes(&self, stride: Bytes) -> Bytes {
+        let live = self.3.live.load(Ordering::Relaxed) * stride.0;
+        Bytes(self.3.bytes.load(Ordering::Relaxed).saturating_sub(live))
+    }
+
+    // If every cell of the segment that `cell` is in is free, return that
+    // segment's first cell, and the address just past its end. This stops at
+    // the first allocated cell it comes across.
+    unsafe fn free_segment_bounds<'c>(
+        cell: &'c FreeCell<'a>,
+    ) -> Option<(&'c FreeCell<'a>, usize)> {
+        let mut first = cell;
+        while !first.is_segment_start() {
+            first = first.header.neighbors.prev()?.as_free_cell()?;
+        }
+        let mut last = &cell.header;
+        while let Some(next) = last.neighbors.next() {
+            if next.is_allocated() {
+                return None;
+            }
+            last = next;
+        }
+        Some((first, last.neighbors.next_unchecked() as usize))
+    }
+
+    // Give back every segment whose cells are all free, as long as at least
+    // as many of the size class's bytes stay free as are given back, finding
+    // them by the first cell of each segment, which has its segment start bit
+    // set. Only gives back a few segments at a time, so that they can all be
+    // removed from the free list in a single pass.
+    unsafe fn reclaim_free_segments(&self, head: &Cell<*const FreeCell<'a>>, stride: Bytes) {
+        let mut segments = [(0, 0); 8];
+        let mut found = 0;
+        let mut free = self.free_bytes(stride).0;
+        let mut link = head;
+        while let Some(cell) = get_free_list_link(link).as_ref() {
+            link = &cell.next_free_raw;
+            if !cell.is_segment_start() {
+                continue;
+            }
+            if let Some((first, end)) = Self::free_segment_bounds(cell) {
+                let start = first as *const FreeCell as usize;
+                if free >= 2 * (end - start) {
+                    free -= end - start;
+                    segments[found] = (start, end);
+                    found += 1;
+                    if found == segments.len() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.reclaim_segments(head, &segments[..found]);
+    }
+
+    // Remove all of the cells of the given segments, each of which is free,
+    // from the free list in a single pass, and give the segments back to the
+    // main free list.
+    unsafe fn reclaim_segments(
+        &self,
+        head: &Cell<*const FreeCell<'a>>,
+        segments: &[(usize, usize)],
+    ) {
+        if segments.is_empty() {
+            return;
+        }
+
+        let in_segments = |cell: &FreeCell<'a>| {
+            let cell = cell as *const FreeCell as usize;
+            segments.iter().any(|&(start, end)| start <= cell && cell < end)
+        };
+        let mut link = head;
+        while let Some(current) = get_free_list_link(link).as_ref() {
+            if in_segments(current) {
+                set_free_list_link(link, current.next_free());
+                set_prev_free_link(current.next_free(), current.prev_free());
+            } else {
+                link = &current.next_free_raw;
+            }
+        }
+
+        // Each segment is the data of a cell allocated from the main free list,
+        // so free that cell.
+        self.0.with_exclusive_access(|main_head| {
+            let head_cell = Cell::new(*main_head);
+            for &(start, end) in segments {
+                let segment_cell = &*(start as *const CellHeader<'a>).offset(-1);
+                extra_assert!(segment_cell.is_allocated());
+                extra_assert!(segment_cell.holds_size_class_segment());
+                let segment_cell: &AllocatedCell<'a> = mem::transmute(segment_cell);
+                dealloc_cell(segment_cell, &head_cell, self.1);
+                ClassCounts::sub(&self.3.bytes, end - start);
+            }
+            *main_head = head_cell.get();
+        });
+        assert_is_valid_free_list(get_free_list_link(head), self);
+    }
+}
+
 impl<'a, 'b> AllocPolicy<'a> for SizeClassAllocPolicy<'a, 'b>
 where
     'a: 'b,
@@ -45,13 +534,9 @@ where
         extra_assert!(align.0.is_power_of_two());
         extra_assert!(align <= size_of::<usize>());
 
-        // Need room for at least size^2 allocations.
-        let size_of_header: Words = size_of::<CellHeader>().round_up_to();
-        let size_with_header = size + size_of_header;
-        let new_cell_size = cmp::max(
-            size_with_header * size_with_header,
-            MIN_NEW_CELL_SIZE.round_up_to(),
-        );
+        self.2.store(size.0, Ordering::Relaxed);
+
+        let new_cell_size = segment_size(size);
 
         let new_cell = self.0.with_exclusive_access(|head| {
             let head_cell = Cell::new(*head);
@@ -59,27 +544,19 @@ where
                 new_cell_size,
                 size_of::<usize>(),
                 &head_cell,
-                &LargeAllocPolicy,
+                self.1,
             );
             *head = head_cell.get();
             result
         })?;
 
-        let new_cell_size: Bytes = new_cell_size.into();
+        // Mark the cell, so that `WeeAlloc::retained_by_tag` looks inside it
+        // and `WeeAlloc::reset_size_classes` knows to free it.
+        (*(new_cell.as_ptr() as *const CellHeader).offset(-1)).set_holds_size_class_segment();
 
-        let free_cell = FreeCell::from_uninitialized(
-            new_cell,
-            new_cell_size - size_of::<CellHeader>(),
-            None,
-            self as &dyn AllocPolicy,
-        );
-        let next_cell = (new_cell.as_ptr() as *const u8).offset(new_cell_size.0 as isize);
-        (*free_cell)
-            .header
-            .neighbors
-            .set_next(next_cell as *const CellHeader);
-        CellHeader::set_next_cell_is_invalid(&(*free_cell).header.neighbors);
-        Ok(free_cell)
+        let new_cell_size: Bytes = new_cell_size.into();
+        ClassCounts::add(&self.3.bytes, new_cell_size.0);
+        Ok(new_segment(new_cell, new_cell_size, self))
     }
 
     fn min_cell_size(&self, alloc_size: Words) -> Words {
@@ -94,8 +571,119 @@ where
         false
     }
 
+    // The exception to the above is when every cell in a segment is free. Then
+    // we can give the whole segment back to the main free list, where it can
+    // be merged and used to satisfy allocations of any size.
+    unsafe fn try_reclaim_segment(
+        &self,
+        head: &Cell<*const FreeCell<'a>>,
+        cell: &FreeCell<'a>,
+    ) -> bool {
+        let (first, end) = match Self::free_segment_bounds(cell) {
+            Some(bounds) => bounds,
+            None => return false,
+        };
+        let start = first as *const FreeCell as usize;
+
+        // Keep the segment unless at least as many of the size class's bytes
+        // would still be free without it. Otherwise, a size class that is
+        // emptied and refilled over and over would give its segment back and
+        // carve out a new one every time.
+        let stride = cell.header.size() + size_of::<CellHeader>();
+        if self.free_bytes(stride).0 < 2 * (end - start) {
+            return false;
+        }
+
+        self.reclaim_segments(head, &[(start, end)]);
+        true
+    }
+
+    fn cell_allocated(&self) {
+        ClassCounts::add(&self.3.live, 1);
+    }
+
+    unsafe fn cell_freed(&self, head: &Cell<*const FreeCell<'a>>) {
+        ClassCounts::sub(&self.3.live, 1);
+        ClassCounts::add(&self.3.freed, 1);
+
+        // Finding the segments whose cells are all free means walking the
+        // whole free list, so only look once enough of the size class is free
+        // that one might be given back, and either every cell is free, or at
+        // least half as many cells as there are in the free list have been
+        // freed since the last look. Size classes never merge cells, so the
+        // freed cell was pushed onto the front of the free list.
+        let cell = &*get_free_list_link(head);
+        let stride = cell.header.size() + size_of::<CellHeader>();
+        let usual: Bytes = segment_size(cell.header.size().round_up_to()).into();
+        let free = self.free_bytes(stride);
+        if free.0 < 2 * usual.0 {
+            return;
+        }
+        if self.3.live.load(Ordering::Relaxed) > 0
+            && 2 * self.3.freed.load(Ordering::Relaxed) * stride.0 < free.0
+        {
+            return;
+        }
+        self.3.freed.store(0, Ordering::Relaxed);
+        self.reclaim_free_segments(head, stride);
+    }
+
+    unsafe fn try_pop_head(
+        &self,
+        head: &Cell<*const FreeCell<'a>>,
+        size: Words,
+    ) -> Option<NonNull<u8>> {
+        // Once a segment has been split up, every cell in it is exactly the
+        // size of this size class, so there is nothing to split and nothing to
+        // align: any such cell will do, and the first one is the cheapest to
+        // get at. A fresh segment, or the oversized cell at the end of one,
+        // still needs the general first-fit path to carve it up.
+        let cell = get_free_list_link(head).as_ref()?;
+        let size: Bytes = size.into();
+        if cell.header.size() != size {
+            return None;
+        }
+        extra_assert!(!cell.next_free_can_merge());
+
+        set_free_list_link(head, cell.next_free());
+        set_prev_free_link(cell.next_free(), ptr::null());
+        let allocated = cell.into_allocated_cell(self);
+        assert_is_valid_free_list(get_free_list_link(head), self);
+        Some(unchecked_unwrap(NonNull::new(allocated.data() as *mut u8)))
+    }
+
     #[cfg(feature = "extra_assertions")]
     fn free_pattern(&self) -> u8 {
-        CellHeader::SIZE_CLASS_FREE_PATTERN
+        self.1
+            .poison_byte()
+            .unwrap_or(CellHeader::SIZE_CLASS_FREE_PATTERN)
+    }
+
+    #[cfg(feature = "extra_assertions")]
+    fn owns(&self, ptr: *const u8) -> bool {
+        self.1.owns(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_to_class_round_trips() {
+        let mut prev_class = 0;
+        for size in 1..SizeClasses::MAX_SIZE_CLASS_WORDS + 1 {
+            let class = size_to_class(Words(size)).unwrap();
+            assert!(class < NUM_SIZE_CLASSES);
+            assert!(class == prev_class || class == prev_class + 1);
+            assert!(class_to_size(class).0 >= size);
+            assert_eq!(size_to_class(class_to_size(class)), Some(class));
+            if class > 0 {
+                assert!(class_to_size(class - 1).0 < size);
+            }
+            prev_class = class;
+        }
+        assert_eq!(prev_class, NUM_SIZE_CLASSES - 1);
+        assert_eq!(size_to_class(Words(SizeClasses::MAX_SIZE_CLASS_WORDS + 1)), None);
     }
 }
@@ -0,0 +1,18 @@
+[
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+    imp::Exclusive::INIT,
+]
@@ -0,0 +1,1592 @@
+use super::*;
+
+// Run `f` with a fresh allocator whose memory is a 64KiB buffer on the stack,
+// so that it never touches the backend, and nothing it does is seen by other
+// tests running at the same time.
+fn with_heap<F>(f: F)
+where
+    F: FnOnce(&mut WeeAlloc),
+{
+    let mut buf = [0u8; 64 * 1024];
+    f(&mut WeeAlloc::from_slice(&mut buf));
+}
+
+#[test]
+#[cfg(not(any(feature = "size_classes", feature = "buddy_classes")))]
+fn shrink_in_place_frees_the_tail() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let small = Layout::from_size_align(64, mem::size_of::<usize>()).unwrap();
+
+        unsafe {
+            let p = a.alloc_impl(layout).ok().unwrap();
+            let q = a.realloc_impl(p, layout, 64).ok().unwrap();
+            assert_eq!(p, q, "shrinking should not move the allocation");
+
+            // The tail of the old cell is now the first free cell, so allocating
+            // from it should land within the original allocation.
+            let layout = Layout::from_size_align(2048, mem::size_of::<usize>()).unwrap();
+            let r = a.alloc_impl(layout).ok().unwrap();
+            let start = p.as_ptr() as usize + 64;
+            let end = p.as_ptr() as usize + 4096;
+            assert!(start <= r.as_ptr() as usize && (r.as_ptr() as usize) < end);
+
+            a.dealloc_impl(r, layout);
+            a.dealloc_impl(q, small);
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn shrink_into_a_size_class_frees_the_whole_cell() {
+    with_heap(|a| {
+        // The main free list's minimum cell size is 4KiB with size classes, so
+        // there is no tail to split off, but 64 bytes is served by a size class.
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+        let small = Layout::from_size_align(64, mem::size_of::<usize>()).unwrap();
+
+        unsafe {
+            let p = a.alloc_impl(layout).ok().unwrap();
+            let q = a.realloc_impl(p, layout, 64).ok().unwrap();
+            assert_ne!(p, q, "shrinking into a size class should move the allocation");
+
+            // The old cell went back to the main free list whole, so it can
+            // serve another allocation of the original size.
+            let r = a.alloc_impl(layout).ok().unwrap();
+            assert_eq!(p, r);
+
+            a.dealloc_impl(r, layout);
+            a.dealloc_impl(q, small);
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn shrink_in_place_keeps_a_tail_smaller_than_the_min_cell_size() {
+    with_heap(|a| {
+        // Use a larger than word alignment, so that both the original and the
+        // shrunk allocation come from the main free list.
+        let align = mem::size_of::<usize>() * 2;
+        let layout = Layout::from_size_align(4096, align).unwrap();
+
+        unsafe {
+            let p = a.alloc_impl(layout).ok().unwrap();
+            let q = a.realloc_impl(p, layout, 64).ok().unwrap();
+            assert_eq!(p, q, "shrinking should not move the allocation");
+
+            // The would-be tail is smaller than the main free list's minimum
+            // cell size, so the cell keeps it.
+            let cell = &*(q.as_ptr() as *const CellHeader).offset(-1);
+            assert!(cell.size() >= Bytes(4096));
+
+            a.dealloc_impl(q, Layout::from_size_align(64, align).unwrap());
+        }
+    });
+}
+
+#[test]
+#[cfg(not(any(feature = "no_merge", feature = "buddy_classes")))]
+fn coalesce_with_both_neighbors_free() {
+    let a = WeeAlloc::INIT;
+    let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+
+    unsafe {
+        // A refill may only be big enough for the allocation that needed it, so
+        // make one big enough for all of them up front. They are then carved
+        // out of the same cell, right next to each other.
+        let all = Layout::from_size_align(16 * 4096, mem::size_of::<usize>()).unwrap();
+        let p = a.alloc_impl(all).ok().unwrap();
+        a.dealloc_impl(p, all);
+
+        let mut ptrs = [NonNull::dangling(); 8];
+        for p in ptrs.iter_mut() {
+            *p = a.alloc_impl(layout).ok().unwrap();
+        }
+
+        // Free every other allocation, and then the rest, so that each of the
+        // second batch of frees has free neighbors on both sides.
+        for p in ptrs.iter().step_by(2) {
+            a.dealloc_impl(*p, layout);
+        }
+        for p in ptrs.iter().skip(1).step_by(2) {
+            a.dealloc_impl(*p, layout);
+        }
+
+        // Count the free cells, not including those that are pending a delayed
+        // merge with their previous adjacent cell.
+        let num_free_cells = a.head.with_exclusive_access(|head| {
+            let mut n = 0;
+            let mut current = *head;
+            while !current.is_null() {
+                if !(*current).next_free_can_merge() {
+                    n += 1;
+                }
+                current = (*current).next_free();
+            }
+            n
+        });
+        assert_eq!(num_free_cells, 1);
+    }
+}
+
+#[test]
+fn verify_integrity_reports_busy_instead_of_blocking() {
+    let a = WeeAlloc::new();
+    unsafe {
+        a.head.with_exclusive_access(|_| {
+            assert_eq!(a.verify_integrity(), Err(IntegrityError::Busy));
+        });
+    }
+    assert_eq!(a.verify_integrity(), Ok(()));
+}
+
+#[test]
+fn debug_does_not_hold_the_lock_while_formatting() {
+    // Allocates from the allocator being formatted on every write, like
+    // `format!` does when that allocator is the global allocator.
+    struct AllocatingWriter<'b, 'a: 'b>(&'b WeeAlloc<'a>);
+
+    impl<'b, 'a: 'b> fmt::Write for AllocatingWriter<'b, 'a> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let layout = Layout::from_size_align(cmp::max(s.len(), 1), 1).unwrap();
+            unsafe {
+                let p = self.0.try_alloc(layout).map_err(|_| fmt::Error)?;
+                self.0.try_dealloc(p, layout);
+            }
+            Ok(())
+        }
+    }
+
+    let a = WeeAlloc::new();
+    unsafe {
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let p = a.try_alloc(layout).unwrap();
+        a.try_dealloc(p, layout);
+    }
+    fmt::write(&mut AllocatingWriter(&a), format_args!("{:?}", a)).unwrap();
+}
+
+#[test]
+fn realloc_within_the_same_word_does_not_move() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+
+        unsafe {
+            let mut layout = Layout::from_size_align(1, 1).unwrap();
+            let p = a.alloc_impl(layout).ok().unwrap();
+            for size in 2..word + 1 {
+                let q = a.realloc_impl(p, layout, size).ok().unwrap();
+                assert_eq!(p, q, "growing to {} bytes should not move the allocation", size);
+                layout = Layout::from_size_align(size, 1).unwrap();
+            }
+            a.dealloc_impl(p, layout);
+        }
+    });
+}
+
+#[test]
+fn byte_aligned_dealloc_uses_the_same_free_list_as_alloc() {
+    with_heap(|a| {
+        unsafe {
+            for &size in &[1, 3, mem::size_of::<usize>() + 1] {
+                let layout = Layout::from_size_align(size, 1).unwrap();
+                assert_eq!(layout_align(&layout), Bytes(1));
+
+                let p = a.alloc_impl(layout).ok().unwrap();
+                a.dealloc_impl(p, layout);
+                assert_eq!(a.verify_integrity(), Ok(()));
+
+                // If the cell went back to the free list it came from, it is the
+                // first one found when allocating the same layout again.
+                let q = a.alloc_impl(layout).ok().unwrap();
+                assert_eq!(p, q);
+                a.dealloc_impl(q, layout);
+            }
+        }
+    });
+}
+
+#[cfg(feature = "guard_words")]
+#[test]
+fn guard_word_survives_realloc() {
+    with_heap(|a| {
+        unsafe {
+            let layout = Layout::from_size_align(13, 1).unwrap();
+            let p = a.alloc_impl(layout).ok().unwrap();
+            ptr::write_bytes(p.as_ptr(), 0xff, 13);
+
+            let q = a.realloc_impl(p, layout, 5).ok().unwrap();
+            ptr::write_bytes(q.as_ptr(), 0xff, 5);
+            let q = a.realloc_impl(q, Layout::from_size_align(5, 1).unwrap(), 100).ok().unwrap();
+            ptr::write_bytes(q.as_ptr(), 0xff, 100);
+            a.dealloc_impl(q, Layout::from_size_align(100, 1).unwrap());
+        }
+    });
+}
+
+#[cfg(feature = "guard_words")]
+#[test]
+#[should_panic(expected = "heap buffer overrun")]
+fn guard_word_catches_overrun() {
+    with_heap(|a| {
+        unsafe {
+            let layout = Layout::from_size_align(13, 1).unwrap();
+            let p = a.alloc_impl(layout).ok().unwrap();
+            // One byte too many.
+            ptr::write_bytes(p.as_ptr(), 0, 14);
+            a.dealloc_impl(p, layout);
+        }
+    });
+}
+
+#[test]
+fn owns_only_the_allocators_own_segments() {
+    let mut buf = [0u8; 64 * 1024];
+    let buf_range = buf.as_ptr() as usize..buf.as_ptr() as usize + buf.len();
+    let a = WeeAlloc::from_slice(&mut buf);
+    let b = WeeAlloc::new();
+
+    unsafe {
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let p = a.alloc_impl(layout).ok().unwrap();
+        let q = b.alloc_impl(layout).ok().unwrap();
+
+        assert!(a.owns(p.as_ptr()));
+        assert!(!a.owns(q.as_ptr()));
+        assert!(b.owns(q.as_ptr()));
+        assert!(!b.owns(p.as_ptr()));
+
+        assert!(a.owns((buf_range.end - 1) as *const u8));
+        assert!(!a.owns(buf_range.end as *const u8));
+        assert!(!a.owns(ptr::null()));
+        assert!(!a.owns(&layout as *const _ as *const u8));
+
+        a.dealloc_impl(p, layout);
+        b.dealloc_impl(q, layout);
+    }
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+#[should_panic(expected = "not a wee_alloc pointer")]
+fn dealloc_catches_other_allocators_pointers() {
+    // Both buffers are on the stack next to each other, so a pointer from one
+    // allocator lies between the lowest and highest addresses of the other's.
+    let mut a_buf = [0u8; 64 * 1024];
+    let mut b_buf = [0u8; 64 * 1024];
+    let a = WeeAlloc::from_slice(&mut a_buf);
+    let b = WeeAlloc::from_slice(&mut b_buf);
+    unsafe {
+        let layout = Layout::from_size_align(8192, 8).unwrap();
+        let _p = a.alloc_impl(layout).ok().unwrap();
+        let q = b.alloc_impl(layout).ok().unwrap();
+        a.dealloc_impl(q, layout);
+    }
+}
+
+#[test]
+#[cfg(all(feature = "no_merge", not(feature = "buddy_classes")))]
+fn no_merge_leaves_free_neighbors_apart() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+
+        unsafe {
+            let mut ptrs = [NonNull::dangling(); 8];
+            for p in ptrs.iter_mut() {
+                *p = a.alloc_impl(layout).ok().unwrap();
+            }
+            for p in ptrs.iter() {
+                a.dealloc_impl(*p, layout);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+
+            // Every freed cell is still its own free cell, and can be reused as is.
+            let num_free_cells = a.head.with_exclusive_access(|head| {
+                let mut n = 0;
+                let mut current = *head;
+                while !current.is_null() {
+                    assert!(!(*current).next_free_can_merge());
+                    n += 1;
+                    current = (*current).next_free();
+                }
+                n
+            });
+            assert!(num_free_cells >= ptrs.len());
+
+            for p in ptrs.iter_mut() {
+                *p = a.alloc_impl(layout).ok().unwrap();
+            }
+            for p in ptrs.iter() {
+                a.dealloc_impl(*p, layout);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "extra_assertions")]
+fn set_poison_byte_repoisons_free_cells() {
+    with_heap(|a| {
+        unsafe {
+            // Leave free cells behind in the main free list, and in a size class.
+            let large = Layout::from_size_align(8 * 1024, mem::size_of::<usize>()).unwrap();
+            let small = Layout::from_size_align(16, mem::size_of::<usize>()).unwrap();
+            let p = a.alloc_impl(large).ok().unwrap();
+            let q = a.alloc_impl(small).ok().unwrap();
+            a.dealloc_impl(q, small);
+
+            a.set_poison_byte(0xAB);
+            assert_eq!(a.verify_integrity(), Ok(()));
+
+            a.dealloc_impl(p, large);
+            assert_eq!(a.verify_integrity(), Ok(()));
+            a.head.with_exclusive_access(|head| {
+                let cell = &**head;
+                let data = core::slice::from_raw_parts(cell.tail_data(), cell.tail_data_size().0);
+                assert!(data.iter().all(|b| *b == 0xAB));
+            });
+
+            // Cells poisoned with the new byte pass the poison check when reused.
+            let p = a.alloc_impl(large).ok().unwrap();
+            let q = a.alloc_impl(small).ok().unwrap();
+            a.dealloc_impl(q, small);
+            a.dealloc_impl(p, large);
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(not(feature = "buddy_classes"))]
+fn try_alloc_at_least_reports_the_whole_cell() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+
+        unsafe {
+            let layout = Layout::from_size_align(3, 1).unwrap();
+            let (p, excess) = a.try_alloc_at_least(layout).unwrap();
+            if cfg!(feature = "guard_words") {
+                assert_eq!(excess, 3);
+            } else {
+                assert!(excess >= word);
+                assert_eq!(excess % word, 0);
+                let cell = &*(p.as_ptr() as *const CellHeader).offset(-1);
+                assert!(excess <= cell.size().0);
+            }
+
+            // All of the excess is usable, and freeing with it is fine.
+            ptr::write_bytes(p.as_ptr(), 0xff, excess);
+            a.dealloc_impl(p, Layout::from_size_align(excess, 1).unwrap());
+            assert_eq!(a.verify_integrity(), Ok(()));
+
+            // A large allocation usually gets a whole cell that is too small to
+            // split, which is bigger than what was asked for.
+            let layout = Layout::from_size_align(4096 + 1, word).unwrap();
+            let (p, excess) = a.try_alloc_at_least(layout).unwrap();
+            assert!(excess > 4096);
+            ptr::write_bytes(p.as_ptr(), 0xff, excess);
+            a.dealloc_impl(p, Layout::from_size_align(excess, word).unwrap());
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(all(
+    feature = "doubly_linked_free_list",
+    not(any(feature = "no_merge", feature = "buddy_classes"))
+))]
+fn doubly_linked_free_list_merges_freed_cells_immediately() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(4096, mem::size_of::<usize>()).unwrap();
+
+        let num_free_cells = || unsafe {
+            a.head.with_exclusive_access(|head| {
+                let mut n = 0;
+                let mut current = *head;
+                while !current.is_null() {
+                    assert!(!(*current).next_free_can_merge());
+                    n += 1;
+                    current = (*current).next_free();
+                }
+                n
+            })
+        };
+
+        unsafe {
+            let mut ptrs = [NonNull::dangling(); 8];
+            for p in ptrs.iter_mut() {
+                *p = a.alloc_impl(layout).ok().unwrap();
+            }
+            let before = num_free_cells();
+
+            // Free every other cell, so that none of them can merge yet, and then
+            // the rest, each of which merges with both of its neighbors.
+            for p in ptrs.iter().step_by(2).chain(ptrs.iter().skip(1).step_by(2)) {
+                a.dealloc_impl(*p, layout);
+                assert_eq!(a.verify_integrity(), Ok(()));
+            }
+
+            // Everything merged back together, without any allocation having to
+            // walk the free list first.
+            assert!(num_free_cells() <= before);
+
+            // Allocations too small for two words still get room for both links.
+            let small = Layout::from_size_align(1, 1).unwrap();
+            let p = a.alloc_impl(small).ok().unwrap();
+            let q = a.alloc_impl(small).ok().unwrap();
+            a.dealloc_impl(p, small);
+            a.dealloc_impl(q, small);
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+fn realloc_across_size_zero() {
+    with_heap(|a| {
+        unsafe {
+            for &align in &[1, 8, 64] {
+                // Growing a zero-sized allocation allocates a real cell.
+                let zero = Layout::from_size_align(0, align).unwrap();
+                let p = a.alloc_impl(zero).ok().unwrap();
+                assert_eq!(p.as_ptr() as usize, align);
+                let q = a.realloc_impl(p, zero, 24).ok().unwrap();
+                assert_ne!(q.as_ptr() as usize, align);
+                assert_eq!(q.as_ptr() as usize % align, 0);
+                ptr::write_bytes(q.as_ptr(), 0x42, 24);
+
+                // Shrinking it back to zero frees the cell, and returns a made up
+                // pointer that may be deallocated or reallocated again.
+                let layout = Layout::from_size_align(24, align).unwrap();
+                let r = a.realloc_impl(q, layout, 0).ok().unwrap();
+                assert_eq!(r.as_ptr() as usize, align);
+                assert_eq!(a.verify_integrity(), Ok(()));
+
+                let s = a.realloc_impl(r, zero, 0).ok().unwrap();
+                assert_eq!(s.as_ptr() as usize, align);
+                a.dealloc_impl(s, zero);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(not(feature = "buddy_classes"))]
+fn dealloc_large_unchecked_round_trips_with_alloc() {
+    with_heap(|a| {
+        unsafe {
+            for &(size, align) in &[(257 * mem::size_of::<usize>(), 8), (64, 64)] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let p = a.alloc_impl(layout).ok().unwrap();
+                ptr::write_bytes(p.as_ptr(), 0x42, size);
+                a.dealloc_large_unchecked(p, layout);
+                assert_eq!(a.verify_integrity(), Ok(()));
+
+                // The cell went back to the main free list, ready to be reused.
+                let q = a.alloc_impl(layout).ok().unwrap();
+                assert_eq!(p, q);
+                a.dealloc_large_unchecked(q, layout);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "runtime_histogram")]
+fn alloc_size_histogram_counts_rounded_sizes() {
+    with_heap(|a| {
+        // 100 bytes round up to 13 words, which is 104 bytes on 64-bit targets,
+        // unless there is a guard word too. Other tests may allocate concurrently,
+        // so only count on our allocations being among those counted.
+        let rounded: Words = cell_data_size(100).round_up_to();
+        let rounded: Bytes = rounded.into();
+        let bucket = mem::size_of::<usize>() * 8 - 1 - rounded.0.leading_zeros() as usize;
+        let before = a.alloc_size_histogram();
+        let layout = Layout::from_size_align(100, 1).unwrap();
+        unsafe {
+            for _ in 0..3 {
+                let p = a.alloc_impl(layout).ok().unwrap();
+                a.dealloc_impl(p, layout);
+            }
+        }
+        let after = a.alloc_size_histogram();
+        assert!(after[bucket] >= before[bucket] + 3);
+    });
+}
+
+#[test]
+#[cfg(feature = "trace_events")]
+fn event_hook_sees_allocs_deallocs_and_growth() {
+    static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static DEALLOCS: AtomicUsize = AtomicUsize::new(0);
+    static GROWS: AtomicUsize = AtomicUsize::new(0);
+
+    fn hook(event: AllocEvent) {
+        match event {
+            AllocEvent::Alloc { .. } => ALLOCS.fetch_add(1, Ordering::SeqCst),
+            AllocEvent::Dealloc { .. } => DEALLOCS.fetch_add(1, Ordering::SeqCst),
+            AllocEvent::GrowPages { .. } => GROWS.fetch_add(1, Ordering::SeqCst),
+        };
+    }
+
+    let a = WeeAlloc::INIT;
+    a.set_event_hook(Some(hook));
+    let layout = Layout::from_size_align(4242, 8).unwrap();
+    unsafe {
+        let p = a.alloc_impl(layout).ok().unwrap();
+        a.dealloc_impl(p, layout);
+    }
+
+    assert_eq!(ALLOCS.load(Ordering::SeqCst), 1);
+    assert_eq!(DEALLOCS.load(Ordering::SeqCst), 1);
+    assert!(GROWS.load(Ordering::SeqCst) >= 1);
+
+    // The hook belongs to `a`, so another allocator's events don't reach it.
+    let b = WeeAlloc::INIT;
+    unsafe {
+        let p = b.alloc_impl(layout).ok().unwrap();
+        b.dealloc_impl(p, layout);
+    }
+    a.set_event_hook(None);
+
+    assert_eq!(ALLOCS.load(Ordering::SeqCst), 1);
+    assert_eq!(DEALLOCS.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn realloc_across_size_class_and_large_free_lists() {
+    use core::slice;
+
+    // Whether the given free list has a free cell containing `ptr`.
+    unsafe fn is_free_in<'a>(head: &imp::Exclusive<*const FreeCell<'a>>, ptr: NonNull<u8>) -> bool {
+        head.with_exclusive_access(|head| {
+            let mut current = *head;
+            while let Some(cell) = current.as_ref() {
+                let data = cell.header.unchecked_data() as usize;
+                if data <= ptr.as_ptr() as usize && (ptr.as_ptr() as usize) < data + cell.header.size().0 {
+                    return true;
+                }
+                current = cell.next_free();
+            }
+            false
+        })
+    }
+
+    let a = WeeAlloc::INIT;
+    let word = mem::size_of::<usize>();
+    let small = Layout::from_size_align(200 * word, word).unwrap();
+    let large = Layout::from_size_align(500 * word, word).unwrap();
+    let small_list = a
+        .size_classes
+        .get(cell_data_size(small.size()).round_up_to())
+        .unwrap();
+
+    unsafe {
+        // Small to large moves the data out of the size class, and gives the
+        // old cell back to its size class.
+        let p = a.alloc_impl(small).ok().unwrap();
+        ptr::write_bytes(p.as_ptr(), 0x11, small.size());
+        let q = a.realloc_impl(p, small, large.size()).ok().unwrap();
+        assert_ne!(p, q);
+        assert!(slice::from_raw_parts(q.as_ptr(), small.size()).iter().all(|&b| b == 0x11));
+        assert!(is_free_in(small_list, p));
+        assert!(!is_free_in(&a.head, p));
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        // Large to small moves the data back into a size class, and gives the
+        // old cell back to the main free list.
+        ptr::write_bytes(q.as_ptr(), 0x22, large.size());
+        let r = a.realloc_impl(q, large, small.size()).ok().unwrap();
+        assert_ne!(q, r);
+        assert!(slice::from_raw_parts(r.as_ptr(), small.size()).iter().all(|&b| b == 0x22));
+        assert!(is_free_in(&a.head, q));
+        assert!(!is_free_in(small_list, q));
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        a.dealloc_impl(r, small);
+    }
+}
+
+#[test]
+#[cfg(feature = "fault_injection")]
+fn injected_faults_fail_allocations() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(16, 8).unwrap();
+
+        unsafe {
+            let alloc = || match a.alloc_impl(layout) {
+                Ok(p) => {
+                    a.dealloc_impl(p, layout);
+                    true
+                }
+                Err(AllocErr) => false,
+            };
+
+            a.fail_next_n_allocations(2);
+            assert!(!alloc());
+            assert!(!alloc());
+            assert!(alloc());
+
+            a.fail_every_nth_allocation(3);
+            assert!(alloc());
+            assert!(alloc());
+            assert!(!alloc());
+            assert!(alloc());
+            assert!(alloc());
+            assert!(!alloc());
+
+            a.fail_every_nth_allocation(0);
+            assert!(alloc());
+            assert!(alloc());
+            assert!(alloc());
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+    });
+}
+
+#[test]
+fn round_down_to_boundaries() {
+    let word = Words::BYTE_SIZE.0;
+    let page = Pages::BYTE_SIZE.0;
+
+    assert_eq!(round_down_to(0, 4), 0);
+    assert_eq!(round_down_to(7, 4), 1);
+    assert_eq!(round_down_to(8, 4), 2);
+    assert_eq!(round_down_to(9, 4), 2);
+
+    let words = |n: usize| -> Words { Bytes(n).round_down_to() };
+    assert_eq!(words(0), Words(0));
+    assert_eq!(words(word - 1), Words(0));
+    assert_eq!(words(word), Words(1));
+    assert_eq!(words(word + 1), Words(1));
+    assert_eq!(words(3 * word - 1), Words(2));
+    assert_eq!(words(3 * word), Words(3));
+
+    let pages = |n: usize| -> Pages { Bytes(n).round_down_to() };
+    assert_eq!(pages(0), Pages(0));
+    assert_eq!(pages(page - 1), Pages(0));
+    assert_eq!(pages(page), Pages(1));
+    assert_eq!(pages(page + 1), Pages(1));
+    assert_eq!(pages(usize::max_value()), Pages(usize::max_value() / page));
+
+    let words_per_page = page / word;
+    let pages = |n: usize| -> Pages { Words(n).round_down_to() };
+    assert_eq!(pages(words_per_page - 1), Pages(0));
+    assert_eq!(pages(words_per_page), Pages(1));
+    assert_eq!(pages(words_per_page + 1), Pages(1));
+    assert_eq!(pages(2 * words_per_page - 1), Pages(1));
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn populate_size_class_fills_the_free_list() {
+    with_heap(|a| {
+        assert_eq!(a.populate_size_class(Words(4), 0), 0);
+        assert_eq!(
+            a.populate_size_class(Words(size_classes::SizeClasses::MAX_SIZE_CLASS_WORDS + 1), 8),
+            0
+        );
+
+        assert_eq!(a.populate_size_class(Words(4), 8), 8);
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        // All eight allocations come from the populated cells, so the main free
+        // list doesn't shrink any further.
+        let largest = a.largest_free_cell();
+        let layout = Layout::from_size_align(4 * mem::size_of::<usize>(), 1).unwrap();
+        let mut ptrs = [ptr::null_mut(); 8];
+        for p in ptrs.iter_mut() {
+            *p = unsafe { a.try_alloc(layout).unwrap().as_ptr() };
+        }
+        assert_eq!(a.largest_free_cell(), largest);
+        for &p in ptrs.iter() {
+            unsafe { a.dealloc_impl(NonNull::new(p).unwrap(), layout) };
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        // There isn't room for this many, so settle for fewer.
+        let n = a.populate_size_class(Words(4), 100_000);
+        assert!(0 < n && n < 100_000);
+        assert_eq!(a.verify_integrity(), Ok(()));
+    });
+}
+
+#[test]
+#[cfg(feature = "zero_header_on_free")]
+#[should_panic(expected = "double free")]
+fn zero_header_on_free_catches_double_free() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let p = a.try_alloc(layout).unwrap();
+            a.try_dealloc(p, layout);
+            a.try_dealloc(p, layout);
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "zero_header_on_free")]
+fn zero_header_on_free_allows_reusing_freed_cells() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            for _ in 0..4 {
+                let p = a.try_alloc(layout).unwrap();
+                a.try_dealloc(p, layout);
+            }
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+    });
+}
+
+#[test]
+#[cfg(not(any(feature = "buddy_classes", feature = "cacheline_align_cells")))]
+fn aligned_allocations_are_not_over_rounded() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+
+        let layouts = [
+            (24, 16),
+            (8, 16),
+            (40, 32),
+            (3 * word, 4 * word),
+            (72, 64),
+            (100, 64),
+            (200, 128),
+        ];
+        let mut ptrs = [ptr::null_mut(); 7];
+
+        unsafe {
+            for (&(size, align), p) in layouts.iter().zip(ptrs.iter_mut()) {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                *p = a.try_alloc(layout).unwrap().as_ptr();
+                assert_eq!(*p as usize % align, 0, "{{ size: {}, align: {} }}", size, align);
+
+                // The cell is split off from the end of a much bigger free cell,
+                // so the only slack is from aligning its start.
+                let cell = &*(*p as *const CellHeader).offset(-1);
+                let min_size: Words = cell_data_size(size).round_up_to();
+                let min_size: Bytes = min_size.into();
+                assert!(cell.size() >= min_size);
+                assert!(
+                    cell.size().0 - min_size.0 < align,
+                    "{{ size: {}, align: {} }} got a {} byte cell",
+                    size,
+                    align,
+                    cell.size().0
+                );
+            }
+
+            for (&(size, align), &p) in layouts.iter().zip(ptrs.iter()) {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                a.try_dealloc(NonNull::new(p).unwrap(), layout);
+            }
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+    });
+}
+
+#[test]
+#[cfg(not(feature = "buddy_classes"))]
+fn dump_to_lists_every_cell() {
+    use core::fmt::Write;
+
+    // Collects the output into a fixed buffer, like a trap handler would.
+    struct Buf {
+        bytes: [u8; 1024],
+        len: usize,
+    }
+
+    impl Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.bytes.len() {
+                return Err(fmt::Error);
+            }
+            self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    let mut heap = [0u8; 64 * 1024];
+    let a = WeeAlloc::from_slice(&mut heap);
+    let layout = Layout::from_size_align(1024, mem::size_of::<usize>() * 2).unwrap();
+    let p = unsafe { a.try_alloc(layout).unwrap() };
+
+    let mut buf = Buf {
+        bytes: [0; 1024],
+        len: 0,
+    };
+    a.dump_to(&mut buf).unwrap();
+    let dump = core::str::from_utf8(&buf.bytes[..buf.len]).unwrap();
+
+    let mut lines = dump.lines();
+    assert!(lines.next().unwrap().starts_with("segment "));
+    let free = lines.next().unwrap();
+    assert!(free.starts_with("  +") && free.ends_with(" f"), "{}", dump);
+    let allocated = lines.next().unwrap();
+    assert!(allocated.ends_with(" a"), "{}", dump);
+    let offset: usize = allocated[3..].split(' ').next().unwrap().parse().unwrap();
+    let segment_start = p.as_ptr() as usize - mem::size_of::<CellHeader>() - offset;
+    let address = dump["segment 0x".len()..].split(' ').next().unwrap();
+    assert_eq!(usize::from_str_radix(address, 16), Ok(segment_start));
+    assert_eq!(lines.next(), None);
+
+    unsafe { a.try_dealloc(p, layout) };
+}
+
+#[test]
+#[cfg(all(feature = "header_checksum", not(feature = "buddy_classes")))]
+#[should_panic(expected = "corrupted cell header")]
+fn header_checksum_catches_corrupted_headers() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        unsafe {
+            let p = a.try_alloc(layout).unwrap();
+
+            // Scribble over the cell's header, the way an overrun of the
+            // allocation before it would.
+            let header = (p.as_ptr() as *mut CellHeader).offset(-1) as *mut usize;
+            *header ^= 0x40;
+
+            a.try_dealloc(p, layout);
+        }
+    });
+}
+
+#[test]
+fn failed_realloc_leaves_the_allocation_intact() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(1024, 16).unwrap();
+
+        unsafe {
+            let p = a.try_alloc(layout).unwrap();
+            for i in 0..layout.size() {
+                *p.as_ptr().add(i) = i as u8;
+            }
+
+            // Growing past the end of the buffer can't succeed, neither in place
+            // nor by moving.
+            assert!(a.realloc_impl(p, layout, 128 * 1024).is_err());
+            assert_eq!(a.verify_integrity(), Ok(()));
+            for i in 0..layout.size() {
+                assert_eq!(*p.as_ptr().add(i), i as u8);
+            }
+
+            // The original allocation can still be grown within reason, and freed.
+            let q = a.realloc_impl(p, layout, 2048).ok().unwrap();
+            for i in 0..1024 {
+                assert_eq!(*q.as_ptr().offset(i as isize), i as u8);
+            }
+            a.try_dealloc(q, Layout::from_size_align(2048, 16).unwrap());
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(all(feature = "cacheline_align_cells", not(target_arch = "wasm32")))]
+fn large_allocations_start_on_their_own_cache_line() {
+    with_heap(|a| {
+        // Big enough to come from the main free list, even with size classes, and
+        // not a multiple of the cache line size.
+        let layout = Layout::from_size_align(4100, 8).unwrap();
+        unsafe {
+            let mut ptrs = [ptr::null_mut(); 4];
+            for p in ptrs.iter_mut() {
+                *p = a.try_alloc(layout).unwrap().as_ptr();
+                assert_eq!(*p as usize % CACHE_LINE_SIZE.0, 0);
+
+                // Its size is rounded up to whole cache lines, too.
+                let cell = &*(*p as *const CellHeader).offset(-1);
+                assert!(cell.size().0 >= 4160);
+            }
+            for &p in ptrs.iter() {
+                a.try_dealloc(NonNull::new_unchecked(p), layout);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+fn big_over_aligned_allocations_fit_in_a_fresh_refill() {
+    // With a fresh allocator, each of these has to refill the main free list,
+    // and the new cell must have room to align the allocation.
+    for i in 0..16 {
+        let a = WeeAlloc::new();
+        let layout = Layout::from_size_align(64 * 1024 + i * 1000, 64).unwrap();
+        unsafe {
+            let p = a.try_alloc(layout).unwrap();
+            assert_eq!(p.as_ptr() as usize % 64, 0);
+            a.try_dealloc(p, layout);
+        }
+    }
+}
+
+#[test]
+fn saturating_arithmetic_clamps_instead_of_wrapping() {
+    let max = usize::max_value();
+
+    assert_eq!(Bytes(max - 1).saturating_add(Bytes(1)), Bytes(max));
+    assert_eq!(Bytes(max - 1).saturating_add(Bytes(2)), Bytes(max));
+    assert_eq!(Bytes(1).saturating_sub(Bytes(2)), Bytes(0));
+    assert_eq!(Bytes(max).saturating_sub(Bytes(1)), Bytes(max - 1));
+    assert_eq!(Bytes(max / 2).saturating_mul(2), Bytes(max - 1));
+    assert_eq!(Bytes(max / 2).saturating_mul(3), Bytes(max));
+
+    assert_eq!(Words(max - 1).saturating_add(Words(1)), Words(max));
+    assert_eq!(Words(max).saturating_add(Words(max)), Words(max));
+    assert_eq!(Words(0).saturating_sub(Words(max)), Words(0));
+    assert_eq!(Words(max).saturating_sub(Words(max)), Words(0));
+    assert_eq!(Words(max).saturating_mul(1), Words(max));
+    assert_eq!(Words(max).saturating_mul(2), Words(max));
+
+    assert_eq!(Pages(max).saturating_add(Pages(0)), Pages(max));
+    assert_eq!(Pages(max).saturating_add(Pages(1)), Pages(max));
+    assert_eq!(Pages(3).saturating_sub(Pages(max)), Pages(0));
+    assert_eq!(Pages(max).saturating_sub(Pages(0)), Pages(max));
+    assert_eq!(Pages(max).saturating_mul(0), Pages(0));
+    assert_eq!(Pages(max / 4).saturating_mul(8), Pages(max));
+}
+
+#[test]
+#[cfg(feature = "track_peak")]
+fn peak_only_rises_to_new_highs() {
+    let counts = PageCounts::new();
+    counts.granted(Pages(3));
+    assert_eq!(counts.peak(), Pages(3));
+
+    // Pages given back and then handed out again don't count twice.
+    counts.in_use.fetch_sub(1, Ordering::Relaxed);
+    counts.granted(Pages(1));
+    assert_eq!(counts.in_use(), Pages(3));
+    assert_eq!(counts.peak(), Pages(3));
+
+    counts.granted(Pages(2));
+    assert_eq!(counts.peak(), Pages(5));
+}
+
+#[test]
+fn batched_grows_get_bigger_up_to_the_cap() {
+    // With batching off, only ever grow by what is needed.
+    assert_eq!(batched_grow_pages(Pages(1), Pages(8), 0), Pages(1));
+    assert_eq!(batched_grow_pages(Pages(3), Pages(8), 0), Pages(3));
+
+    let mut last = Pages(0);
+    for &expected in &[1, 2, 3, 5, 8, 12, 16, 16] {
+        last = batched_grow_pages(Pages(1), last, 150);
+        assert_eq!(last, Pages(expected));
+    }
+
+    // A single big grow doesn't get any bigger, and doesn't make the next
+    // small one bigger than the cap.
+    assert_eq!(batched_grow_pages(Pages(100), Pages(16), 150), Pages(100));
+    assert_eq!(batched_grow_pages(Pages(1), Pages(100), 150), MAX_BATCHED_GROW_PAGES);
+
+    // At 100% or less, grows never get bigger. All a small percentage does is
+    // make the grows after a big one big too, for pages that may never be used.
+    assert_eq!(batched_grow_pages(Pages(1), Pages(1), 25), Pages(1));
+    assert_eq!(batched_grow_pages(Pages(1), Pages(100), 25), MAX_BATCHED_GROW_PAGES);
+    assert_eq!(batched_grow_pages(Pages(1), Pages(16), 25), Pages(4));
+
+    // Batching is off unless asked for.
+    if option_env!("WEE_ALLOC_GROW_PERCENT").is_none() {
+        assert_eq!(GROW_PERCENT, 0);
+    }
+}
+
+#[test]
+fn absorbed_memory_is_reused() {
+    let mut a_buf = [0u8; 64 * 1024];
+    let mut b_buf = [0u8; 64 * 1024];
+    let b_start = b_buf.as_ptr() as usize;
+    let b_end = b_start + b_buf.len();
+    let a = WeeAlloc::from_slice(&mut a_buf);
+    let b = WeeAlloc::from_slice(&mut b_buf);
+    let large = Layout::from_size_align(5000, mem::size_of::<usize>()).unwrap();
+    let small = Layout::from_size_align(16, mem::size_of::<usize>()).unwrap();
+
+    unsafe {
+        // Use `b` for allocations of both sizes, so that it has free cells in
+        // its size classes as well as its main free list, and then free
+        // everything.
+        let mut ptrs = [NonNull::dangling(); 8];
+        for (i, p) in ptrs.iter_mut().enumerate() {
+            let layout = if i % 2 == 0 { large } else { small };
+            *p = b.try_alloc(layout).ok().unwrap();
+        }
+        for (i, p) in ptrs.iter().enumerate() {
+            let layout = if i % 2 == 0 { large } else { small };
+            b.dealloc_impl(*p, layout);
+        }
+
+        // Use up all of `a`.
+        while a.try_alloc(large).is_ok() {}
+        while a.try_alloc(small).is_ok() {}
+        assert!(!a.owns(b_start as *const u8));
+
+        a.absorb(b);
+        assert!(a.owns(b_start as *const u8));
+
+        // Everything that `a` allocates from now on comes out of `b`'s memory,
+        // and there is room for the same allocations that `b` had made.
+        for (i, p) in ptrs.iter_mut().enumerate() {
+            let layout = if i % 2 == 0 { large } else { small };
+            *p = a.try_alloc(layout).ok().unwrap();
+            let addr = p.as_ptr() as usize;
+            assert!(b_start <= addr && addr < b_end);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "allocator_api")]
+fn allocator_hands_out_whole_cells() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+
+        unsafe {
+            // One byte gets at least a whole word, unless there's a guard word right
+            // after it, and all of it may be used.
+            let layout = Layout::from_size_align(1, 1).unwrap();
+            let slice = (&*a).allocate(layout).ok().unwrap();
+            if !cfg!(feature = "guard_words") {
+                assert!(slice.len() >= word);
+            }
+            let ptr = slice.cast::<u8>();
+            ptr::write_bytes(ptr.as_ptr(), 0xAB, slice.len());
+
+            // Growing keeps the contents, even when the alignment changes.
+            let bigger = Layout::from_size_align(4 * word, 2 * word).unwrap();
+            let grown = (&*a).grow(ptr, layout, bigger).ok().unwrap();
+            assert!(grown.len() >= bigger.size());
+            assert_eq!(grown.cast::<u8>().as_ptr() as usize % (2 * word), 0);
+            assert_eq!(*grown.cast::<u8>().as_ptr(), 0xAB);
+
+            let smaller = Layout::from_size_align(word, 2 * word).unwrap();
+            let shrunk = (&*a).shrink(grown.cast(), bigger, smaller).ok().unwrap();
+            assert_eq!(*shrunk.cast::<u8>().as_ptr(), 0xAB);
+            (&*a).deallocate(shrunk.cast::<u8>(), smaller);
+        }
+    });
+}
+
+#[test]
+fn best_fit_placement_takes_the_smallest_cell_that_fits() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+        let layout = |size| Layout::from_size_align(size, word).unwrap();
+
+        unsafe {
+            // Make free cells of a few different sizes, kept apart by live
+            // allocations so that they can't be merged.
+            let mut ptrs = [NonNull::dangling(); 6];
+            let sizes = [16 * 1024, 1024, 6 * 1024, 1024, 10 * 1024, 1024];
+            for (p, &size) in ptrs.iter_mut().zip(sizes.iter()) {
+                *p = a
+                    .alloc_with_placement(layout(size), &policy::FirstFit)
+                    .unwrap();
+            }
+            for i in (0..6).step_by(2) {
+                a.dealloc_with_placement(ptrs[i], layout(sizes[i]));
+            }
+
+            let p = a
+                .alloc_with_placement(layout(5 * 1024), &policy::BestFit)
+                .unwrap();
+            let start = ptrs[2].as_ptr() as usize;
+            let addr = p.as_ptr() as usize;
+            assert!(start <= addr && addr < start + 6 * 1024);
+
+            a.dealloc_with_placement(p, layout(5 * 1024));
+            for i in (1..6).step_by(2) {
+                a.dealloc_with_placement(ptrs[i], layout(sizes[i]));
+            }
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "alloc_tags")]
+fn retained_by_tag_attributes_live_allocations() {
+    with_heap(|a| {
+        let small = Layout::from_size_align(16, 1).unwrap();
+        let large = Layout::from_size_align(5000, 1).unwrap();
+
+        unsafe {
+            let untagged = a.try_alloc(small).ok().unwrap();
+            let s1 = a.alloc_tagged(small, 7).ok().unwrap();
+            let s2 = a.alloc_tagged(small, 7).ok().unwrap();
+            let l = a.alloc_tagged(large, 3).ok().unwrap();
+
+            let mut retained = a.retained_by_tag();
+            let (tag, bytes) = retained.next().unwrap();
+            assert_eq!(tag, 0);
+            assert!(bytes >= Bytes(16) && bytes < Bytes(32));
+            let (tag, bytes) = retained.next().unwrap();
+            assert_eq!(tag, 3);
+            assert!(bytes >= Bytes(5000));
+            let (tag, bytes) = retained.next().unwrap();
+            assert_eq!(tag, 7);
+            assert!(bytes >= Bytes(32));
+            assert!(retained.next().is_none());
+
+            // Moving an allocation keeps its tag.
+            let s1 = a.realloc_impl(s1, small, 4000).ok().unwrap();
+            let (tag, bytes) = a.retained_by_tag().last().unwrap();
+            assert_eq!(tag, 7);
+            assert!(bytes >= Bytes(4016));
+
+            a.try_dealloc(untagged, small);
+            a.try_dealloc(l, large);
+            a.try_dealloc(s2, small);
+            assert_eq!(a.retained_by_tag().map(|(tag, _)| tag).sum::<u16>(), 7);
+            a.try_dealloc(s1, Layout::from_size_align(4000, 1).unwrap());
+            assert!(a.retained_by_tag().next().is_none());
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "alloc_tags")]
+fn retained_by_tag_reports_more_tags_than_fit_in_one_walk() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(16, 1).unwrap();
+        let mut ptrs = [NonNull::dangling(); 150];
+
+        unsafe {
+            // Tag the allocations out of order, so that the tags that don't fit
+            // in a walk turn up both before and after the ones that do.
+            for (i, p) in ptrs.iter_mut().enumerate() {
+                let tag = (i * 7 % 150 + 1) as u16;
+                *p = a.alloc_tagged(layout, tag).ok().unwrap();
+            }
+
+            let mut expected = 1;
+            for (tag, bytes) in a.retained_by_tag() {
+                assert_eq!(tag, expected);
+                assert!(bytes >= Bytes(16) && bytes < Bytes(32));
+                expected += 1;
+            }
+            assert_eq!(expected, 151);
+
+            for p in ptrs.iter() {
+                a.try_dealloc(*p, layout);
+            }
+        }
+    });
+}
+
+#[test]
+#[cfg(not(any(feature = "no_merge", feature = "buddy_classes")))]
+fn cells_never_merge_across_adjacent_segments() {
+    // Two segments, the second starting right where the first one ends, so
+    // that the last cell of the first one is followed in memory by the header
+    // of the second one.
+    let mut buf = [0usize; 8 * 1024];
+    let (first, second) = buf.split_at_mut(4 * 1024);
+    let half = Bytes(mem::size_of_val(first));
+    let a = WeeAlloc::from_slice(unsafe {
+        core::slice::from_raw_parts_mut(first.as_mut_ptr() as *mut u8, half.0)
+    });
+    unsafe {
+        a.donate(NonNull::new_unchecked(second.as_mut_ptr() as *mut u8), half);
+    }
+
+    let layout = Layout::from_size_align(4096, 1).unwrap();
+    unsafe {
+        // Fill both segments, then free every cell, in an order that has the
+        // cells at the boundary merge with their neighbors from both sides.
+        let mut ptrs = [None; 16];
+        for p in ptrs.iter_mut() {
+            *p = a.try_alloc(layout).ok();
+        }
+        assert!(ptrs.iter().any(|p| p.is_none()), "both segments are full");
+        for i in (0..16).step_by(2).chain((1..16).step_by(2)) {
+            if let Some(p) = ptrs[i] {
+                a.try_dealloc(p, layout);
+            }
+        }
+        a.compact();
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        // Each segment is a single free cell again, but not one that spans
+        // both of them.
+        let big = Layout::from_size_align(half.0 * 5 / 4, 1).unwrap();
+        assert!(a.try_alloc(big).is_err());
+        let most = Layout::from_size_align(half.0 * 3 / 4, 1).unwrap();
+        let p = a.try_alloc(most).ok().unwrap();
+        let q = a.try_alloc(most).ok().unwrap();
+        a.try_dealloc(p, most);
+        a.try_dealloc(q, most);
+        assert_eq!(a.verify_integrity(), Ok(()));
+    }
+}
+
+#[test]
+fn can_allocate_matches_what_allocating_does() {
+    let word = mem::size_of::<usize>();
+    let max = usize::max_value();
+    assert!(WeeAlloc::MIN_ALLOC_SIZE >= Bytes(word));
+
+    let can_allocate =
+        |size, align| WeeAlloc::can_allocate(Layout::from_size_align(size, align).unwrap());
+    let align = WeeAlloc::MAX_SUPPORTED_ALIGN.0;
+    assert!(can_allocate(0, 1));
+    assert!(can_allocate(1, 1));
+    assert!(can_allocate(1 << 20, 4096));
+    assert!(can_allocate(1, align));
+    assert!(!can_allocate(1, align * 2));
+    assert!(!can_allocate(max / 2, 1));
+
+    // Layouts that can't be represented fail up front, without touching the
+    // backend.
+    with_heap(|a| {
+        for &(size, align) in &[(max / 2, 1), (1, align * 2), (max / 2 - 4096, 4096)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            assert!(!WeeAlloc::can_allocate(layout));
+            assert!(unsafe { a.try_alloc(layout) }.is_err());
+        }
+    });
+}
+
+#[test]
+#[cfg(all(
+    feature = "size_classes",
+    not(any(feature = "no_merge", feature = "buddy_classes"))
+))]
+fn reset_size_classes_keeps_large_allocations() {
+    with_heap(|a| {
+        let large = Layout::from_size_align(5000, 1).unwrap();
+        let small = Layout::from_size_align(16, 1).unwrap();
+        unsafe {
+            let p = a.try_alloc(large).ok().unwrap();
+            ptr::write_bytes(p.as_ptr(), 0xAA, large.size());
+            let q = a.try_alloc(large).ok().unwrap();
+            ptr::write_bytes(q.as_ptr(), 0xBB, large.size());
+            for _ in 0..300 {
+                a.try_alloc(small).ok().unwrap();
+            }
+            a.compact();
+            let before = a.largest_free_cell();
+
+            a.reset_size_classes();
+            assert_eq!(a.verify_integrity(), Ok(()));
+
+            // The small allocations' memory is back in the main free list, and
+            // merged with the free memory around it.
+            assert!(a.largest_free_cell() > before);
+            let p_data = core::slice::from_raw_parts(p.as_ptr(), large.size());
+            let q_data = core::slice::from_raw_parts(q.as_ptr(), large.size());
+            assert!(p_data.iter().all(|&b| b == 0xAA));
+            assert!(q_data.iter().all(|&b| b == 0xBB));
+
+            // Both kinds of allocation still work afterwards.
+            let r = a.try_alloc(small).ok().unwrap();
+            a.try_dealloc(r, small);
+
+            // Resetting through `&mut` does the same, without locking.
+            for _ in 0..300 {
+                a.try_alloc(small).ok().unwrap();
+            }
+            a.reset_size_classes_mut();
+            assert_eq!(a.verify_integrity(), Ok(()));
+            assert!(a.largest_free_cell() > before);
+
+            a.try_dealloc(p, large);
+            a.try_dealloc(q, large);
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+fn typed_allocations_are_never_the_zero_sized_pointer() {
+    with_heap(|a| {
+        let word = mem::size_of::<usize>();
+
+        unsafe {
+            for &(size, align) in &[(1, 1), (word, word), (100, 2 * word), (5000, 1)] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let sentinel = zero_sized_alloc(Bytes(align));
+
+                let p = a.try_alloc(layout).ok().unwrap();
+                assert!(p != sentinel && a.owns(p.as_ptr()));
+                let (q, _) = a.try_alloc_at_least(layout).ok().unwrap();
+                assert!(q != sentinel && a.owns(q.as_ptr()));
+                #[cfg(feature = "allocator_api")]
+                {
+                    let r = (&*a).allocate(layout).ok().unwrap().cast::<u8>();
+                    assert!(r != sentinel && a.owns(r.as_ptr()));
+                    (&*a).deallocate(r, layout);
+                }
+                a.try_dealloc(q, layout);
+                a.try_dealloc(p, layout);
+            }
+
+            let array = a.alloc_array::<u64>(3).ok().unwrap();
+            assert!(a.owns(array.as_ptr() as *const u8));
+            a.dealloc_array(array, 3);
+
+            // Zero-sized allocations get the made up pointer, and nothing else.
+            let empty = Layout::from_size_align(0, word).unwrap();
+            assert_eq!(a.try_alloc(empty).ok(), Some(zero_sized_alloc(Bytes(word))));
+        }
+    });
+}
+
+#[test]
+fn any_alignment_is_satisfied_however_the_heap_is_placed() {
+    let mut buf = [0u8; 128 * 1024];
+    let word = mem::size_of::<usize>();
+
+    // Start the heap at every offset within a word, so that nothing but the
+    // allocator's own placement lines allocations up.
+    for offset in 0..word {
+        let a = WeeAlloc::from_slice(&mut buf[offset..]);
+        let mut align = 1;
+        while align <= 8192 {
+            for &size in &[1, word, 100, 5000] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                unsafe {
+                    let p = a.try_alloc(layout).ok().unwrap();
+                    assert_eq!(p.as_ptr() as usize % align, 0);
+                    ptr::write_bytes(p.as_ptr(), 0xCD, size);
+                    a.try_dealloc(p, layout);
+                }
+            }
+            align *= 2;
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+    }
+}
+
+#[test]
+fn build_info_reports_the_backend_for_this_target() {
+    let info = build_info();
+    let expected = if cfg!(feature = "static_array_backend") {
+        "static_array"
+    } else if cfg!(feature = "growable_backend") {
+        "growable"
+    } else if cfg!(target_arch = "wasm32") {
+        "wasm32"
+    } else if cfg!(target_os = "redox") {
+        "redox"
+    } else if cfg!(unix) {
+        "unix"
+    } else {
+        "windows"
+    };
+    assert_eq!(info.backend, expected);
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.size_classes, info.num_size_classes > 0);
+    assert_eq!(info.static_array_backend, info.static_array_bytes.is_some());
+}
+
+#[test]
+#[cfg(all(
+    feature = "address_ordered_free_list",
+    not(any(feature = "no_merge", feature = "buddy_classes"))
+))]
+fn walking_the_free_list_merges_cells_without_the_merge_bit() {
+    with_heap(|a| {
+        let layout = Layout::from_size_align(3000, 1).unwrap();
+
+        unsafe {
+            // Fill the heap, so that only a merged cell can satisfy a bigger
+            // allocation.
+            let mut ptrs = [None; 32];
+            for p in ptrs.iter_mut() {
+                *p = a.try_alloc(layout).ok();
+            }
+            assert!(ptrs.iter().any(|p| p.is_none()), "the heap is full");
+
+            // Free two adjacent allocations straight into the free list, leaving
+            // them unmerged, and with no merge bit set.
+            a.head.with_exclusive_access(|head| {
+                let head_cell = Cell::new(*head);
+                for p in ptrs[..2].iter() {
+                    let p = p.unwrap();
+                    let cell = &*(p.as_ptr() as *const AllocatedCell).offset(-1);
+                    cell.into_free_cell(a.large_alloc_policy())
+                        .insert_into_free_list(&head_cell, a.large_alloc_policy());
+                }
+                *head = head_cell.get();
+            });
+            assert_eq!(a.verify_integrity(), Ok(()));
+
+            // The next allocation's walk merges them, making room for it.
+            let bigger = Layout::from_size_align(5000, 1).unwrap();
+            let p = a.try_alloc(bigger).ok().unwrap();
+            a.try_dealloc(p, bigger);
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    });
+}
+
+#[test]
+#[cfg(feature = "deterministic")]
+fn deterministic_allocation_sites_survive_growing_from_the_backend() {
+    // Where `ptr` is: the index of its segment, oldest first, and its offset
+    // from the start of that segment.
+    unsafe fn site(a: &WeeAlloc, ptr: NonNull<u8>) -> (usize, usize) {
+        let addr = ptr.as_ptr() as usize;
+        a.large_alloc_policy
+            .segments
+            .with_exclusive_access(|segments| {
+                let mut count = 0;
+                let mut found = None;
+                let mut segment = *segments;
+                while !segment.is_null() {
+                    let start = segment as usize;
+                    if start <= addr && addr < (*segment).end {
+                        found = Some((count, addr - start));
+                    }
+                    count += 1;
+                    segment = (*segment).next;
+                }
+                let (i, offset) = found.expect("allocation is in one of the segments");
+                (count - 1 - i, offset)
+            })
+    }
+
+    // Run the same operations on a fresh allocator, and give the site of
+    // every allocation. The two big allocations each need a segment of their
+    // own from the backend, and the rest is carved out of what is left.
+    unsafe fn run() -> [(usize, usize); 8] {
+        let a = WeeAlloc::new();
+        let big = Layout::from_size_align(40_000, 8).unwrap();
+        let small = Layout::from_size_align(24, 8).unwrap();
+        let medium = Layout::from_size_align(3000, 8).unwrap();
+        let aligned = Layout::from_size_align(100, 64).unwrap();
+
+        let mut sites = [(0, 0); 8];
+        let p0 = a.try_alloc(big).unwrap();
+        sites[0] = site(&a, p0);
+        let p1 = a.try_alloc(big).unwrap();
+        sites[1] = site(&a, p1);
+        let p2 = a.try_alloc(small).unwrap();
+        sites[2] = site(&a, p2);
+        a.try_dealloc(p0, big);
+        let p3 = a.try_alloc(medium).unwrap();
+        sites[3] = site(&a, p3);
+        let p4 = a.try_alloc(aligned).unwrap();
+        sites[4] = site(&a, p4);
+        let p2 = a.realloc_impl(p2, small, 6000).ok().unwrap();
+        sites[5] = site(&a, p2);
+        a.try_dealloc(p3, medium);
+        let p5 = a.try_alloc(small).unwrap();
+        sites[6] = site(&a, p5);
+        let p6 = a.try_alloc(big).unwrap();
+        sites[7] = site(&a, p6);
+        sites
+    }
+
+    unsafe {
+        let first = run();
+        assert_eq!(first[1].0, 1, "the second big allocation needs a new segment");
+        let second = run();
+        assert_eq!(first, second);
+    }
+}
+
+#[test]
+#[cfg(feature = "size_classes")]
+fn emptied_size_classes_give_their_segments_back() {
+    // Between them, these size classes would need much more than the whole
+    // buffer at once, so every round only fits if the size classes that are
+    // done with give their segments back to the main free list.
+    let mut buf = [0u8; 128 * 1024];
+    let a = WeeAlloc::from_slice(&mut buf);
+    let large = Layout::from_size_align(4096, 1).unwrap();
+    let mut ptrs = [ptr::null_mut(); 1000];
+    unsafe {
+        for round in 0..10 {
+            let small =
+                Layout::from_size_align((round % 5 + 1) * mem::size_of::<usize>(), 1).unwrap();
+            let p = a.try_alloc(large).ok().unwrap();
+            for q in ptrs.iter_mut() {
+                *q = a.try_alloc(small).ok().unwrap().as_ptr();
+            }
+            a.try_dealloc(p, large);
+            for &q in ptrs.iter() {
+                a.try_dealloc(NonNull::new_unchecked(q), small);
+            }
+            assert_eq!(a.verify_integrity(), Ok(()));
+        }
+    }
+}
+
+// The given back segments have to merge for the large allocation to fit.
+#[test]
+#[cfg(all(feature = "size_classes", not(feature = "no_merge")))]
+fn emptied_size_class_segments_are_given_back_while_others_are_live() {
+    let mut buf = [0u8; 256 * 1024];
+    let a = WeeAlloc::from_slice(&mut buf);
+    let small = Layout::from_size_align(mem::size_of::<usize>(), 1).unwrap();
+    let large = Layout::from_size_align(64 * 1024, 1).unwrap();
+
+    // Fill the heap with cells, across many of the size class's segments.
+    let mut ptrs = [ptr::null_mut(); 256 * 1024 / (2 * mem::size_of::<usize>())];
+    let mut n = 0;
+    unsafe {
+        while let Ok(q) = a.try_alloc(small) {
+            ptrs[n] = q.as_ptr();
+            n += 1;
+        }
+        assert!(a.try_alloc(large).is_err());
+
+        // Keep the first cell alive, so that its segment is never empty, and
+        // free the rest. The other segments go back to the main free list as
+        // they empty.
+        for &q in ptrs[1..n].iter() {
+            a.try_dealloc(NonNull::new_unchecked(q), small);
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        let p = a.try_alloc(large).ok().unwrap();
+        a.try_dealloc(p, large);
+        a.try_dealloc(NonNull::new_unchecked(ptrs[0]), small);
+        assert_eq!(a.verify_integrity(), Ok(()));
+    }
+}
+
+#[test]
+#[cfg(feature = "buddy_classes")]
+fn emptied_buddy_arenas_are_given_back() {
+    let mut buf = [0u8; 256 * 1024];
+    let a = WeeAlloc::from_slice(&mut buf);
+    let small = Layout::from_size_align(mem::size_of::<usize>(), 1).unwrap();
+    let large = Layout::from_size_align(64 * 1024, 1).unwrap();
+
+    // Fill the heap with the smallest blocks, across many arenas.
+    let mut ptrs = [ptr::null_mut(); 256 * 1024 / 16];
+    let mut n = 0;
+    unsafe {
+        while let Ok(q) = a.try_alloc(small) {
+            ptrs[n] = q.as_ptr();
+            n += 1;
+        }
+        assert!(a.try_alloc(large).is_err());
+
+        // Every arena but the last one to empty goes back to the main free list.
+        for &q in ptrs[..n].iter() {
+            a.try_dealloc(NonNull::new_unchecked(q), small);
+        }
+        assert_eq!(a.verify_integrity(), Ok(()));
+
+        let p = a.try_alloc(large).ok().unwrap();
+        a.try_dealloc(p, large);
+        assert_eq!(a.verify_integrity(), Ok(()));
+    }
+}
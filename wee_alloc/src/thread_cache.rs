@@ -0,0 +1,264 @@
+//! A per-thread cache of small, recently freed cells in front of a `WeeAlloc`.
+//!
+//! Every `WeeAlloc` free list sits behind a lock, so threads that allocate a
+//! lot contend on it. Small allocations are usually freed and allocated again
+//! soon after, on the same thread, so each thread keeps a few cells of each
+//! small size that it freed, and hands them out again without taking any lock.
+//!
+//! Cells in a cache are still allocated as far as the `WeeAlloc` is concerned.
+//! A cell is only ever in the cache of the thread that freed it, so no cell
+//! can be handed out by two threads: a cell allocated on one thread and freed
+//! on another simply moves to the second thread's cache. When a thread exits,
+//! its cache is freed back into the `WeeAlloc`.
+//!
+//! Registering the destructor that flushes the cache may itself allocate, so
+//! allocations made while it is being registered bypass the cache, as do
+//! allocations made after the cache was flushed while the thread exits.
+
+use super::{cell_data_size, check_guard_word, layout_align, write_guard_word, WeeAlloc};
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::{Cell, RefCell};
+use core::ptr::{self, NonNull};
+use memory_units::{size_of, Bytes, RoundUpTo, Words};
+
+// Allocations of up to this many words are cached, each size in its own bin.
+const NUM_BINS: usize = 16;
+
+// How many cells each bin holds before frees go to the `WeeAlloc` again.
+const BIN_CAPACITY: usize = 16;
+
+// The states that a thread's cache goes through.
+const UNREGISTERED: u8 = 0;
+const REGISTERING: u8 = 1;
+const ACTIVE: u8 = 2;
+const EXITED: u8 = 3;
+
+#[derive(Clone, Copy)]
+struct Bin {
+    len: usize,
+    cells: [*mut u8; BIN_CAPACITY],
+}
+
+impl Bin {
+    const EMPTY: Bin = Bin {
+        len: 0,
+        cells: [ptr::null_mut(); BIN_CAPACITY],
+    };
+}
+
+struct ThreadCache {
+    // The allocator that the cached cells belong to. A thread only caches cells
+    // for the first `ThreadCachedWeeAlloc` it uses.
+    owner: *const WeeAlloc<'static>,
+    bins: [Bin; NUM_BINS],
+}
+
+impl ThreadCache {
+    const INIT: ThreadCache = ThreadCache {
+        owner: ptr::null(),
+        bins: [Bin::EMPTY; NUM_BINS],
+    };
+
+    fn pop(&mut self, bin: usize) -> Option<NonNull<u8>> {
+        let bin = &mut self.bins[bin];
+        if bin.len == 0 {
+            return None;
+        }
+        bin.len -= 1;
+        NonNull::new(bin.cells[bin.len])
+    }
+
+    fn push(&mut self, bin: usize, ptr: NonNull<u8>) -> bool {
+        let bin = &mut self.bins[bin];
+        if bin.len == BIN_CAPACITY {
+            return false;
+        }
+        bin.cells[bin.len] = ptr.as_ptr();
+        bin.len += 1;
+        true
+    }
+
+    // Free every cached cell back into the owning allocator.
+    unsafe fn flush(&mut self) {
+        if self.owner.is_null() {
+            return;
+        }
+        let owner = &*self.owner;
+        for (i, bin) in self.bins.iter_mut().enumerate() {
+            for &cell in &bin.cells[..bin.len] {
+                owner.dealloc_words(NonNull::new_unchecked(cell), Words(i + 1), Bytes(1));
+            }
+            bin.len = 0;
+        }
+    }
+}
+
+// Flushes the thread's cache when the thread exits.
+struct FlushOnExit;
+
+impl Drop for FlushOnExit {
+    fn drop(&mut self) {
+        let _ = STATE.try_with(|s| s.set(EXITED));
+        let _ = CACHE.try_with(|cache| {
+            if let Ok(mut cache) = cache.try_borrow_mut() {
+                unsafe { cache.flush() }
+            }
+        });
+    }
+}
+
+// Neither `STATE` nor `CACHE` have destructors, so they are usable at any point
+// in the thread's life, without registering anything.
+thread_local! {
+    static STATE: Cell<u8> = const { Cell::new(UNREGISTERED) };
+    static CACHE: RefCell<ThreadCache> = const { RefCell::new(ThreadCache::INIT) };
+    static FLUSH_ON_EXIT: FlushOnExit = const { FlushOnExit };
+}
+
+// The bin for allocations with this layout, if they are cached at all.
+fn bin_for_layout(layout: &Layout) -> Option<usize> {
+    if layout.size() == 0 || layout_align(layout) > size_of::<usize>() {
+        return None;
+    }
+    let words: Words = cell_data_size(layout.size()).round_up_to();
+    if words.0 > NUM_BINS {
+        return None;
+    }
+    Some(words.0 - 1)
+}
+
+// Run `f` with this thread's cache, unless the cache can't be used for cells
+// from `owner` right now.
+fn with_cache<F, T>(owner: &WeeAlloc<'static>, f: F) -> Option<T>
+where
+    F: FnOnce(&mut ThreadCache) -> Option<T>,
+{
+    match STATE.try_with(|s| s.get()).ok()? {
+        ACTIVE => {}
+        UNREGISTERED => {
+            STATE.with(|s| s.set(REGISTERING));
+            // This registers the destructor, which may allocate. Those
+            // allocations see the `REGISTERING` state and skip the cache.
+            let registered = FLUSH_ON_EXIT.try_with(|_| ()).is_ok();
+            STATE.with(|s| s.set(if registered { ACTIVE } else { EXITED }));
+            if !registered {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+
+    CACHE
+        .try_with(|cache| {
+            let mut cache = cache.try_borrow_mut().ok()?;
+            if cache.owner.is_null() {
+                cache.owner = owner;
+            } else if !ptr::eq(cache.owner, owner) {
+                return None;
+            }
+            f(&mut cache)
+        })
+        .ok()?
+}
+
+/// A `WeeAlloc` with a per-thread cache of small, recently freed cells in
+/// front of it, to reduce contention on its locks when many threads allocate.
+///
+/// Only available with the `thread_local_cache` feature. A thread only caches
+/// cells for the first `ThreadCachedWeeAlloc` that it uses; any others work
+/// like a plain `WeeAlloc` on that thread.
+///
+/// Cached cells remember which allocator they belong to by its address, so a
+/// `ThreadCachedWeeAlloc` must live in a `static`, such as the
+/// `#[global_allocator]`.
+pub struct ThreadCachedWeeAlloc {
+    inner: WeeAlloc<'static>,
+}
+
+impl ThreadCachedWeeAlloc {
+    /// An initial `const` default construction of a `ThreadCachedWeeAlloc`.
+    ///
+    /// This is usable for initializing `static`s that get set as the global
+    /// allocator.
+    pub const INIT: Self = ThreadCachedWeeAlloc {
+        inner: WeeAlloc::INIT,
+    };
+
+    /// The underlying allocator, for example to call
+    /// `WeeAlloc::verify_integrity`. Cells sitting in a thread's cache look
+    /// allocated to it.
+    pub fn allocator(&self) -> &WeeAlloc<'static> {
+        &self.inner
+    }
+}
+
+unsafe impl GlobalAlloc for ThreadCachedWeeAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(bin) = bin_for_layout(&layout) {
+            if let Some(ptr) = with_cache(&self.inner, |cache| cache.pop(bin)) {
+                write_guard_word(ptr, layout.size());
+                return ptr.as_ptr();
+            }
+        }
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => return,
+        };
+        if let Some(bin) = bin_for_layout(&layout) {
+            check_guard_word(ptr, layout.size());
+            if with_cache(&self.inner, |cache| Some(cache.push(bin, ptr))) == Some(true) {
+                return;
+            }
+        }
+        self.inner.dealloc(ptr.as_ptr(), layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.inner.realloc(ptr, layout, new_size)
+    }
+}
+
+#[test]
+fn frees_are_reused_on_the_same_thread() {
+    static A: ThreadCachedWeeAlloc = ThreadCachedWeeAlloc::INIT;
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let p = A.alloc(layout);
+        assert!(!p.is_null());
+        A.dealloc(p, layout);
+        assert_eq!(A.alloc(layout), p);
+        A.dealloc(p, layout);
+    }
+}
+
+#[test]
+fn thread_exit_flushes_the_cache() {
+    use std::thread;
+
+    static A: ThreadCachedWeeAlloc = ThreadCachedWeeAlloc::INIT;
+    let layout = Layout::from_size_align(40, 8).unwrap();
+
+    let freed: Vec<usize> = thread::spawn(move || unsafe {
+        let ptrs: Vec<*mut u8> = (0..4).map(|_| A.alloc(layout)).collect();
+        for &p in &ptrs {
+            assert!(!p.is_null());
+            A.dealloc(p, layout);
+        }
+        ptrs.into_iter().map(|p| p as usize).collect()
+    })
+    .join()
+    .unwrap();
+
+    // The exiting thread gave its cached cells back, so allocating from the
+    // underlying allocator directly finds them again.
+    assert_eq!(A.allocator().verify_integrity(), Ok(()));
+    unsafe {
+        let p = A.allocator().alloc(layout);
+        assert!(freed.contains(&(p as usize)));
+        A.allocator().dealloc(p, layout);
+    }
+}